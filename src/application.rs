@@ -1,5 +1,6 @@
 pub use crate::colink_proto::co_link_client::CoLinkClient;
 pub use crate::colink_proto::*;
+use crate::KeyPath;
 use futures_lite::stream::StreamExt;
 use lapin::{
     options::{BasicAckOptions, BasicConsumeOptions},
@@ -37,6 +38,16 @@ pub struct CoLink {
     pub(crate) identity: Option<Identity>,
     #[cfg(feature = "variable_transfer")]
     pub(crate) vt_p2p_ctx: Arc<crate::extensions::variable_transfer::p2p_inbox::VtP2pCtx>,
+    #[cfg(feature = "storage_macro")]
+    pub(crate) storage_macros: crate::extensions::storage_macro::StorageMacroRegistry,
+    #[cfg(feature = "storage_macro")]
+    pub(crate) redis_connections: crate::extensions::storage_macro::RedisConnectionPool,
+    #[cfg(all(feature = "storage_macro", feature = "storage_macro_encrypt"))]
+    pub(crate) encryption_keys: crate::extensions::storage_macro::EncryptionKeyRegistry,
+    #[cfg(all(feature = "storage_macro", feature = "storage_macro_sqlite"))]
+    pub(crate) sqlite_connections: crate::extensions::storage_macro::SqliteConnectionPool,
+    #[cfg(feature = "extensions")]
+    pub(crate) read_cache: Option<Arc<crate::extensions::read_cache::ReadCache>>,
 }
 
 type Error = Box<dyn std::error::Error + Send + Sync + 'static>;
@@ -53,6 +64,16 @@ impl CoLink {
             vt_p2p_ctx: Arc::new(
                 crate::extensions::variable_transfer::p2p_inbox::VtP2pCtx::default(),
             ),
+            #[cfg(feature = "storage_macro")]
+            storage_macros: crate::extensions::storage_macro::default_storage_macros(),
+            #[cfg(feature = "storage_macro")]
+            redis_connections: Default::default(),
+            #[cfg(all(feature = "storage_macro", feature = "storage_macro_encrypt"))]
+            encryption_keys: Default::default(),
+            #[cfg(all(feature = "storage_macro", feature = "storage_macro_sqlite"))]
+            sqlite_connections: Default::default(),
+            #[cfg(feature = "extensions")]
+            read_cache: None,
         }
     }
 
@@ -232,6 +253,8 @@ impl CoLink {
         );
         let response = client.create_entry(request).await?;
         debug!("RESPONSE={:?}", response);
+        #[cfg(feature = "extensions")]
+        self._read_cache_invalidate(key_name);
         Ok(response.get_ref().key_path.clone())
     }
 
@@ -260,6 +283,10 @@ impl CoLink {
             .into());
         }
 
+        #[cfg(feature = "extensions")]
+        if let Some(payload) = self._read_cache_get(key) {
+            return Ok(payload);
+        }
         let storage_entry = if key.contains("::") {
             StorageEntry {
                 key_path: key.to_string(),
@@ -272,6 +299,12 @@ impl CoLink {
             }
         };
         let res = self.read_entries(&[storage_entry]).await?;
+        #[cfg(feature = "extensions")]
+        self._read_cache_insert(
+            key,
+            &res[0].payload,
+            crate::utils::get_path_timestamp(&res[0].key_path) + 1,
+        );
         Ok(res[0].payload.clone())
     }
 
@@ -298,6 +331,8 @@ impl CoLink {
         );
         let response = client.update_entry(request).await?;
         debug!("RESPONSE={:?}", response);
+        #[cfg(feature = "extensions")]
+        self._read_cache_invalidate(key_name);
         Ok(response.get_ref().key_path.clone())
     }
 
@@ -323,6 +358,8 @@ impl CoLink {
         );
         let response = client.delete_entry(request).await?;
         debug!("RESPONSE={:?}", response);
+        #[cfg(feature = "extensions")]
+        self._read_cache_invalidate(key_name);
         Ok(response.get_ref().key_path.clone())
     }
 
@@ -358,7 +395,10 @@ impl CoLink {
     pub async fn import_guest_jwt(&self, jwt: &str) -> Result<(), Error> {
         let jwt_decoded = decode_jwt_without_validation(jwt)?;
         self.update_entry(
-            &format!("_internal:known_users:{}:guest_jwt", jwt_decoded.user_id),
+            &KeyPath::new("_internal:known_users")
+                .escaped(&jwt_decoded.user_id)
+                .segment("guest_jwt")
+                .to_string(),
             jwt.as_bytes(),
         )
         .await?;
@@ -367,7 +407,10 @@ impl CoLink {
 
     pub async fn import_core_addr(&self, user_id: &str, core_addr: &str) -> Result<(), Error> {
         self.update_entry(
-            &format!("_internal:known_users:{}:core_addr", user_id),
+            &KeyPath::new("_internal:known_users")
+                .escaped(user_id)
+                .segment("core_addr")
+                .to_string(),
             core_addr.as_bytes(),
         )
         .await?;
@@ -380,7 +423,10 @@ impl CoLink {
         forwarding_user_id: &str,
     ) -> Result<(), Error> {
         self.update_entry(
-            &format!("_internal:known_users:{}:forwarding_user_id", user_id),
+            &KeyPath::new("_internal:known_users")
+                .escaped(user_id)
+                .segment("forwarding_user_id")
+                .to_string(),
             forwarding_user_id.as_bytes(),
         )
         .await?;
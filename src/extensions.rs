@@ -1,11 +1,29 @@
 #[cfg(feature = "extensions")]
+mod atomic_counter;
+#[cfg(feature = "variable_transfer")]
+mod barrier;
+#[cfg(feature = "extensions")]
+pub(crate) mod conditional_update;
+#[cfg(feature = "extensions")]
+mod entry_history;
+#[cfg(feature = "extensions")]
+mod entry_range;
+#[cfg(feature = "extensions")]
+mod entry_stream;
+#[cfg(feature = "extensions")]
 mod get_participant_index;
 #[cfg(feature = "instant_server")]
 pub mod instant_server;
 #[cfg(feature = "extensions")]
+mod leader_election;
+#[cfg(feature = "extensions")]
 mod lock;
 #[cfg(feature = "policy_module")]
 pub mod policy_module;
+#[cfg(feature = "storage_macro")]
+mod prefix_operations;
+#[cfg(feature = "extensions")]
+pub(crate) mod read_cache;
 #[cfg(feature = "extensions")]
 mod read_or_wait;
 #[cfg(feature = "registry")]
@@ -13,7 +31,9 @@ pub mod registry;
 #[cfg(feature = "remote_storage")]
 mod remote_storage;
 #[cfg(feature = "storage_macro")]
-mod storage_macro;
+pub mod storage_archive;
+#[cfg(feature = "storage_macro")]
+pub mod storage_macro;
 #[cfg(feature = "extensions")]
 mod switch_to_generated_user;
 #[cfg(feature = "variable_transfer")]
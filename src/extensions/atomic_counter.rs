@@ -0,0 +1,53 @@
+use crate::utils::is_not_found_error;
+
+type Error = Box<dyn std::error::Error + Send + Sync + 'static>;
+
+/// Counters are stored as decimal strings so that they are compatible with Redis INCRBY.
+fn decode_counter(payload: &[u8]) -> Result<i64, Error> {
+    Ok(String::from_utf8(payload.to_vec())?.parse::<i64>()?)
+}
+
+impl crate::application::CoLink {
+    /// Atomically add `delta` to the counter in `key_name` and return the new value. A missing counter starts from 0.
+    /// For keys in the $redis storage macro, this maps to INCRBY.
+    pub async fn atomic_add(&self, key_name: &str, delta: i64) -> Result<i64, Error> {
+        if key_name.contains('$') {
+            #[cfg(feature = "storage_macro")]
+            {
+                let (string_before, macro_type, string_after) = self._parse_macro(key_name);
+                if macro_type == "redis" {
+                    return self
+                        ._atomic_add_redis(&string_before, &string_after, delta)
+                        .await;
+                }
+            }
+            Err(format!(
+                "atomic_add is not supported for storage macros, found $ symbol in key name: {}",
+                key_name
+            ))?
+        }
+        let mut value = 0;
+        self._update_with(key_name, |current| {
+            let res = match current {
+                Some(current) => decode_counter(&current).map(|x| x + delta),
+                None => Ok(delta),
+            }
+            .map(|new_value| {
+                value = new_value;
+                new_value.to_string().into_bytes()
+            });
+            async move { res }
+        })
+        .await?;
+        Ok(value)
+    }
+
+    /// Read the counter in `key_name`. A missing counter is 0.
+    pub async fn atomic_get(&self, key_name: &str) -> Result<i64, Error> {
+        match self.read_entry(key_name).await {
+            Ok(res) => decode_counter(&res),
+            Err(e) if is_not_found_error(&*e) => Ok(0),
+            Err(e) => Err(e),
+        }
+    }
+}
@@ -0,0 +1,72 @@
+use crate::{colink_proto::*, KeyPath};
+
+type Error = Box<dyn std::error::Error + Send + Sync + 'static>;
+
+impl crate::application::CoLink {
+    /// Wait until all participants of the current task reach the barrier `name`.
+    /// Each barrier name can only be used once in a task.
+    pub async fn barrier(&self, name: &str, participants: &[Participant]) -> Result<(), Error> {
+        self._barrier(name, participants, None).await?;
+        Ok(())
+    }
+
+    /// Return the participants that did not reach the barrier `name` before the timeout.
+    /// The returned list is empty if all participants arrived in time.
+    pub async fn barrier_with_timeout(
+        &self,
+        name: &str,
+        participants: &[Participant],
+        timeout_in_ms: u64,
+    ) -> Result<Vec<Participant>, Error> {
+        self._barrier(name, participants, Some(timeout_in_ms)).await
+    }
+
+    async fn _barrier(
+        &self,
+        name: &str,
+        participants: &[Participant],
+        timeout_in_ms: Option<u64>,
+    ) -> Result<Vec<Participant>, Error> {
+        let key = KeyPath::new("_barrier").segment(name).to_string();
+        let user_id = self.get_user_id()?;
+        let mut others: Vec<Participant> = vec![];
+        for participant in participants {
+            if participant.user_id != user_id
+                && !others.iter().any(|x| x.user_id == participant.user_id)
+            {
+                others.push(participant.clone());
+            }
+        }
+        self.send_variable(&key, b"", &others).await?;
+        let mut arrivals = vec![];
+        for participant in &others {
+            let cl = self.clone();
+            let key = key.clone();
+            let participant = participant.clone();
+            arrivals.push(tokio::spawn(async move {
+                cl.recv_variable(&key, &participant).await
+            }));
+        }
+        let deadline = timeout_in_ms.map(|timeout_in_ms| {
+            tokio::time::Instant::now() + tokio::time::Duration::from_millis(timeout_in_ms)
+        });
+        let mut missing = vec![];
+        for (participant, mut arrival) in others.into_iter().zip(arrivals) {
+            match deadline {
+                Some(deadline) => match tokio::time::timeout_at(deadline, &mut arrival).await {
+                    Ok(res) => {
+                        res??;
+                    }
+                    Err(_) => {
+                        arrival.abort();
+                        missing.push(participant);
+                    }
+                },
+                None => {
+                    arrival.await??;
+                }
+            }
+        }
+        Ok(missing)
+    }
+}
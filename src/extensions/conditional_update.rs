@@ -0,0 +1,214 @@
+use crate::{
+    colink_proto::*,
+    utils::{get_path_timestamp, is_not_found_error},
+    KeyPath,
+};
+use rand::Rng;
+use std::future::Future;
+use tracing::error;
+
+type Error = Box<dyn std::error::Error + Send + Sync + 'static>;
+
+/// The number of times update_with retries when the entry is modified concurrently.
+pub(crate) const CAS_MAX_RETRIES: usize = 100;
+
+impl crate::application::CoLink {
+    /// Update an entry only if its latest version is still `expected_version`, which is either a key_path
+    /// returned by create_entry/update_entry or the timestamp in that key_path.
+    ///
+    /// Conditional writers of the same entry exclude each other, but a plain update_entry is not checked and
+    /// is overwritten, so all writers of an entry should use update_entry_if or update_with.
+    pub async fn update_entry_if(
+        &self,
+        key_name: &str,
+        expected_version: &str,
+        payload: &[u8],
+    ) -> Result<String, Error> {
+        let expected_timestamp = match expected_version.rsplit_once('@') {
+            Some((_, timestamp)) => timestamp.parse::<i64>()?,
+            None => expected_version.parse::<i64>()?,
+        };
+        match self
+            ._update_entry_if(key_name, expected_timestamp, payload)
+            .await?
+        {
+            Some(key_path) => Ok(key_path),
+            None => Err(format!(
+                "Compare-and-swap failed: {} has been modified.",
+                key_name
+            ))?,
+        }
+    }
+
+    /// Return Ok(None) if the latest version of the entry is not the expected one.
+    pub(crate) async fn _update_entry_if(
+        &self,
+        key_name: &str,
+        expected_timestamp: i64,
+        payload: &[u8],
+    ) -> Result<Option<String>, Error> {
+        self._write_entry_if(key_name, expected_timestamp, key_name, Some(payload))
+            .await
+    }
+
+    /// Delete an entry only if its latest version is the expected one. Return Ok(None) otherwise.
+    pub(crate) async fn _delete_entry_if(
+        &self,
+        key_name: &str,
+        expected_timestamp: i64,
+    ) -> Result<Option<String>, Error> {
+        self._write_entry_if(key_name, expected_timestamp, key_name, None)
+            .await
+    }
+
+    /// Update `key_name`, or delete it if `payload` is None, only if the latest version of `guard_key` is the
+    /// expected one. The writer that creates the claim entry `_cas_claim:{guard_key}:{expected_timestamp}` is the only
+    /// one that can write under this version, and conditional writes to `guard_key` itself also need the claim,
+    /// so the version cannot be replaced by them during the write. The claim is taken before the version is
+    /// checked, so a writer that claims a version after it was replaced sees the newer one.
+    pub(crate) async fn _write_entry_if(
+        &self,
+        guard_key: &str,
+        expected_timestamp: i64,
+        key_name: &str,
+        payload: Option<&[u8]>,
+    ) -> Result<Option<String>, Error> {
+        if guard_key.contains('$') || key_name.contains('$') {
+            Err("Compare-and-swap is not supported for storage macros.")?
+        }
+        let claim_key = KeyPath::new("_cas_claim")
+            .segment(guard_key)
+            .segment(expected_timestamp)
+            .to_string();
+        if let Err(e) = self.create_entry(&claim_key, b"").await {
+            // the version is being replaced by another writer if the claim exists
+            return match self.read_entry(&claim_key).await {
+                Ok(_) => Ok(None),
+                Err(_) => Err(e),
+            };
+        }
+        // use a closure to prevent claiming forever caused by errors
+        let res = async {
+            let timestamp = match self
+                .read_entries(&[StorageEntry {
+                    key_name: guard_key.to_string(),
+                    ..Default::default()
+                }])
+                .await
+            {
+                Ok(res) => get_path_timestamp(&res[0].key_path),
+                Err(e) if is_not_found_error(&*e) => return Ok(None),
+                Err(e) => return Err(e),
+            };
+            if timestamp != expected_timestamp {
+                return Ok(None);
+            }
+            let key_path = match payload {
+                Some(payload) => self.update_entry(key_name, payload).await?,
+                None => self.delete_entry(key_name).await?,
+            };
+            Ok::<Option<String>, Error>(Some(key_path))
+        }
+        .await;
+        // the result of the write does not depend on the cleanup, and a claim left behind only blocks writes
+        // under the replaced version
+        if let Err(e) = self.delete_entry(&claim_key).await {
+            error!("failed to delete the claim {}: {}", claim_key, e);
+        }
+        res
+    }
+
+    /// Create an entry if it does not exist. Return the payload stored in the entry.
+    pub async fn create_or_get(&self, key_name: &str, payload: &[u8]) -> Result<Vec<u8>, Error> {
+        match self.create_entry(key_name, payload).await {
+            Ok(_) => Ok(payload.to_vec()),
+            Err(_) => self.read_entry(key_name).await,
+        }
+    }
+
+    /// Update an entry with `f`, which takes the current payload (None if the entry does not exist) and returns
+    /// the new payload. `f` is called again whenever the entry is modified concurrently, up to CAS_MAX_RETRIES
+    /// times.
+    pub async fn update_with<F>(&self, key_name: &str, mut f: F) -> Result<String, Error>
+    where
+        F: FnMut(Option<&[u8]>) -> Vec<u8> + Send,
+    {
+        self._update_with(key_name, |current| {
+            let new_payload = f(current.as_deref());
+            async move { Ok(new_payload) }
+        })
+        .await
+    }
+
+    /// update_with for async and fallible updates. An error returned by `f` stops the update.
+    pub(crate) async fn _update_with<F, Fut>(
+        &self,
+        key_name: &str,
+        mut f: F,
+    ) -> Result<String, Error>
+    where
+        F: FnMut(Option<Vec<u8>>) -> Fut + Send,
+        Fut: Future<Output = Result<Vec<u8>, Error>> + Send,
+    {
+        if key_name.contains('$') {
+            Err("Compare-and-swap is not supported for storage macros.")?
+        }
+        let mut sleep_time_cap = 1;
+        for _ in 0..CAS_MAX_RETRIES {
+            let current = match self
+                .read_entries(&[StorageEntry {
+                    key_name: key_name.to_string(),
+                    ..Default::default()
+                }])
+                .await
+            {
+                Ok(mut res) => Some((
+                    std::mem::take(&mut res[0].payload),
+                    get_path_timestamp(&res[0].key_path),
+                )),
+                Err(e) if is_not_found_error(&*e) => None,
+                Err(e) => return Err(e),
+            };
+            match current {
+                Some((payload, timestamp)) => {
+                    let new_payload = f(Some(payload)).await?;
+                    if let Some(key_path) = self
+                        ._update_entry_if(key_name, timestamp, &new_payload)
+                        .await?
+                    {
+                        return Ok(key_path);
+                    }
+                }
+                None => {
+                    let new_payload = f(None).await?;
+                    match self.create_entry(key_name, &new_payload).await {
+                        Ok(key_path) => return Ok(key_path),
+                        // retry only if the entry has been created concurrently
+                        Err(e) => {
+                            if let Err(read_err) = self
+                                .read_entries(&[StorageEntry {
+                                    key_name: key_name.to_string(),
+                                    ..Default::default()
+                                }])
+                                .await
+                            {
+                                return Err(if is_not_found_error(&*read_err) {
+                                    e
+                                } else {
+                                    read_err
+                                });
+                            }
+                        }
+                    }
+                }
+            }
+            let st = rand::thread_rng().gen_range(0..sleep_time_cap);
+            tokio::time::sleep(tokio::time::Duration::from_millis(st)).await;
+            sleep_time_cap = std::cmp::min(sleep_time_cap * 2, 100);
+        }
+        Err(format!(
+            "Compare-and-swap failed: {} is modified concurrently after {} retries.",
+            key_name, CAS_MAX_RETRIES
+        ))?
+    }
+}
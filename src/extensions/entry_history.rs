@@ -0,0 +1,54 @@
+use crate::{utils::get_path_timestamp, KeyPath};
+
+type Error = Box<dyn std::error::Error + Send + Sync + 'static>;
+
+impl crate::application::CoLink {
+    /// List the key_paths of all versions of an entry, ordered from the oldest to the latest.
+    pub async fn list_versions(&self, key_name: &str) -> Result<Vec<String>, Error> {
+        if key_name.contains('$') {
+            Err("Version history is not supported for storage macros.")?
+        }
+        let key_path_prefix = KeyPath::new(key_name)
+            .user_id(&self.get_user_id()?)
+            .to_string();
+        // read_keys is a prefix scan, so the entries under `{key_name}:` and the keys starting with
+        // key_name are filtered out
+        let mut versions = self
+            .read_keys(&key_path_prefix, true)
+            .await?
+            .into_iter()
+            .map(|entry| entry.key_path)
+            .filter(|key_path| match key_path.rsplit_once('@') {
+                Some((key_path_name, _)) => key_path_name == key_path_prefix,
+                None => false,
+            })
+            .collect::<Vec<String>>();
+        versions.sort_by_key(|key_path| get_path_timestamp(key_path));
+        Ok(versions)
+    }
+
+    /// Read the version of an entry that was the latest at `timestamp`.
+    pub async fn read_entry_at(&self, key_name: &str, timestamp: i64) -> Result<Vec<u8>, Error> {
+        let key_path = match self
+            .list_versions(key_name)
+            .await?
+            .into_iter()
+            .rev()
+            .find(|key_path| get_path_timestamp(key_path) <= timestamp)
+        {
+            Some(key_path) => key_path,
+            None => Err(format!(
+                "{} does not have a version at timestamp {}.",
+                key_name, timestamp
+            ))?,
+        };
+        self.read_entry(&key_path).await
+    }
+
+    /// Write the version of an entry that was the latest at `timestamp` as a new version. The history is kept.
+    /// Return the key_path of the new version.
+    pub async fn revert_entry(&self, key_name: &str, timestamp: i64) -> Result<String, Error> {
+        let payload = self.read_entry_at(key_name, timestamp).await?;
+        self.update_entry(key_name, &payload).await
+    }
+}
@@ -0,0 +1,27 @@
+type Error = Box<dyn std::error::Error + Send + Sync + 'static>;
+
+impl crate::application::CoLink {
+    /// Read at most `length` bytes of an entry starting from `offset`. For $chunk, only the chunks covering
+    /// the range are read; $fs and $redis read the range directly.
+    pub async fn read_entry_range(
+        &self,
+        key: &str,
+        offset: u64,
+        length: u64,
+    ) -> Result<Vec<u8>, Error> {
+        if key.contains('$') {
+            #[cfg(feature = "storage_macro")]
+            return self._sm_read_entry_range(key, offset, length).await;
+            #[cfg(not(feature = "storage_macro"))]
+            return Err(format!(
+                "Storage Macro feature not enabled, but found $ symbol in key name: {}",
+                key
+            )
+            .into());
+        }
+        let payload = self.read_entry(key).await?;
+        let start = std::cmp::min(offset, payload.len() as u64) as usize;
+        let end = std::cmp::min(offset.saturating_add(length), payload.len() as u64) as usize;
+        Ok(payload[start..end].to_vec())
+    }
+}
@@ -0,0 +1,48 @@
+use tokio::io::{AsyncRead, AsyncReadExt};
+
+type Error = Box<dyn std::error::Error + Send + Sync + 'static>;
+
+impl crate::application::CoLink {
+    /// Read an entry as a stream. For $chunk and $fs, the entry is read incrementally instead of being loaded into memory.
+    pub async fn read_entry_stream(
+        &self,
+        key: &str,
+    ) -> Result<impl AsyncRead + Send + Unpin, Error> {
+        if key.contains('$') {
+            #[cfg(feature = "storage_macro")]
+            return self._sm_read_entry_stream(key).await;
+            #[cfg(not(feature = "storage_macro"))]
+            return Err(format!(
+                "Storage Macro feature not enabled, but found $ symbol in key name: {}",
+                key
+            )
+            .into());
+        }
+        let payload = self.read_entry(key).await?;
+        Ok(Box::new(std::io::Cursor::new(payload)) as Box<dyn AsyncRead + Send + Unpin>)
+    }
+
+    /// Update an entry with the data from `reader`. For $chunk and $fs, the data is written incrementally.
+    pub async fn write_entry_stream<R>(
+        &self,
+        key_name: &str,
+        mut reader: R,
+    ) -> Result<String, Error>
+    where
+        R: AsyncRead + Send + Unpin,
+    {
+        if key_name.contains('$') {
+            #[cfg(feature = "storage_macro")]
+            return self._sm_write_entry_stream(key_name, &mut reader).await;
+            #[cfg(not(feature = "storage_macro"))]
+            return Err(format!(
+                "Storage Macro feature not enabled, but found $ symbol in key name: {}",
+                key_name
+            )
+            .into());
+        }
+        let mut payload = Vec::new();
+        reader.read_to_end(&mut payload).await?;
+        self.update_entry(key_name, &payload).await
+    }
+}
@@ -0,0 +1,240 @@
+use crate::{
+    application::CoLink,
+    colink_proto::*,
+    utils::{get_path_timestamp, is_not_found_error},
+    KeyPath,
+};
+use serde::{Deserialize, Serialize};
+use tokio::sync::watch;
+
+type Error = Box<dyn std::error::Error + Send + Sync + 'static>;
+
+#[derive(Serialize, Deserialize)]
+struct LeaderLease {
+    holder_id: String,
+    lease_duration_in_ms: u64,
+}
+
+impl crate::application::CoLink {
+    /// The default lease duration is 10 s. If you want to specify a lease duration, use elect_leader_with_lease_duration instead.
+    pub async fn elect_leader(&self, group: &str) -> Result<CoLinkLeadership, Error> {
+        self.elect_leader_with_lease_duration(group, 10000).await
+    }
+
+    /// Wait until this instance becomes the leader of `group`. The leader keeps renewing its lease in the background;
+    /// the lease of a leader that stops renewing expires after `lease_duration_in_ms`.
+    pub async fn elect_leader_with_lease_duration(
+        &self,
+        group: &str,
+        lease_duration_in_ms: u64,
+    ) -> Result<CoLinkLeadership, Error> {
+        let lease_key = KeyPath::new("_leader_election")
+            .escaped(group)
+            .segment("lease")
+            .to_string();
+        let holder_id = uuid::Uuid::new_v4().to_string();
+        let payload = serde_json::to_vec(&LeaderLease {
+            holder_id: holder_id.clone(),
+            lease_duration_in_ms,
+        })?;
+        // the expiration of a lease is measured with the local clock from the time its version is first seen,
+        // so it does not depend on the clock of the core
+        let mut observed_lease: Option<(String, tokio::time::Instant)> = None;
+        let (key_path, acquired_at) = loop {
+            let res = self
+                .read_entries(&[StorageEntry {
+                    key_name: lease_key.clone(),
+                    ..Default::default()
+                }])
+                .await;
+            let lease_entry = match res {
+                Ok(res) => res[0].clone(),
+                Err(e) if is_not_found_error(&*e) => {
+                    let acquired_at = tokio::time::Instant::now();
+                    if let Ok(key_path) = self.create_entry(&lease_key, &payload).await {
+                        break (key_path, acquired_at);
+                    }
+                    continue;
+                }
+                Err(e) => return Err(e),
+            };
+            let lease: LeaderLease = serde_json::from_slice(&lease_entry.payload)?;
+            let lease_duration = tokio::time::Duration::from_millis(lease.lease_duration_in_ms);
+            let observed_at = match &observed_lease {
+                Some((key_path, observed_at)) if *key_path == lease_entry.key_path => *observed_at,
+                _ => {
+                    let observed_at = tokio::time::Instant::now();
+                    observed_lease = Some((lease_entry.key_path.clone(), observed_at));
+                    observed_at
+                }
+            };
+            if observed_at.elapsed() >= lease_duration {
+                let acquired_at = tokio::time::Instant::now();
+                if let Some(key_path) = self
+                    ._update_entry_if(
+                        &lease_key,
+                        get_path_timestamp(&lease_entry.key_path),
+                        &payload,
+                    )
+                    .await?
+                {
+                    break (key_path, acquired_at);
+                }
+                continue;
+            }
+            // wait for the current leader to renew or resign, or for its lease to expire
+            let queue_name = self
+                .subscribe(
+                    &lease_key,
+                    Some(get_path_timestamp(&lease_entry.key_path) + 1),
+                )
+                .await?;
+            let mut subscriber = self.new_subscriber(&queue_name).await?;
+            let _ = tokio::time::timeout(
+                lease_duration.saturating_sub(observed_at.elapsed()),
+                subscriber.get_next(),
+            )
+            .await;
+            self.unsubscribe(&queue_name).await?;
+        };
+
+        let (lost_sender, lost_receiver) = watch::channel(false);
+        let cl = self.clone();
+        let renewal_lease_key = lease_key.clone();
+        let renewal_holder_id = holder_id.clone();
+        let renewal_task = tokio::spawn(async move {
+            let lease_duration = tokio::time::Duration::from_millis(lease_duration_in_ms);
+            let mut key_path = key_path;
+            // the lease is valid for lease_duration after the start of the last successful renewal
+            let mut last_successful_renewal = acquired_at;
+            loop {
+                let remaining = lease_duration.saturating_sub(last_successful_renewal.elapsed());
+                if remaining.is_zero() {
+                    break;
+                }
+                tokio::time::sleep(std::cmp::min(lease_duration / 3, remaining)).await;
+                let renewal_start = tokio::time::Instant::now();
+                let remaining = lease_duration.saturating_sub(last_successful_renewal.elapsed());
+                let res = tokio::time::timeout(
+                    remaining,
+                    cl._renew_lease(&renewal_lease_key, &renewal_holder_id, &key_path, &payload),
+                )
+                .await;
+                match res {
+                    Ok(Ok(Some(new_key_path))) => {
+                        key_path = new_key_path;
+                        last_successful_renewal = renewal_start;
+                    }
+                    // the lease has been taken over by another instance
+                    Ok(Ok(None)) => break,
+                    // keep retrying until the lease could have expired
+                    Ok(Err(_)) | Err(_) => {}
+                }
+            }
+            let _ = lost_sender.send(true);
+        });
+        Ok(CoLinkLeadership {
+            cl: self.clone(),
+            lease_key,
+            holder_id,
+            lost_receiver,
+            renewal_task,
+        })
+    }
+}
+
+impl crate::application::CoLink {
+    /// Renew the lease at `key_path`. Return Ok(None) if the lease is held by another instance.
+    async fn _renew_lease(
+        &self,
+        lease_key: &str,
+        holder_id: &str,
+        key_path: &str,
+        payload: &[u8],
+    ) -> Result<Option<String>, Error> {
+        if let Some(new_key_path) = self
+            ._update_entry_if(lease_key, get_path_timestamp(key_path), payload)
+            .await?
+        {
+            return Ok(Some(new_key_path));
+        }
+        // the version may be claimed by a follower that sees the same lease, so check the holder
+        let res = self
+            .read_entries(&[StorageEntry {
+                key_name: lease_key.to_string(),
+                ..Default::default()
+            }])
+            .await;
+        match res {
+            Ok(res) => {
+                let lease: LeaderLease = serde_json::from_slice(&res[0].payload)?;
+                if lease.holder_id != holder_id {
+                    return Ok(None);
+                }
+                if res[0].key_path == key_path {
+                    Err("The lease is being checked by another instance.")?
+                }
+                // a previous renewal succeeded without a response, so renew the latest version
+                match self
+                    ._update_entry_if(lease_key, get_path_timestamp(&res[0].key_path), payload)
+                    .await?
+                {
+                    Some(new_key_path) => Ok(Some(new_key_path)),
+                    None => Err("The lease is modified concurrently.")?,
+                }
+            }
+            Err(e) if is_not_found_error(&*e) => Ok(None),
+            Err(e) => Err(e),
+        }
+    }
+}
+
+pub struct CoLinkLeadership {
+    cl: CoLink,
+    lease_key: String,
+    holder_id: String,
+    lost_receiver: watch::Receiver<bool>,
+    renewal_task: tokio::task::JoinHandle<()>,
+}
+
+impl CoLinkLeadership {
+    pub fn is_leader(&self) -> bool {
+        !*self.lost_receiver.borrow()
+    }
+
+    /// Wait until the leadership is lost, e.g. because the lease could not be renewed in time.
+    pub async fn lost(&self) {
+        let mut lost_receiver = self.lost_receiver.clone();
+        while !*lost_receiver.borrow() {
+            if lost_receiver.changed().await.is_err() {
+                break;
+            }
+        }
+    }
+
+    /// Stop renewing the lease and release it so that another instance can take over immediately.
+    pub async fn resign(self) -> Result<(), Error> {
+        self.renewal_task.abort();
+        let res = self
+            .cl
+            .read_entries(&[StorageEntry {
+                key_name: self.lease_key.clone(),
+                ..Default::default()
+            }])
+            .await?;
+        let lease: LeaderLease = serde_json::from_slice(&res[0].payload)?;
+        if lease.holder_id == self.holder_id {
+            // the lease is only deleted if it has not been taken over in the meantime
+            self.cl
+                ._delete_entry_if(&self.lease_key, get_path_timestamp(&res[0].key_path))
+                .await?;
+        }
+        Ok(())
+    }
+}
+
+impl Drop for CoLinkLeadership {
+    fn drop(&mut self) {
+        self.renewal_task.abort();
+    }
+}
@@ -1,13 +1,27 @@
+use crate::{
+    colink_proto::*, extensions::conditional_update::CAS_MAX_RETRIES, utils::is_not_found_error,
+    KeyPath,
+};
 use rand::Rng;
+use std::sync::atomic::{AtomicBool, Ordering};
 
 type Error = Box<dyn std::error::Error + Send + Sync + 'static>;
 
+/// The key used by lock, where `$` is replaced so that the lock entry is not handled by a storage macro.
+fn _lock_key(key: &str) -> String {
+    #[cfg(feature = "storage_macro")]
+    let key = key.replace('$', "_lock_dollar_");
+    key.to_string()
+}
+
+fn _lock_entry(key: &str) -> String {
+    KeyPath::new("_lock").segment(key).to_string()
+}
+
 impl crate::application::CoLink {
     /// The default retry time cap is 100 ms. If you want to specify a retry time cap, use lock_with_retry_time instead.
     pub async fn lock(&self, key: &str) -> Result<CoLinkLockToken, Error> {
-        #[cfg(feature = "storage_macro")]
-        let key = &key.replace('$', "_lock_dollar_");
-        self.lock_with_retry_time(key, 100).await
+        self.lock_with_retry_time(&_lock_key(key), 100).await
     }
 
     pub async fn lock_with_retry_time(
@@ -17,13 +31,19 @@ impl crate::application::CoLink {
     ) -> Result<CoLinkLockToken, Error> {
         let mut sleep_time_cap = 1;
         let rnd_num = rand::thread_rng().gen::<i32>();
-        loop {
-            if self
-                .create_entry(&format!("_lock:{}", key), &rnd_num.to_le_bytes())
+        let fencing_token = loop {
+            if let Ok(key_path) = self
+                .create_entry(&_lock_entry(key), &rnd_num.to_le_bytes())
                 .await
-                .is_ok()
             {
-                break;
+                // the timestamp of the lock entry increases with every acquisition of the same lock
+                match key_path
+                    .rsplit_once('@')
+                    .and_then(|(_, timestamp)| timestamp.parse::<i64>().ok())
+                {
+                    Some(timestamp) => break timestamp,
+                    None => Err(format!("Invalid lock key: {}", key))?,
+                }
             }
             let st = rand::thread_rng().gen_range(0..sleep_time_cap);
             tokio::time::sleep(tokio::time::Duration::from_millis(st)).await;
@@ -31,25 +51,104 @@ impl crate::application::CoLink {
             if sleep_time_cap > retry_time_cap_in_ms {
                 sleep_time_cap = retry_time_cap_in_ms;
             }
-        }
+        };
         Ok(CoLinkLockToken {
             key: key.to_string(),
             rnd_num,
+            fencing_token,
+            fenced: AtomicBool::new(false),
         })
     }
 
     pub async fn unlock(&self, lock_token: CoLinkLockToken) -> Result<(), Error> {
-        let rnd_num_in_storage = self
-            .read_entry(&format!("_lock:{}", lock_token.key))
-            .await?;
+        let lock_key = _lock_entry(&lock_token.key);
+        let rnd_num_in_storage = self.read_entry(&lock_key).await?;
         let rnd_num_in_storage =
             i32::from_le_bytes(<[u8; 4]>::try_from(rnd_num_in_storage).unwrap());
-        if rnd_num_in_storage == lock_token.rnd_num {
-            self.delete_entry(&format!("_lock:{}", lock_token.key))
-                .await?;
-        } else {
+        if rnd_num_in_storage != lock_token.rnd_num {
             Err("Invalid token.")?
         }
+        if !lock_token.fenced.load(Ordering::SeqCst) {
+            self.delete_entry(&lock_key).await?;
+            return Ok(());
+        }
+        // the lock entry is deleted under the claim of its version, so that it is not released while a fenced
+        // write of this holder is in flight
+        for _ in 0..CAS_MAX_RETRIES {
+            if self
+                ._delete_entry_if(&lock_key, lock_token.fencing_token)
+                .await?
+                .is_some()
+            {
+                return Ok(());
+            }
+            self._check_fencing_token(&lock_token).await?;
+            tokio::time::sleep(tokio::time::Duration::from_millis(1)).await;
+        }
+        Err(format!(
+            "Failed to unlock {} after {} retries.",
+            lock_token.key, CAS_MAX_RETRIES
+        ))?
+    }
+
+    /// Update an entry protected by the lock of `lock_token`. The write is rejected if the lock is no longer
+    /// held with this fencing token, which happens when the lock was taken over by another holder. The write
+    /// holds the claim of the lock version (see update_entry_if), so the lock cannot be released and taken over
+    /// through unlock while the write is in flight.
+    pub async fn update_entry_if_fenced(
+        &self,
+        key_name: &str,
+        payload: &[u8],
+        lock_token: &CoLinkLockToken,
+    ) -> Result<String, Error> {
+        let lock_key = _lock_entry(&lock_token.key);
+        lock_token.fenced.store(true, Ordering::SeqCst);
+        for _ in 0..CAS_MAX_RETRIES {
+            if let Some(key_path) = self
+                ._write_entry_if(&lock_key, lock_token.fencing_token, key_name, Some(payload))
+                .await?
+            {
+                return Ok(key_path);
+            }
+            // the claim is taken by another write of this holder if the lock is still held
+            self._check_fencing_token(lock_token).await?;
+            tokio::time::sleep(tokio::time::Duration::from_millis(1)).await;
+        }
+        Err(format!(
+            "Compare-and-swap failed: the lock {} is used concurrently after {} retries.",
+            lock_token.key, CAS_MAX_RETRIES
+        ))?
+    }
+
+    /// Check that the latest acquisition of the lock is the one of `lock_token`.
+    async fn _check_fencing_token(&self, lock_token: &CoLinkLockToken) -> Result<(), Error> {
+        let latest_fencing_token = match self
+            .read_entries(&[StorageEntry {
+                key_name: _lock_entry(&lock_token.key),
+                ..Default::default()
+            }])
+            .await
+        {
+            Ok(res) => match res[0]
+                .key_path
+                .rsplit_once('@')
+                .and_then(|(_, timestamp)| timestamp.parse::<i64>().ok())
+            {
+                Some(timestamp) => timestamp,
+                None => Err(format!("Invalid lock key path: {}", res[0].key_path))?,
+            },
+            Err(e) if is_not_found_error(&*e) => Err(format!(
+                "Stale fencing token: the lock {} has been released.",
+                lock_token.key
+            ))?,
+            Err(e) => return Err(e),
+        };
+        if latest_fencing_token != lock_token.fencing_token {
+            Err(format!(
+                "Stale fencing token: {} is not the latest token {}.",
+                lock_token.fencing_token, latest_fencing_token
+            ))?
+        }
         Ok(())
     }
 }
@@ -57,4 +156,14 @@ impl crate::application::CoLink {
 pub struct CoLinkLockToken {
     key: String,
     rnd_num: i32,
+    fencing_token: i64,
+    // whether update_entry_if_fenced has been called with this token
+    fenced: AtomicBool,
+}
+
+impl CoLinkLockToken {
+    /// A token that increases monotonically with each acquisition of the same lock.
+    pub fn fencing_token(&self) -> i64 {
+        self.fencing_token
+    }
 }
@@ -10,7 +10,7 @@ type Error = Box<dyn std::error::Error + Send + Sync + 'static>;
 impl crate::application::CoLink {
     pub async fn policy_module_start(&self) -> Result<(), Error> {
         let lock = self.lock("_policy_module:settings").await?;
-        let (mut settings, timestamp): (Settings, i64) = match self
+        let (settings, timestamp): (Settings, i64) = match self
             .read_entries(&[StorageEntry {
                 key_name: "_policy_module:settings".to_string(),
                 ..Default::default()
@@ -27,14 +27,16 @@ impl crate::application::CoLink {
             self.unlock(lock).await?;
             return self.wait_for_applying(timestamp).await; // Wait for the current timestamp to be applied.
         }
-        settings.enable = true;
-        let mut payload = vec![];
-        settings.encode(&mut payload).unwrap();
-        let timestamp = get_path_timestamp(
-            &self
-                .update_entry("_policy_module:settings", &payload)
-                .await?,
-        );
+        let timestamp = match self
+            ._update_policy_module_settings(|settings| settings.enable = true)
+            .await
+        {
+            Ok((_, timestamp)) => timestamp,
+            Err(e) => {
+                self.unlock(lock).await?;
+                return Err(e);
+            }
+        };
         self.unlock(lock).await?;
         let participants = vec![Participant {
             user_id: self.get_user_id()?,
@@ -47,7 +49,7 @@ impl crate::application::CoLink {
 
     pub async fn policy_module_stop(&self) -> Result<(), Error> {
         let lock = self.lock("_policy_module:settings").await?;
-        let mut settings: Settings = match self.read_entry("_policy_module:settings").await {
+        let settings: Settings = match self.read_entry("_policy_module:settings").await {
             Ok(res) => prost::Message::decode(&*res)?,
             Err(_) => Default::default(),
         };
@@ -55,15 +57,13 @@ impl crate::application::CoLink {
             self.unlock(lock).await?;
             return Ok(()); // Return directly here because we only release the lock after the policy module truly stopped.
         }
-        settings.enable = false;
-        let mut payload = vec![];
-        settings.encode(&mut payload).unwrap();
-        let timestamp = get_path_timestamp(
-            &self
-                .update_entry("_policy_module:settings", &payload)
-                .await?,
-        );
-        let res = self.wait_for_applying(timestamp).await;
+        let res = async {
+            let (_, timestamp) = self
+                ._update_policy_module_settings(|settings| settings.enable = false)
+                .await?;
+            self.wait_for_applying(timestamp).await
+        }
+        .await;
         self.unlock(lock).await?; // Unlock after the policy module truly stopped.
         res
     }
@@ -77,23 +77,12 @@ impl crate::application::CoLink {
     }
 
     pub async fn policy_module_add_rule(&self, rule: &Rule) -> Result<String, Error> {
-        let lock = self.lock("_policy_module:settings").await?;
-        let mut settings: Settings = match self.read_entry("_policy_module:settings").await {
-            Ok(res) => prost::Message::decode(&*res)?,
-            Err(_) => Default::default(),
-        };
         let rule_id = uuid::Uuid::new_v4().to_string();
         let mut rule = rule.clone();
         rule.rule_id = rule_id.clone();
-        settings.rules.push(rule);
-        let mut payload = vec![];
-        settings.encode(&mut payload).unwrap();
-        let timestamp = get_path_timestamp(
-            &self
-                .update_entry("_policy_module:settings", &payload)
-                .await?,
-        );
-        self.unlock(lock).await?;
+        let (settings, timestamp) = self
+            ._update_policy_module_settings(|settings| settings.rules.push(rule.clone()))
+            .await?;
         if settings.enable {
             self.wait_for_applying(timestamp).await?;
         }
@@ -101,26 +90,43 @@ impl crate::application::CoLink {
     }
 
     pub async fn policy_module_remove_rule(&self, rule_id: &str) -> Result<(), Error> {
-        let lock = self.lock("_policy_module:settings").await?;
-        let mut settings: Settings = match self.read_entry("_policy_module:settings").await {
-            Ok(res) => prost::Message::decode(&*res)?,
-            Err(_) => Default::default(),
-        };
-        settings.rules.retain(|x| x.rule_id != rule_id);
-        let mut payload = vec![];
-        settings.encode(&mut payload).unwrap();
-        let timestamp = get_path_timestamp(
-            &self
-                .update_entry("_policy_module:settings", &payload)
-                .await?,
-        );
-        self.unlock(lock).await?;
+        let (settings, timestamp) = self
+            ._update_policy_module_settings(|settings| {
+                settings.rules.retain(|x| x.rule_id != rule_id)
+            })
+            .await?;
         if settings.enable {
             self.wait_for_applying(timestamp).await?;
         }
         Ok(())
     }
 
+    /// Apply `f` to the settings with compare-and-swap, so that concurrent updates are not lost. Return the new
+    /// settings and the timestamp of the update.
+    async fn _update_policy_module_settings<F>(&self, mut f: F) -> Result<(Settings, i64), Error>
+    where
+        F: FnMut(&mut Settings) + Send,
+    {
+        let mut new_settings = Settings::default();
+        let key_path = self
+            ._update_with("_policy_module:settings", |current| {
+                let res = match current {
+                    Some(current) => Settings::decode(&*current).map_err(|e| e.into()),
+                    None => Ok(Settings::default()),
+                }
+                .map(|mut settings: Settings| {
+                    f(&mut settings);
+                    let mut payload = vec![];
+                    settings.encode(&mut payload).unwrap();
+                    new_settings = settings;
+                    payload
+                });
+                async move { res }
+            })
+            .await?;
+        Ok((new_settings, get_path_timestamp(&key_path)))
+    }
+
     async fn wait_for_applying(&self, timestamp: i64) -> Result<(), Error> {
         let key = "_policy_module:applied_settings_timestamp";
         let start_timestamp = match self
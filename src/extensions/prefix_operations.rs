@@ -0,0 +1,96 @@
+use crate::{extensions::storage_macro::_is_chunk_suffix, key_path::_split_key_path, KeyPath};
+
+type Error = Box<dyn std::error::Error + Send + Sync + 'static>;
+
+impl crate::application::CoLink {
+    /// Delete the entry `prefix` and all entries under `{prefix}:`. Chunked entries are deleted with their chunks.
+    /// If the prefix contains a storage macro, the keys listed by the storage macro are deleted.
+    /// Return the number of deleted entries.
+    pub async fn delete_prefix(&self, prefix: &str) -> Result<usize, Error> {
+        let key_names = self._list_prefix(prefix).await?;
+        for key_name in &key_names {
+            self.delete_entry(key_name).await?;
+        }
+        Ok(key_names.len())
+    }
+
+    /// Copy the latest version of an entry to `to_key_name`, overwriting it if it exists. The payload is streamed,
+    /// so $chunk and $fs entries are not loaded into memory. Return the key_path of the copy.
+    pub async fn copy_entry(
+        &self,
+        from_key_name: &str,
+        to_key_name: &str,
+    ) -> Result<String, Error> {
+        let reader = self.read_entry_stream(from_key_name).await?;
+        self.write_entry_stream(to_key_name, reader).await
+    }
+
+    /// Copy the entry `from_prefix` and all entries under `{from_prefix}:` to the same names under `to_prefix`.
+    /// Chunked entries are copied as a whole. Return the number of copied entries.
+    pub async fn copy_prefix(&self, from_prefix: &str, to_prefix: &str) -> Result<usize, Error> {
+        let key_names = self._list_prefix(from_prefix).await?;
+        for key_name in &key_names {
+            let suffix = match key_name.strip_prefix(from_prefix) {
+                Some(suffix) => suffix,
+                None => Err(format!("{} is not under {}.", key_name, from_prefix))?,
+            };
+            self.copy_entry(key_name, &format!("{}{}", to_prefix, suffix))
+                .await?;
+        }
+        Ok(key_names.len())
+    }
+
+    /// Move an entry to `to_key_name`. The entry is copied and then deleted, so this is not atomic.
+    pub async fn rename_entry(
+        &self,
+        from_key_name: &str,
+        to_key_name: &str,
+    ) -> Result<String, Error> {
+        let key_path = self.copy_entry(from_key_name, to_key_name).await?;
+        self.delete_entry(from_key_name).await?;
+        Ok(key_path)
+    }
+
+    /// List the key names of the entries in a prefix, or of all entries if the prefix is empty. The chunks of
+    /// a chunked entry are replaced by the entry itself, `{key_name}:$chunk`.
+    pub(crate) async fn _list_prefix(&self, prefix: &str) -> Result<Vec<String>, Error> {
+        let entries = self
+            .read_keys(
+                &KeyPath::new(prefix)
+                    .user_id(&self.get_user_id()?)
+                    .to_string(),
+                false,
+            )
+            .await?;
+        let mut key_names = entries
+            .iter()
+            .map(|entry| _split_key_path(&entry.key_path).1.to_string())
+            .collect::<Vec<String>>();
+        if prefix.contains('$') {
+            return Ok(key_names);
+        }
+        let key_name_prefix = format!("{}:", prefix);
+        if !prefix.is_empty() {
+            key_names.retain(|name| name == prefix || name.starts_with(&key_name_prefix));
+        }
+        let chunked_key_names = key_names
+            .iter()
+            .filter_map(|name| name.strip_suffix(":chunk_metadata"))
+            .map(|name| name.to_string())
+            .collect::<Vec<String>>();
+        key_names.retain(|name| {
+            !chunked_key_names.iter().any(|chunked_key_name| {
+                match name.strip_prefix(&format!("{}:", chunked_key_name)) {
+                    Some(suffix) => _is_chunk_suffix(suffix),
+                    None => false,
+                }
+            })
+        });
+        key_names.extend(
+            chunked_key_names
+                .into_iter()
+                .map(|name| KeyPath::new(&name).storage_macro("chunk").to_string()),
+        );
+        Ok(key_names)
+    }
+}
@@ -0,0 +1,449 @@
+use lapin::{
+    options::{BasicCancelOptions, BasicConsumeOptions},
+    types::FieldTable,
+    ConnectionProperties,
+};
+use redis::streams::{StreamReadOptions, StreamReadReply};
+use std::{
+    collections::HashMap,
+    sync::{Arc, Mutex, Weak},
+    time::{Duration, Instant},
+};
+use tokio::sync::mpsc;
+
+type Error = Box<dyn std::error::Error + Send + Sync + 'static>;
+
+/// Entries used for coordination must always be read from the core, so they are never cached.
+const UNCACHED_PREFIXES: [&str; 4] = ["_lock:", "_cas_store:", "_cas_claim:", "_internal:"];
+
+/// Requests to the task that consumes the subscriptions of the cached key names.
+enum WatchRequest {
+    Watch {
+        key: String,
+        start_timestamp: i64,
+        generation: u64,
+    },
+    Unwatch {
+        key: String,
+    },
+}
+
+struct CachedEntry {
+    payload: Vec<u8>,
+    inserted_at: Instant,
+    last_used: u64,
+    generation: u64,
+    // the entry is invalidated by a subscription, which is stopped when the entry is removed
+    watched: bool,
+}
+
+#[derive(Default)]
+struct CacheState {
+    entries: HashMap<String, CachedEntry>,
+    size: usize,
+    clock: u64,
+    watcher: Option<mpsc::UnboundedSender<WatchRequest>>,
+}
+
+/// A local cache for read_entry on core keys, bounded by the total payload size and the age of the entries.
+/// Key names are invalidated by local writes and by subscribing to them. Key paths with timestamps never change,
+/// so they only expire.
+pub(crate) struct ReadCache {
+    prefixes: Vec<String>,
+    capacity: usize,
+    ttl: Duration,
+    state: Mutex<CacheState>,
+}
+
+impl ReadCache {
+    fn is_cached(&self, key: &str) -> bool {
+        let key_name = match key.split_once("::") {
+            Some((_, key_name)) => key_name.split('@').next().unwrap_or_default(),
+            None => key,
+        };
+        !UNCACHED_PREFIXES
+            .iter()
+            .any(|prefix| key_name.starts_with(prefix))
+            && self
+                .prefixes
+                .iter()
+                .any(|prefix| key_name.starts_with(prefix.as_str()))
+    }
+
+    fn get(&self, key: &str) -> Option<Vec<u8>> {
+        let mut state = self.state.lock().unwrap();
+        // the subscription task may be dropped without stopping, e.g. when its runtime shuts down
+        if matches!(&state.watcher, Some(watcher) if watcher.is_closed()) {
+            Self::remove_watcher(&mut state);
+        }
+        state.clock += 1;
+        let clock = state.clock;
+        let expired = match state.entries.get_mut(key) {
+            Some(entry) if entry.inserted_at.elapsed() < self.ttl => {
+                entry.last_used = clock;
+                return Some(entry.payload.clone());
+            }
+            Some(_) => true,
+            None => false,
+        };
+        if expired {
+            Self::remove_entry(&mut state, key);
+        }
+        None
+    }
+
+    /// Return the generation of the cached entry, or None if it is not cached. With `start_timestamp`, the entry
+    /// is watched by the subscription task, which must be running.
+    fn insert(&self, key: &str, payload: &[u8], start_timestamp: Option<i64>) -> Option<u64> {
+        let mut state = self.state.lock().unwrap();
+        Self::remove_entry(&mut state, key);
+        if payload.len() > self.capacity {
+            return None;
+        }
+        // evict the least recently used entries
+        while state.size + payload.len() > self.capacity {
+            let lru = state
+                .entries
+                .iter()
+                .min_by_key(|(_, entry)| entry.last_used)
+                .map(|(key, _)| key.clone());
+            match lru {
+                Some(lru) => Self::remove_entry(&mut state, &lru),
+                None => break,
+            }
+        }
+        state.clock += 1;
+        let clock = state.clock;
+        if let Some(start_timestamp) = start_timestamp {
+            let request = WatchRequest::Watch {
+                key: key.to_string(),
+                start_timestamp,
+                generation: clock,
+            };
+            match &state.watcher {
+                Some(watcher) if watcher.send(request).is_ok() => {}
+                _ => return None,
+            }
+        }
+        state.size += payload.len();
+        state.entries.insert(
+            key.to_string(),
+            CachedEntry {
+                payload: payload.to_vec(),
+                inserted_at: Instant::now(),
+                last_used: clock,
+                generation: clock,
+                watched: start_timestamp.is_some(),
+            },
+        );
+        Some(clock)
+    }
+
+    fn invalidate(&self, key: &str, generation: Option<u64>) {
+        let mut state = self.state.lock().unwrap();
+        match (state.entries.get(key), generation) {
+            (Some(entry), Some(generation)) if entry.generation != generation => {}
+            _ => Self::remove_entry(&mut state, key),
+        }
+    }
+
+    fn clear(&self) {
+        let mut state = self.state.lock().unwrap();
+        let keys = state.entries.keys().cloned().collect::<Vec<String>>();
+        for key in keys {
+            Self::remove_entry(&mut state, &key);
+        }
+    }
+
+    /// Return the receiver of the requests if the subscription task is not running and should be started.
+    fn start_watcher(&self) -> Option<mpsc::UnboundedReceiver<WatchRequest>> {
+        let mut state = self.state.lock().unwrap();
+        match &state.watcher {
+            Some(watcher) if !watcher.is_closed() => return None,
+            Some(_) => Self::remove_watcher(&mut state),
+            None => {}
+        }
+        let (sender, receiver) = mpsc::unbounded_channel();
+        state.watcher = Some(sender);
+        Some(receiver)
+    }
+
+    /// Called when the subscription task stops. The key names it watched can no longer be invalidated.
+    fn stop_watcher(&self) {
+        let mut state = self.state.lock().unwrap();
+        Self::remove_watcher(&mut state);
+    }
+
+    fn remove_watcher(state: &mut CacheState) {
+        state.watcher = None;
+        state.entries.retain(|_, entry| !entry.watched);
+        state.size = state
+            .entries
+            .values()
+            .map(|entry| entry.payload.len())
+            .sum();
+    }
+
+    fn remove_entry(state: &mut CacheState, key: &str) {
+        if let Some(entry) = state.entries.remove(key) {
+            state.size -= entry.payload.len();
+            if entry.watched {
+                if let Some(watcher) = &state.watcher {
+                    let _ = watcher.send(WatchRequest::Unwatch {
+                        key: key.to_string(),
+                    });
+                }
+            }
+        }
+    }
+}
+
+impl crate::application::CoLink {
+    /// Enable a local read-through cache for read_entry on the keys under `prefixes`, holding at most `capacity`
+    /// bytes of payloads for at most `ttl`. The cache is shared by the clones of this CoLink. Keys with storage
+    /// macros are not cached, but the core keys read by the storage macros are. Entries used for coordination
+    /// (`_lock:`, `_cas_store:`, `_cas_claim:` and `_internal:`) are never cached.
+    pub fn read_cache(mut self, prefixes: &[&str], capacity: usize, ttl: Duration) -> Self {
+        self.read_cache = Some(Arc::new(ReadCache {
+            prefixes: prefixes.iter().map(|x| x.to_string()).collect(),
+            capacity,
+            ttl,
+            state: Mutex::new(CacheState::default()),
+        }));
+        self
+    }
+
+    /// Remove all entries from the read cache.
+    pub fn clear_read_cache(&self) {
+        if let Some(cache) = &self.read_cache {
+            cache.clear();
+        }
+    }
+
+    pub(crate) fn _read_cache_get(&self, key: &str) -> Option<Vec<u8>> {
+        self.read_cache.as_ref()?.get(key)
+    }
+
+    /// Cache the payload of the version read. For key names, the cached entry is invalidated by a subscription to
+    /// the updates since `start_timestamp`, the timestamp right after that version.
+    pub(crate) fn _read_cache_insert(&self, key: &str, payload: &[u8], start_timestamp: i64) {
+        let cache = match &self.read_cache {
+            Some(cache) if cache.is_cached(key) => cache,
+            _ => return,
+        };
+        if key.contains("::") {
+            if key.contains('@') {
+                cache.insert(key, payload, None);
+            }
+            return;
+        }
+        if let Some(requests) = cache.start_watcher() {
+            let mut cl = self.clone();
+            cl.read_cache = None;
+            let cache = Arc::downgrade(cache);
+            tokio::spawn(async move {
+                let _ = cl._read_cache_watch(requests, &cache).await;
+                if let Some(cache) = cache.upgrade() {
+                    cache.stop_watcher();
+                }
+            });
+        }
+        cache.insert(key, payload, Some(start_timestamp));
+    }
+
+    pub(crate) fn _read_cache_invalidate(&self, key_name: &str) {
+        if let Some(cache) = &self.read_cache {
+            cache.invalidate(key_name, None);
+        }
+    }
+
+    /// Subscribe to the cached key names and invalidate them on updates. The subscriptions of all key names are
+    /// consumed over one MQ connection, and stopped when the cached entries are removed.
+    async fn _read_cache_watch(
+        &self,
+        requests: mpsc::UnboundedReceiver<WatchRequest>,
+        cache: &Weak<ReadCache>,
+    ) -> Result<(), Error> {
+        let mq_uri = self.request_info().await?.mq_uri;
+        // queue name -> (key name, generation)
+        let mut queues = HashMap::new();
+        let res = if url::Url::parse(&mq_uri)?.scheme().starts_with("redis") {
+            self._read_cache_watch_redis(&mq_uri, requests, cache, &mut queues)
+                .await
+        } else {
+            self._read_cache_watch_rabbitmq(&mq_uri, requests, cache, &mut queues)
+                .await
+        };
+        for queue_name in queues.keys() {
+            let _ = self.unsubscribe(queue_name).await;
+        }
+        res
+    }
+
+    /// Subscribe to the key name for a Watch request. Return the queue name, or None if the entry is invalidated.
+    async fn _read_cache_subscribe(
+        &self,
+        key: &str,
+        start_timestamp: i64,
+        generation: u64,
+        cache: &Weak<ReadCache>,
+    ) -> Option<String> {
+        match self.subscribe(key, Some(start_timestamp)).await {
+            Ok(queue_name) => Some(queue_name),
+            Err(_) => {
+                if let Some(cache) = cache.upgrade() {
+                    cache.invalidate(key, Some(generation));
+                }
+                None
+            }
+        }
+    }
+
+    async fn _read_cache_watch_rabbitmq(
+        &self,
+        mq_uri: &str,
+        mut requests: mpsc::UnboundedReceiver<WatchRequest>,
+        cache: &Weak<ReadCache>,
+        queues: &mut HashMap<String, (String, u64)>,
+    ) -> Result<(), Error> {
+        let mq = lapin::Connection::connect(mq_uri, ConnectionProperties::default()).await?;
+        let channel = mq.create_channel().await?;
+        while let Some(request) = requests.recv().await {
+            match request {
+                WatchRequest::Watch {
+                    key,
+                    start_timestamp,
+                    generation,
+                } => {
+                    let queue_name = match self
+                        ._read_cache_subscribe(&key, start_timestamp, generation, cache)
+                        .await
+                    {
+                        Some(queue_name) => queue_name,
+                        None => continue,
+                    };
+                    queues.insert(queue_name.clone(), (key.clone(), generation));
+                    let consumer = channel
+                        .basic_consume(
+                            &queue_name,
+                            &queue_name,
+                            BasicConsumeOptions {
+                                no_ack: true,
+                                ..Default::default()
+                            },
+                            FieldTable::default(),
+                        )
+                        .await?;
+                    let cache = cache.clone();
+                    consumer.set_delegate(move |_| {
+                        let cache = cache.clone();
+                        let key = key.clone();
+                        async move {
+                            if let Some(cache) = cache.upgrade() {
+                                cache.invalidate(&key, Some(generation));
+                            }
+                        }
+                    });
+                }
+                WatchRequest::Unwatch { key } => {
+                    let queue_name = queues
+                        .iter()
+                        .find(|(_, (watched_key, _))| *watched_key == key)
+                        .map(|(queue_name, _)| queue_name.clone());
+                    if let Some(queue_name) = queue_name {
+                        queues.remove(&queue_name);
+                        channel
+                            .basic_cancel(&queue_name, BasicCancelOptions::default())
+                            .await?;
+                        self.unsubscribe(&queue_name).await?;
+                    }
+                }
+            }
+        }
+        Ok(())
+    }
+
+    async fn _read_cache_watch_redis(
+        &self,
+        mq_uri: &str,
+        mut requests: mpsc::UnboundedReceiver<WatchRequest>,
+        cache: &Weak<ReadCache>,
+        queues: &mut HashMap<String, (String, u64)>,
+    ) -> Result<(), Error> {
+        let client = redis::Client::open(mq_uri)?;
+        let mut con = client.get_async_connection().await?;
+        // the id of the last message read from each queue
+        let mut last_ids = HashMap::new();
+        loop {
+            // wait for a request when nothing is watched, otherwise only take the pending ones
+            let mut pending = vec![];
+            if queues.is_empty() {
+                match requests.recv().await {
+                    Some(request) => pending.push(request),
+                    None => return Ok(()),
+                }
+            }
+            loop {
+                match requests.try_recv() {
+                    Ok(request) => pending.push(request),
+                    Err(mpsc::error::TryRecvError::Empty) => break,
+                    Err(mpsc::error::TryRecvError::Disconnected) => return Ok(()),
+                }
+            }
+            for request in pending {
+                match request {
+                    WatchRequest::Watch {
+                        key,
+                        start_timestamp,
+                        generation,
+                    } => {
+                        if let Some(queue_name) = self
+                            ._read_cache_subscribe(&key, start_timestamp, generation, cache)
+                            .await
+                        {
+                            last_ids.insert(queue_name.clone(), "0".to_string());
+                            queues.insert(queue_name, (key, generation));
+                        }
+                    }
+                    WatchRequest::Unwatch { key } => {
+                        let queue_name = queues
+                            .iter()
+                            .find(|(_, (watched_key, _))| *watched_key == key)
+                            .map(|(queue_name, _)| queue_name.clone());
+                        if let Some(queue_name) = queue_name {
+                            queues.remove(&queue_name);
+                            last_ids.remove(&queue_name);
+                            self.unsubscribe(&queue_name).await?;
+                        }
+                    }
+                }
+            }
+            if queues.is_empty() {
+                continue;
+            }
+            // block for a short time, so that new requests are handled soon
+            let queue_names = queues.keys().cloned().collect::<Vec<String>>();
+            let ids = queue_names
+                .iter()
+                .map(|queue_name| last_ids[queue_name].clone())
+                .collect::<Vec<String>>();
+            let res: StreamReadReply = redis::AsyncCommands::xread_options(
+                &mut con,
+                &queue_names,
+                &ids,
+                &StreamReadOptions::default().block(100),
+            )
+            .await?;
+            for stream in res.keys {
+                if let Some(last) = stream.ids.last() {
+                    last_ids.insert(stream.key.clone(), last.id.clone());
+                    if let (Some((key, generation)), Some(cache)) =
+                        (queues.get(&stream.key), cache.upgrade())
+                    {
+                        cache.invalidate(key, Some(*generation));
+                    }
+                }
+            }
+        }
+    }
+}
@@ -0,0 +1,176 @@
+use crate::{colink_proto::*, utils::is_not_found_error, KeyPath};
+use prost::Message;
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+
+type Error = Box<dyn std::error::Error + Send + Sync + 'static>;
+
+const ARCHIVE_HEADER: &[u8] = b"colink-storage-archive:v1\n";
+const MAX_RECORD_SIZE: u64 = 4 * 1024 * 1024; // the default max message size of the core
+const MAX_RECORD_PAYLOAD_SIZE: usize = 3 * 1024 * 1024; // leave room for the key name and the key path
+
+/// How import_storage handles entries that already exist.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum ImportConflictStrategy {
+    /// Keep the existing entry.
+    #[default]
+    Skip,
+    /// Replace the existing entry with the one in the archive.
+    Overwrite,
+    /// Stop the import with an error.
+    Fail,
+}
+
+#[derive(Clone, Debug, Default)]
+pub struct ImportOptions {
+    pub conflict_strategy: ImportConflictStrategy,
+}
+
+/// Read a varint. Return None at the end of the archive.
+async fn _read_record_len<R>(reader: &mut R) -> Result<Option<u64>, Error>
+where
+    R: AsyncRead + Unpin + Send,
+{
+    let mut len = 0;
+    for i in 0..10 {
+        let mut byte = [0; 1];
+        if reader.read(&mut byte).await? == 0 {
+            if i == 0 {
+                return Ok(None);
+            }
+            Err("The archive is truncated.")?
+        }
+        len |= ((byte[0] & 0x7f) as u64) << (7 * i);
+        if byte[0] & 0x80 == 0 {
+            return Ok(Some(len));
+        }
+    }
+    Err("The archive is malformed.".into())
+}
+
+impl crate::application::CoLink {
+    /// Write the entry `prefix` and all entries under `{prefix}:` (all entries if the prefix is empty) to `writer`.
+    /// The archive is a header followed by length-delimited StorageEntry records with key_name and payload, and
+    /// key_path for entries without storage macros. Storage macro entries, including chunked entries, are stored
+    /// with their contents. Records are at most MAX_RECORD_SIZE bytes: larger payloads continue in records for
+    /// `{key_name}:$append`. Return the number of exported entries.
+    pub async fn export_storage<W>(&self, prefix: &str, mut writer: W) -> Result<usize, Error>
+    where
+        W: AsyncWrite + Unpin + Send,
+    {
+        let key_names = self._list_prefix(prefix).await?;
+        writer.write_all(ARCHIVE_HEADER).await?;
+        for key_name in &key_names {
+            let entry = if key_name.contains('$') {
+                StorageEntry {
+                    key_name: key_name.clone(),
+                    payload: self.read_entry(key_name).await?,
+                    ..Default::default()
+                }
+            } else {
+                let mut entries = self
+                    .read_entries(&[StorageEntry {
+                        key_name: key_name.clone(),
+                        ..Default::default()
+                    }])
+                    .await?;
+                StorageEntry {
+                    key_name: key_name.clone(),
+                    key_path: entries[0].key_path.clone(),
+                    payload: std::mem::take(&mut entries[0].payload),
+                }
+            };
+            let mut pieces = entry.payload.chunks(MAX_RECORD_PAYLOAD_SIZE);
+            let first = StorageEntry {
+                payload: pieces.next().unwrap_or_default().to_vec(),
+                ..entry.clone()
+            };
+            writer
+                .write_all(&first.encode_length_delimited_to_vec())
+                .await?;
+            for piece in pieces {
+                let record = StorageEntry {
+                    key_name: KeyPath::new(key_name).storage_macro("append").to_string(),
+                    payload: piece.to_vec(),
+                    ..Default::default()
+                };
+                writer
+                    .write_all(&record.encode_length_delimited_to_vec())
+                    .await?;
+            }
+        }
+        writer.flush().await?;
+        Ok(key_names.len())
+    }
+
+    /// Write the entries in an archive created by export_storage. The entries get new timestamps, and storage
+    /// macro entries are written through their storage macros. Return the number of imported entries.
+    pub async fn import_storage<R>(
+        &self,
+        mut reader: R,
+        options: &ImportOptions,
+    ) -> Result<usize, Error>
+    where
+        R: AsyncRead + Unpin + Send,
+    {
+        let mut header = vec![0; ARCHIVE_HEADER.len()];
+        reader.read_exact(&mut header).await?;
+        if header != ARCHIVE_HEADER {
+            Err("The archive is not created by export_storage.")?
+        }
+        let mut imported = 0;
+        // the continuation records of the last entry, which are skipped with the entry
+        let mut continuation_key = String::new();
+        let mut skipped = false;
+        while let Some(len) = _read_record_len(&mut reader).await? {
+            if len > MAX_RECORD_SIZE {
+                Err("The archive is malformed.")?
+            }
+            let mut record = vec![0; len as usize];
+            reader.read_exact(&mut record).await?;
+            let entry = StorageEntry::decode(&*record)?;
+            if entry.key_name == continuation_key {
+                if !skipped {
+                    self.update_entry(&entry.key_name, &entry.payload).await?;
+                }
+                continue;
+            }
+            continuation_key = KeyPath::new(&entry.key_name)
+                .storage_macro("append")
+                .to_string();
+            skipped = false;
+            if options.conflict_strategy == ImportConflictStrategy::Overwrite {
+                self.update_entry(&entry.key_name, &entry.payload).await?;
+                imported += 1;
+                continue;
+            }
+            if self._entry_exists(&entry.key_name).await? {
+                if options.conflict_strategy == ImportConflictStrategy::Fail {
+                    Err(format!("{} already exists.", entry.key_name))?
+                }
+                skipped = true;
+                continue;
+            }
+            self.create_entry(&entry.key_name, &entry.payload).await?;
+            imported += 1;
+        }
+        Ok(imported)
+    }
+
+    async fn _entry_exists(&self, key_name: &str) -> Result<bool, Error> {
+        let res = if key_name.contains('$') {
+            self.read_entry(key_name).await.map(|_| ())
+        } else {
+            self.read_entries(&[StorageEntry {
+                key_name: key_name.to_string(),
+                ..Default::default()
+            }])
+            .await
+            .map(|_| ())
+        };
+        match res {
+            Ok(()) => Ok(true),
+            Err(e) if is_not_found_error(&*e) => Ok(false),
+            Err(e) => Err(e),
+        }
+    }
+}
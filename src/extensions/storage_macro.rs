@@ -1,14 +1,288 @@
 mod append;
+mod cas;
 mod chunk;
+#[cfg(feature = "storage_macro_compress")]
+mod compress;
 #[cfg(feature = "storage_macro_dbc")]
 mod dbc;
+#[cfg(feature = "storage_macro_encrypt")]
+mod encrypt;
 mod fs;
 mod redis;
-use crate::StorageEntry;
+#[cfg(feature = "storage_macro_s3")]
+mod s3;
+#[cfg(feature = "storage_macro_sqlite")]
+mod sqlite;
+mod ttl;
+use crate::{application::CoLink, key_path::_split_key_path, KeyPath, StorageEntry};
+use async_trait::async_trait;
+pub use chunk::ChunkStorageMacro;
+pub(crate) use chunk::_is_chunk_suffix;
+#[cfg(feature = "storage_macro_dbc")]
+pub use dbc::{dbc_value, DbcColumn, DbcQueryResult, DbcRow, DbcValue};
+#[cfg(feature = "storage_macro_encrypt")]
+pub(crate) use encrypt::EncryptionKeyRegistry;
+pub(crate) use redis::RedisConnectionPool;
+#[cfg(feature = "storage_macro_sqlite")]
+pub(crate) use sqlite::SqliteConnectionPool;
+use std::{
+    collections::HashMap,
+    sync::{Arc, RwLock},
+    time::Duration,
+};
+use tokio::io::{AsyncRead, AsyncReadExt};
 
 type Error = Box<dyn std::error::Error + Send + Sync + 'static>;
 
+/// A storage backend for keys containing `$name`. For a key `string_before:$name:string_after`,
+/// each operation receives `string_before` and `string_after`. Missing keys are reported with
+/// `tonic::Status::not_found`, like the core does.
+#[async_trait]
+pub trait StorageMacro: Send + Sync {
+    async fn create(
+        &self,
+        _cl: &CoLink,
+        _string_before: &str,
+        _string_after: &str,
+        _payload: &[u8],
+    ) -> Result<String, Error> {
+        Err("create is not supported by this storage macro.")?
+    }
+
+    async fn read(
+        &self,
+        _cl: &CoLink,
+        _string_before: &str,
+        _string_after: &str,
+    ) -> Result<Vec<u8>, Error> {
+        Err("read is not supported by this storage macro.")?
+    }
+
+    async fn update(
+        &self,
+        _cl: &CoLink,
+        _string_before: &str,
+        _string_after: &str,
+        _payload: &[u8],
+    ) -> Result<String, Error> {
+        Err("update is not supported by this storage macro.")?
+    }
+
+    async fn delete(
+        &self,
+        _cl: &CoLink,
+        _string_before: &str,
+        _string_after: &str,
+    ) -> Result<String, Error> {
+        Err("delete is not supported by this storage macro.")?
+    }
+
+    /// Used by update_entry_with_ttl. The entry should expire after `ttl`.
+    async fn update_with_ttl(
+        &self,
+        _cl: &CoLink,
+        _string_before: &str,
+        _string_after: &str,
+        _payload: &[u8],
+        _ttl: Duration,
+    ) -> Result<String, Error> {
+        Err(
+            "update_entry_with_ttl is not supported by this storage macro, use a $ttl key instead.",
+        )?
+    }
+
+    /// Used by the $append storage macro when this storage macro is the last one in the key. The default
+    /// implementation reads and updates the entry under a lock.
+    async fn append(
+        &self,
+        cl: &CoLink,
+        string_before: &str,
+        string_after: &str,
+        payload: &[u8],
+    ) -> Result<String, Error> {
+        let lock = cl
+            .lock(
+                &KeyPath::new(string_before)
+                    .segment(string_after)
+                    .to_string(),
+            )
+            .await?;
+        // use a closure to prevent locking forever caused by errors
+        let res = async {
+            let mut data = self.read(cl, string_before, string_after).await?;
+            data.extend_from_slice(payload);
+            self.update(cl, string_before, string_after, &data).await
+        }
+        .await;
+        cl.unlock(lock).await?;
+        res
+    }
+
+    /// Read at most `length` bytes starting from `offset`. The default implementation reads the whole entry.
+    async fn read_range(
+        &self,
+        cl: &CoLink,
+        string_before: &str,
+        string_after: &str,
+        offset: u64,
+        length: u64,
+    ) -> Result<Vec<u8>, Error> {
+        let payload = self.read(cl, string_before, string_after).await?;
+        Ok(_slice_range(&payload, offset, length).to_vec())
+    }
+
+    /// The default implementation reads the whole entry into memory.
+    async fn read_stream(
+        &self,
+        cl: &CoLink,
+        string_before: &str,
+        string_after: &str,
+    ) -> Result<Box<dyn AsyncRead + Send + Unpin>, Error> {
+        let payload = self.read(cl, string_before, string_after).await?;
+        Ok(Box::new(std::io::Cursor::new(payload)))
+    }
+
+    /// The default implementation reads the whole stream into memory and then updates the entry.
+    async fn write_stream(
+        &self,
+        cl: &CoLink,
+        string_before: &str,
+        string_after: &str,
+        reader: &mut (dyn AsyncRead + Send + Unpin),
+    ) -> Result<String, Error> {
+        let mut payload = Vec::new();
+        reader.read_to_end(&mut payload).await?;
+        self.update(cl, string_before, string_after, &payload).await
+    }
+
+    /// `prefix` is the prefix passed to read_keys, which starts with the user_id.
+    async fn read_keys(
+        &self,
+        _cl: &CoLink,
+        _prefix: &str,
+        _string_before: &str,
+        _string_after: &str,
+        _include_history: bool,
+    ) -> Result<Vec<StorageEntry>, Error> {
+        Err("read_keys is not supported by this storage macro.")?
+    }
+}
+
+pub(crate) fn _slice_range(payload: &[u8], offset: u64, length: u64) -> &[u8] {
+    let start = std::cmp::min(offset, payload.len() as u64) as usize;
+    let end = std::cmp::min(offset.saturating_add(length), payload.len() as u64) as usize;
+    &payload[start..end]
+}
+
+/// The key name and the timestamp of a key path, so that storage macro entries can also be read by key path.
+fn _key_name_of(key: &str) -> Result<(&str, Option<i64>), Error> {
+    match _split_key_path(key) {
+        (Some(_), key_name, Some(timestamp)) => Ok((key_name, Some(timestamp.parse::<i64>()?))),
+        (Some(_), key_name, None) => Ok((key_name, None)),
+        _ => Ok((key, None)),
+    }
+}
+
+/// Only $chunk lists the earlier versions of its entries in read_keys, the other storage macros list the
+/// latest version of each entry, so the timestamps in their key paths are ignored.
+fn _check_latest_version(macro_type: &str, key: &str, timestamp: Option<i64>) -> Result<(), Error> {
+    if macro_type == "chunk" && timestamp.is_some() {
+        Err(format!(
+            "{} refers to a version of a chunked entry, which can only be read by read_entry.",
+            key
+        ))?
+    }
+    Ok(())
+}
+
+pub(crate) type StorageMacroRegistry = Arc<RwLock<HashMap<String, Arc<dyn StorageMacro>>>>;
+
+pub(crate) fn default_storage_macros() -> StorageMacroRegistry {
+    let mut storage_macros: HashMap<String, Arc<dyn StorageMacro>> = HashMap::new();
+    storage_macros.insert("append".to_string(), Arc::new(append::AppendStorageMacro));
+    storage_macros.insert("cas".to_string(), Arc::new(cas::CasStorageMacro));
+    storage_macros.insert(
+        "chunk".to_string(),
+        Arc::new(chunk::ChunkStorageMacro::default()),
+    );
+    #[cfg(feature = "storage_macro_compress")]
+    storage_macros.insert(
+        "compress".to_string(),
+        Arc::new(compress::CompressStorageMacro),
+    );
+    #[cfg(feature = "storage_macro_dbc")]
+    storage_macros.insert("dbc".to_string(), Arc::new(dbc::DbcStorageMacro));
+    #[cfg(feature = "storage_macro_encrypt")]
+    storage_macros.insert(
+        "encrypt".to_string(),
+        Arc::new(encrypt::EncryptStorageMacro),
+    );
+    storage_macros.insert("fs".to_string(), Arc::new(fs::FsStorageMacro));
+    storage_macros.insert("redis".to_string(), Arc::new(redis::RedisStorageMacro));
+    #[cfg(feature = "storage_macro_s3")]
+    storage_macros.insert("s3".to_string(), Arc::new(s3::S3StorageMacro));
+    #[cfg(feature = "storage_macro_sqlite")]
+    storage_macros.insert("sqlite".to_string(), Arc::new(sqlite::SqliteStorageMacro));
+    storage_macros.insert("ttl".to_string(), Arc::new(ttl::TtlStorageMacro));
+    Arc::new(RwLock::new(storage_macros))
+}
+
 impl crate::application::CoLink {
+    /// Register a storage macro for keys containing `$name`. The registry is shared by all clones of this CoLink object.
+    /// Registering an existing name replaces the previous storage macro, including the built-in ones.
+    pub fn register_storage_macro(&self, name: &str, storage_macro: impl StorageMacro + 'static) {
+        self.storage_macros
+            .write()
+            .unwrap()
+            .insert(name.to_string(), Arc::new(storage_macro));
+    }
+
+    pub(crate) fn _get_storage_macro(
+        &self,
+        macro_type: &str,
+        key_name: &str,
+    ) -> Result<Arc<dyn StorageMacro>, Error> {
+        crate::key_path::validate_storage_macro_segment(key_name)?;
+        match self.storage_macros.read().unwrap().get(macro_type) {
+            Some(storage_macro) => Ok(storage_macro.clone()),
+            #[cfg(not(feature = "storage_macro_dbc"))]
+            None if macro_type == "dbc" => Err(format!(
+                "Storage Macro DBC feature not enabled, but found $dbc in key name: {}",
+                key_name
+            )
+            .into()),
+            #[cfg(not(feature = "storage_macro_compress"))]
+            None if macro_type == "compress" => Err(format!(
+                "Storage Macro Compress feature not enabled, but found $compress in key name: {}",
+                key_name
+            )
+            .into()),
+            #[cfg(not(feature = "storage_macro_encrypt"))]
+            None if macro_type == "encrypt" => Err(format!(
+                "Storage Macro Encrypt feature not enabled, but found $encrypt in key name: {}",
+                key_name
+            )
+            .into()),
+            #[cfg(not(feature = "storage_macro_s3"))]
+            None if macro_type == "s3" => Err(format!(
+                "Storage Macro S3 feature not enabled, but found $s3 in key name: {}",
+                key_name
+            )
+            .into()),
+            #[cfg(not(feature = "storage_macro_sqlite"))]
+            None if macro_type == "sqlite" => Err(format!(
+                "Storage Macro SQLite feature not enabled, but found $sqlite in key name: {}",
+                key_name
+            )
+            .into()),
+            None => Err(format!(
+                "invalid storage macro, found {} in key name {}",
+                macro_type, key_name
+            )
+            .into()),
+        }
+    }
+
     pub(crate) fn _parse_macro(&self, key_name: &str) -> (String, String, String) {
         let split_key = key_name.split(':').collect::<Vec<&str>>();
         let mut macro_type = String::new();
@@ -37,50 +311,64 @@ impl crate::application::CoLink {
         payload: &[u8],
     ) -> Result<String, Error> {
         let (string_before, macro_type, string_after) = self._parse_macro(key_name);
-        match macro_type.as_str() {
-            "chunk" => self._create_entry_chunk(&string_before, payload).await,
-            "redis" => {
-                self._create_entry_redis(&string_before, &string_after, payload)
+        self._get_storage_macro(&macro_type, key_name)?
+            .create(self, &string_before, &string_after, payload)
+            .await
+    }
+
+    pub(crate) async fn _sm_read_entry(&self, key_name: &str) -> Result<Vec<u8>, Error> {
+        let (key_name, timestamp) = _key_name_of(key_name)?;
+        let (string_before, macro_type, string_after) = self._parse_macro(key_name);
+        let storage_macro = self._get_storage_macro(&macro_type, key_name)?;
+        match timestamp {
+            // the key paths listed by read_keys with history
+            Some(timestamp) if macro_type == "chunk" => {
+                self._read_entry_chunk_version(&string_before, timestamp)
                     .await
             }
-            "fs" => {
-                self._create_entry_fs(&string_before, &string_after, payload)
+            _ => {
+                storage_macro
+                    .read(self, &string_before, &string_after)
                     .await
             }
-            _ => Err(format!(
-                "invalid storage macro, found {} in key name {}",
-                macro_type, key_name
-            )
-            .into()),
         }
     }
 
-    pub(crate) async fn _sm_read_entry(&self, key_name: &str) -> Result<Vec<u8>, Error> {
-        let key_name = if key_name.contains("::") {
-            &key_name
-                [key_name.find(':').unwrap() + 2..key_name.rfind('@').unwrap_or(key_name.len())]
-        } else {
-            key_name
-        };
+    pub(crate) async fn _sm_read_entry_range(
+        &self,
+        key_name: &str,
+        offset: u64,
+        length: u64,
+    ) -> Result<Vec<u8>, Error> {
+        let (key_name, timestamp) = _key_name_of(key_name)?;
         let (string_before, macro_type, string_after) = self._parse_macro(key_name);
-        match macro_type.as_str() {
-            "chunk" => self._read_entry_chunk(&string_before).await,
-            "redis" => self._read_entry_redis(&string_before, &string_after).await,
-            #[cfg(feature = "storage_macro_dbc")]
-            "dbc" => self._read_entry_dbc(&string_before, &string_after).await,
-            #[cfg(not(feature = "storage_macro_dbc"))]
-            "dbc" => Err(format!(
-                "Storage Macro DBC feature not enabled, but found $dbc in key name: {}",
-                key_name
-            )
-            .into()),
-            "fs" => self._read_entry_fs(&string_before, &string_after).await,
-            _ => Err(format!(
-                "invalid storage macro, found {} in key name {}",
-                macro_type, key_name
-            )
-            .into()),
-        }
+        _check_latest_version(&macro_type, key_name, timestamp)?;
+        self._get_storage_macro(&macro_type, key_name)?
+            .read_range(self, &string_before, &string_after, offset, length)
+            .await
+    }
+
+    pub(crate) async fn _sm_read_entry_stream(
+        &self,
+        key_name: &str,
+    ) -> Result<Box<dyn AsyncRead + Send + Unpin>, Error> {
+        let (key_name, timestamp) = _key_name_of(key_name)?;
+        let (string_before, macro_type, string_after) = self._parse_macro(key_name);
+        _check_latest_version(&macro_type, key_name, timestamp)?;
+        self._get_storage_macro(&macro_type, key_name)?
+            .read_stream(self, &string_before, &string_after)
+            .await
+    }
+
+    pub(crate) async fn _sm_write_entry_stream(
+        &self,
+        key_name: &str,
+        reader: &mut (dyn AsyncRead + Send + Unpin),
+    ) -> Result<String, Error> {
+        let (string_before, macro_type, string_after) = self._parse_macro(key_name);
+        self._get_storage_macro(&macro_type, key_name)?
+            .write_stream(self, &string_before, &string_after, reader)
+            .await
     }
 
     pub(crate) async fn _sm_update_entry(
@@ -89,40 +377,28 @@ impl crate::application::CoLink {
         payload: &[u8],
     ) -> Result<String, Error> {
         let (string_before, macro_type, string_after) = self._parse_macro(key_name);
-        match macro_type.as_str() {
-            "chunk" => self._update_entry_chunk(&string_before, payload).await,
-            "redis" => {
-                self._update_entry_redis(&string_before, &string_after, payload)
-                    .await
-            }
-            "fs" => {
-                self._update_entry_fs(&string_before, &string_after, payload)
-                    .await
-            }
-            "append" => self._update_entry_append(&string_before, payload).await,
-            _ => Err(format!(
-                "invalid storage macro, found {} in key name {}",
-                macro_type, key_name
-            )
-            .into()),
-        }
+        self._get_storage_macro(&macro_type, key_name)?
+            .update(self, &string_before, &string_after, payload)
+            .await
+    }
+
+    pub(crate) async fn _sm_update_entry_with_ttl(
+        &self,
+        key_name: &str,
+        payload: &[u8],
+        ttl: Duration,
+    ) -> Result<String, Error> {
+        let (string_before, macro_type, string_after) = self._parse_macro(key_name);
+        self._get_storage_macro(&macro_type, key_name)?
+            .update_with_ttl(self, &string_before, &string_after, payload, ttl)
+            .await
     }
 
     pub(crate) async fn _sm_delete_entry(&self, key_name: &str) -> Result<String, Error> {
         let (string_before, macro_type, string_after) = self._parse_macro(key_name);
-        match macro_type.as_str() {
-            "chunk" => self._delete_entry_chunk(&string_before).await,
-            "redis" => {
-                self._delete_entry_redis(&string_before, &string_after)
-                    .await
-            }
-            "fs" => self._delete_entry_fs(&string_before, &string_after).await,
-            _ => Err(format!(
-                "invalid storage macro, found {} in key name {}",
-                macro_type, key_name
-            )
-            .into()),
-        }
+        self._get_storage_macro(&macro_type, key_name)?
+            .delete(self, &string_before, &string_after)
+            .await
     }
 
     pub(crate) async fn _sm_read_keys(
@@ -130,33 +406,15 @@ impl crate::application::CoLink {
         prefix: &str,
         include_history: bool,
     ) -> Result<Vec<StorageEntry>, Error> {
-        if include_history {
-            return Err("Storage Macro: include_history is not supported.".into());
-        }
-        if !prefix.starts_with(&format!("{}::", self.get_user_id()?)) {
-            return Err("prefix must start with the given user_id".into());
-        }
-        let key_name_prefix = &prefix[prefix.find(':').unwrap() + 2..];
-        let (string_before, macro_type, string_after) = self._parse_macro(key_name_prefix);
-        let key_list = match macro_type.as_str() {
-            "redis" => self._read_keys_redis(&string_before, &string_after).await?,
-            "fs" => self._read_keys_fs(&string_before, &string_after).await?,
-            _ => {
-                return Err(format!(
-                    "invalid storage macro, found {} in prefix {}",
-                    macro_type, key_name_prefix
-                )
-                .into());
+        let key_name_prefix = match _split_key_path(prefix) {
+            (Some(user_id), key_name_prefix, None) if user_id == self.get_user_id()? => {
+                key_name_prefix
             }
+            _ => return Err("prefix must start with the given user_id".into()),
         };
-        let mut res: Vec<StorageEntry> = Vec::new();
-        for key in key_list {
-            res.push(StorageEntry {
-                key_name: Default::default(),
-                key_path: format!("{}:{}@0", prefix, key),
-                payload: Default::default(),
-            });
-        }
-        Ok(res)
+        let (string_before, macro_type, string_after) = self._parse_macro(key_name_prefix);
+        self._get_storage_macro(&macro_type, key_name_prefix)?
+            .read_keys(self, prefix, &string_before, &string_after, include_history)
+            .await
     }
 }
@@ -1,7 +1,25 @@
+use super::StorageMacro;
+use crate::application::CoLink;
 use async_recursion::async_recursion;
+use async_trait::async_trait;
 
 type Error = Box<dyn std::error::Error + Send + Sync + 'static>;
 
+pub(crate) struct AppendStorageMacro;
+
+#[async_trait]
+impl StorageMacro for AppendStorageMacro {
+    async fn update(
+        &self,
+        cl: &CoLink,
+        string_before: &str,
+        _string_after: &str,
+        payload: &[u8],
+    ) -> Result<String, Error> {
+        cl._update_entry_append(string_before, payload).await
+    }
+}
+
 impl crate::application::CoLink {
     #[async_recursion]
     pub(crate) async fn _update_entry_append(
@@ -11,33 +29,23 @@ impl crate::application::CoLink {
     ) -> Result<String, Error> {
         if key_name.contains('$') {
             let (string_before, macro_type, string_after) = self._parse_macro(key_name);
-            match macro_type.as_str() {
-                "redis" => {
-                    return self
-                        ._append_entry_redis(&string_before, &string_after, payload)
-                        .await;
-                }
-                "chunk" => {
-                    return self._append_entry_chunk(&string_before, payload).await;
-                }
-                "fs" => {
-                    return self
-                        ._append_entry_fs(&string_before, &string_after, payload)
-                        .await;
+            return self
+                ._get_storage_macro(&macro_type, key_name)?
+                .append(self, &string_before, &string_after, payload)
+                .await;
+        }
+        self._update_with(key_name, |current| async move {
+            match current {
+                Some(mut data) => {
+                    data.extend_from_slice(payload);
+                    Ok(data)
                 }
-                _ => {}
+                None => Err(tonic::Status::not_found(format!(
+                    "key {} does not exist.",
+                    key_name
+                )))?,
             }
-        }
-        let lock = self.lock(key_name).await?;
-        // use a closure to prevent locking forever caused by errors
-        let res = async {
-            let mut data = self.read_entry(key_name).await?;
-            data.append(&mut payload.to_vec());
-            let res = self.update_entry(key_name, &data).await?;
-            Ok::<String, Error>(res)
-        }
-        .await;
-        self.unlock(lock).await?;
-        res
+        })
+        .await
     }
 }
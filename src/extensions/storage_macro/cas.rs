@@ -0,0 +1,420 @@
+use super::{chunk::CHUNK_SIZE, StorageMacro};
+use crate::{application::CoLink, utils::is_not_found_error, KeyPath};
+use async_recursion::async_recursion;
+use async_trait::async_trait;
+use sha2::{Digest, Sha256};
+use std::collections::BTreeMap;
+
+type Error = Box<dyn std::error::Error + Send + Sync + 'static>;
+
+pub(crate) struct CasStorageMacro;
+
+#[async_trait]
+impl StorageMacro for CasStorageMacro {
+    async fn create(
+        &self,
+        cl: &CoLink,
+        string_before: &str,
+        _string_after: &str,
+        payload: &[u8],
+    ) -> Result<String, Error> {
+        cl._create_entry_cas(string_before, payload).await
+    }
+
+    async fn read(
+        &self,
+        cl: &CoLink,
+        string_before: &str,
+        _string_after: &str,
+    ) -> Result<Vec<u8>, Error> {
+        cl._read_entry_cas(string_before).await
+    }
+
+    async fn update(
+        &self,
+        cl: &CoLink,
+        string_before: &str,
+        _string_after: &str,
+        payload: &[u8],
+    ) -> Result<String, Error> {
+        cl._update_entry_cas(string_before, payload).await
+    }
+
+    async fn delete(
+        &self,
+        cl: &CoLink,
+        string_before: &str,
+        _string_after: &str,
+    ) -> Result<String, Error> {
+        cl._delete_entry_cas(string_before).await
+    }
+
+    async fn append(
+        &self,
+        cl: &CoLink,
+        string_before: &str,
+        _string_after: &str,
+        payload: &[u8],
+    ) -> Result<String, Error> {
+        cl._append_entry_cas(string_before, payload).await
+    }
+
+    async fn read_range(
+        &self,
+        cl: &CoLink,
+        string_before: &str,
+        _string_after: &str,
+        offset: u64,
+        length: u64,
+    ) -> Result<Vec<u8>, Error> {
+        cl._read_entry_cas_range(string_before, offset, length)
+            .await
+    }
+}
+
+/// Chunks are shared by all $cas keys in the same backend: `_cas_store` in the core storage,
+/// or `{prefix}:$name:_cas_store` for keys like `{prefix}:$name:key:$cas`.
+fn _cas_store(key_name: &str) -> String {
+    let split_key = key_name.split(':').collect::<Vec<&str>>();
+    match split_key.iter().rposition(|s| s.contains('$')) {
+        Some(i) => KeyPath::new(&split_key[..=i].join(":"))
+            .segment("_cas_store")
+            .to_string(),
+        None => "_cas_store".to_string(),
+    }
+}
+
+fn _cas_chunk_key(store: &str, hash: &str) -> String {
+    KeyPath::new(store)
+        .segment("chunk")
+        .segment(hash)
+        .to_string()
+}
+
+fn _cas_refs_key(store: &str) -> String {
+    KeyPath::new(store).segment("refs").to_string()
+}
+
+fn _cas_metadata_key(key_name: &str) -> String {
+    KeyPath::new(key_name).segment("cas_metadata").to_string()
+}
+
+fn _split_chunks(payload: &[u8]) -> Vec<(String, &[u8])> {
+    payload
+        .chunks(CHUNK_SIZE)
+        .map(|chunk| (format!("{:x}", Sha256::digest(chunk)), chunk))
+        .collect()
+}
+
+fn _parse_cas_metadata(metadata: &[u8]) -> Result<Vec<String>, Error> {
+    let metadata = String::from_utf8(metadata.to_vec())?;
+    if metadata.is_empty() {
+        return Ok(vec![]);
+    }
+    Ok(metadata.split(';').map(|x| x.to_string()).collect())
+}
+
+/// The reference counts of all chunks in a chunk store, stored as `{hash}={count}` separated by `;`.
+/// Chunks with a count of 0 are no longer referenced and may be removed by cas_gc.
+fn _parse_cas_refs(refs: &[u8]) -> Result<BTreeMap<String, i64>, Error> {
+    let mut counts = BTreeMap::new();
+    for item in _parse_cas_metadata(refs)? {
+        match item.split_once('=') {
+            Some((hash, count)) => {
+                counts.insert(hash.to_string(), count.parse::<i64>()?);
+            }
+            None => Err(format!("invalid cas reference count: {}", item))?,
+        }
+    }
+    Ok(counts)
+}
+
+fn _format_cas_refs(counts: &BTreeMap<String, i64>) -> String {
+    counts
+        .iter()
+        .map(|(hash, count)| format!("{}={}", hash, count))
+        .collect::<Vec<_>>()
+        .join(";")
+}
+
+/// The change in the number of references to each chunk when the hashes of an entry change from `old_hashes`
+/// to `new_hashes`. Chunks shared by both versions are not touched.
+fn _ref_deltas(old_hashes: &[String], new_hashes: &[String]) -> Vec<(String, i64)> {
+    let mut deltas = BTreeMap::new();
+    for hash in old_hashes {
+        *deltas.entry(hash.clone()).or_insert(0) -= 1;
+    }
+    for hash in new_hashes {
+        *deltas.entry(hash.clone()).or_insert(0) += 1;
+    }
+    deltas
+        .into_iter()
+        .filter(|(_, delta)| *delta != 0)
+        .collect()
+}
+
+impl crate::application::CoLink {
+    /// Remove the chunks that are no longer referenced by any key in the chunk store of `key_name`, which is a key
+    /// containing $cas. Return the number of removed chunks.
+    pub async fn cas_gc(&self, key_name: &str) -> Result<usize, Error> {
+        let (string_before, macro_type, _) = self._parse_macro(key_name);
+        if macro_type != "cas" {
+            Err(format!("cas_gc: {} is not a $cas key.", key_name))?
+        }
+        let store = _cas_store(&string_before);
+        let refs_key = _cas_refs_key(&store);
+        // the reference counts are checked under their lock, so a writer cannot reference the chunks meanwhile
+        let lock = self.lock(&refs_key).await?;
+        // use a closure to prevent locking forever caused by errors
+        let res = async {
+            let mut counts = self._cas_read_refs(&refs_key).await?;
+            let garbage = counts
+                .iter()
+                .filter(|(_, count)| **count <= 0)
+                .map(|(hash, _)| hash.clone())
+                .collect::<Vec<_>>();
+            let mut removed = 0;
+            for hash in &garbage {
+                match self.delete_entry(&_cas_chunk_key(&store, hash)).await {
+                    Ok(_) => removed += 1,
+                    Err(e) if !is_not_found_error(&*e) => return Err(e),
+                    Err(_) => {}
+                }
+                counts.remove(hash);
+            }
+            if !garbage.is_empty() {
+                self._cas_write_refs(&refs_key, &counts).await?;
+            }
+            Ok::<usize, Error>(removed)
+        }
+        .await;
+        self.unlock(lock).await?;
+        res
+    }
+
+    async fn _cas_read_refs(&self, refs_key: &str) -> Result<BTreeMap<String, i64>, Error> {
+        match self.read_entry(refs_key).await {
+            Ok(refs) => _parse_cas_refs(&refs),
+            Err(e) if is_not_found_error(&*e) => Ok(BTreeMap::new()),
+            Err(e) => Err(e),
+        }
+    }
+
+    async fn _cas_write_refs(
+        &self,
+        refs_key: &str,
+        counts: &BTreeMap<String, i64>,
+    ) -> Result<(), Error> {
+        if counts.is_empty() {
+            if let Err(e) = self.delete_entry(refs_key).await {
+                if !is_not_found_error(&*e) {
+                    return Err(e);
+                }
+            }
+        } else {
+            self.update_entry(refs_key, _format_cas_refs(counts).as_bytes())
+                .await?;
+        }
+        Ok(())
+    }
+
+    /// Add the deltas to the reference counts of the chunks in one read and one write under the lock of the
+    /// chunk store. Chunks without references keep a count of 0, so that cas_gc can find them.
+    async fn _cas_update_refs(&self, store: &str, deltas: &[(String, i64)]) -> Result<(), Error> {
+        if deltas.is_empty() {
+            return Ok(());
+        }
+        let refs_key = _cas_refs_key(store);
+        let lock = self.lock(&refs_key).await?;
+        // use a closure to prevent locking forever caused by errors
+        let res = async {
+            let mut counts = self._cas_read_refs(&refs_key).await?;
+            for (hash, delta) in deltas {
+                let count = counts.entry(hash.clone()).or_insert(0);
+                *count = (*count + delta).max(0);
+            }
+            self._cas_write_refs(&refs_key, &counts).await
+        }
+        .await;
+        self.unlock(lock).await?;
+        res
+    }
+
+    /// Store the chunks that are not in the chunk store yet. The caller must hold references to these chunks,
+    /// so that they cannot be removed by cas_gc in the meantime.
+    async fn _cas_store_chunks(
+        &self,
+        store: &str,
+        chunks: &[(String, &[u8])],
+    ) -> Result<(), Error> {
+        let mut visited = std::collections::HashSet::new();
+        for (hash, chunk) in chunks {
+            if !visited.insert(hash) {
+                continue;
+            }
+            let chunk_key = _cas_chunk_key(store, hash);
+            if let Err(e) = self.create_entry(&chunk_key, chunk).await {
+                // the chunk already exists
+                if self.read_entry(&chunk_key).await.is_err() {
+                    return Err(e);
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// Write `payload` to the entry, or append it to the entry if `append` is set.
+    async fn _cas_write(
+        &self,
+        key_name: &str,
+        payload: &[u8],
+        create: bool,
+        append: bool,
+    ) -> Result<String, Error> {
+        let store = _cas_store(key_name);
+        let metadata_key = _cas_metadata_key(key_name);
+        let lock = self.lock(&metadata_key).await?;
+        // use a closure to prevent locking forever caused by errors
+        let res = async {
+            let old_hashes = match self.read_entry(&metadata_key).await {
+                Ok(metadata) => {
+                    if create {
+                        Err("key already exists.")?
+                    }
+                    _parse_cas_metadata(&metadata)?
+                }
+                Err(e) if is_not_found_error(&*e) && !append => vec![],
+                Err(e) => return Err(e),
+            };
+            // when appending, the last chunk is rewritten to keep all chunks except the last one full
+            let mut hashes = vec![];
+            let mut data = vec![];
+            if append && !old_hashes.is_empty() {
+                hashes.extend_from_slice(&old_hashes[..old_hashes.len() - 1]);
+                data = self
+                    .read_entry(&_cas_chunk_key(&store, &old_hashes[old_hashes.len() - 1]))
+                    .await?;
+            }
+            data.extend_from_slice(payload);
+            let chunks = _split_chunks(&data);
+            hashes.extend(chunks.iter().map(|(hash, _)| hash.clone()));
+            // references are added before the chunks are stored and removed after the metadata is updated
+            let deltas = _ref_deltas(&old_hashes, &hashes);
+            let added = deltas
+                .iter()
+                .filter(|(_, delta)| *delta > 0)
+                .cloned()
+                .collect::<Vec<_>>();
+            let removed = deltas
+                .iter()
+                .filter(|(_, delta)| *delta < 0)
+                .cloned()
+                .collect::<Vec<_>>();
+            self._cas_update_refs(&store, &added).await?;
+            let res = async {
+                self._cas_store_chunks(&store, &chunks).await?;
+                self.update_entry(&metadata_key, hashes.join(";").as_bytes())
+                    .await
+            }
+            .await;
+            match res {
+                Ok(res) => {
+                    self._cas_update_refs(&store, &removed).await?;
+                    Ok(res)
+                }
+                Err(e) => {
+                    let reverted = added
+                        .into_iter()
+                        .map(|(hash, delta)| (hash, -delta))
+                        .collect::<Vec<_>>();
+                    self._cas_update_refs(&store, &reverted).await?;
+                    Err(e)
+                }
+            }
+        }
+        .await;
+        self.unlock(lock).await?;
+        res
+    }
+
+    #[async_recursion]
+    pub(crate) async fn _create_entry_cas(
+        &self,
+        key_name: &str,
+        payload: &[u8],
+    ) -> Result<String, Error> {
+        self._cas_write(key_name, payload, true, false).await
+    }
+
+    #[async_recursion]
+    pub(crate) async fn _update_entry_cas(
+        &self,
+        key_name: &str,
+        payload: &[u8],
+    ) -> Result<String, Error> {
+        self._cas_write(key_name, payload, false, false).await
+    }
+
+    #[async_recursion]
+    pub(crate) async fn _append_entry_cas(
+        &self,
+        key_name: &str,
+        payload: &[u8],
+    ) -> Result<String, Error> {
+        self._cas_write(key_name, payload, false, true).await
+    }
+
+    #[async_recursion]
+    pub(crate) async fn _read_entry_cas(&self, key_name: &str) -> Result<Vec<u8>, Error> {
+        let store = _cas_store(key_name);
+        let metadata = self.read_entry(&_cas_metadata_key(key_name)).await?;
+        let mut payload = Vec::new();
+        for hash in _parse_cas_metadata(&metadata)? {
+            let mut chunk = self.read_entry(&_cas_chunk_key(&store, &hash)).await?;
+            payload.append(&mut chunk);
+        }
+        Ok(payload)
+    }
+
+    #[async_recursion]
+    pub(crate) async fn _read_entry_cas_range(
+        &self,
+        key_name: &str,
+        offset: u64,
+        length: u64,
+    ) -> Result<Vec<u8>, Error> {
+        let store = _cas_store(key_name);
+        let metadata = self.read_entry(&_cas_metadata_key(key_name)).await?;
+        let chunk_size = CHUNK_SIZE as u64;
+        let first_chunk_id = offset / chunk_size;
+        let end = offset.saturating_add(length);
+        let mut payload = Vec::new();
+        for (i, hash) in _parse_cas_metadata(&metadata)?.iter().enumerate() {
+            let i = i as u64;
+            if i < first_chunk_id || i * chunk_size >= end {
+                continue;
+            }
+            let mut chunk = self.read_entry(&_cas_chunk_key(&store, hash)).await?;
+            payload.append(&mut chunk);
+        }
+        Ok(super::_slice_range(&payload, offset - first_chunk_id * chunk_size, length).to_vec())
+    }
+
+    #[async_recursion]
+    pub(crate) async fn _delete_entry_cas(&self, key_name: &str) -> Result<String, Error> {
+        let store = _cas_store(key_name);
+        let metadata_key = _cas_metadata_key(key_name);
+        let lock = self.lock(&metadata_key).await?;
+        // use a closure to prevent locking forever caused by errors
+        let res = async {
+            let hashes = _parse_cas_metadata(&self.read_entry(&metadata_key).await?)?;
+            let res = self.delete_entry(&metadata_key).await?;
+            self._cas_update_refs(&store, &_ref_deltas(&hashes, &[]))
+                .await?;
+            Ok::<String, Error>(res)
+        }
+        .await;
+        self.unlock(lock).await?;
+        res
+    }
+}
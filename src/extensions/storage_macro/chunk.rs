@@ -1,9 +1,286 @@
+use super::StorageMacro;
+use crate::{
+    application::CoLink, extensions::conditional_update::CAS_MAX_RETRIES,
+    key_path::_split_key_path, utils::get_path_timestamp, KeyPath, StorageEntry,
+};
 use async_recursion::async_recursion;
+use async_trait::async_trait;
+use std::{
+    collections::VecDeque,
+    pin::Pin,
+    task::{ready, Context, Poll},
+};
+use tokio::{
+    io::{AsyncRead, AsyncReadExt, ReadBuf},
+    sync::mpsc,
+};
 
-const CHUNK_SIZE: usize = 1024 * 1024; // use 1MB chunks
+pub(crate) const CHUNK_SIZE: usize = 1024 * 1024; // use 1MB chunks
+const STREAM_CONCURRENCY: usize = 4; // the number of chunks read or written at the same time in streams
+const METADATA_THRESHOLD: usize = CHUNK_SIZE; // the size of the largest flat metadata entry
+const METADATA_FANOUT: usize = 4096; // the number of references stored in each chunk index entry
+const METADATA_VERSION: &str = "v2";
 
 type Error = Box<dyn std::error::Error + Send + Sync + 'static>;
 
+/// Stores the entry in chunks of CHUNK_SIZE bytes. The chunk timestamps are stored in the metadata entry if they
+/// fit in `metadata_threshold` bytes, otherwise in chunk index entries holding `metadata_fanout` references each.
+pub struct ChunkStorageMacro {
+    metadata_threshold: usize,
+    metadata_fanout: usize,
+}
+
+impl ChunkStorageMacro {
+    /// `metadata_fanout` is at least 2.
+    pub fn new(metadata_threshold: usize, metadata_fanout: usize) -> Self {
+        Self {
+            metadata_threshold,
+            metadata_fanout: std::cmp::max(metadata_fanout, 2),
+        }
+    }
+}
+
+impl Default for ChunkStorageMacro {
+    fn default() -> Self {
+        Self::new(METADATA_THRESHOLD, METADATA_FANOUT)
+    }
+}
+
+#[async_trait]
+impl StorageMacro for ChunkStorageMacro {
+    async fn create(
+        &self,
+        cl: &CoLink,
+        string_before: &str,
+        _string_after: &str,
+        payload: &[u8],
+    ) -> Result<String, Error> {
+        cl._create_entry_chunk(string_before, payload, self).await
+    }
+
+    async fn read(
+        &self,
+        cl: &CoLink,
+        string_before: &str,
+        _string_after: &str,
+    ) -> Result<Vec<u8>, Error> {
+        cl._read_entry_chunk(string_before).await
+    }
+
+    async fn update(
+        &self,
+        cl: &CoLink,
+        string_before: &str,
+        _string_after: &str,
+        payload: &[u8],
+    ) -> Result<String, Error> {
+        cl._update_entry_chunk(string_before, payload, self).await
+    }
+
+    async fn delete(
+        &self,
+        cl: &CoLink,
+        string_before: &str,
+        _string_after: &str,
+    ) -> Result<String, Error> {
+        cl._delete_entry_chunk(string_before).await
+    }
+
+    async fn append(
+        &self,
+        cl: &CoLink,
+        string_before: &str,
+        _string_after: &str,
+        payload: &[u8],
+    ) -> Result<String, Error> {
+        cl._append_entry_chunk(string_before, payload, self).await
+    }
+
+    async fn read_range(
+        &self,
+        cl: &CoLink,
+        string_before: &str,
+        _string_after: &str,
+        offset: u64,
+        length: u64,
+    ) -> Result<Vec<u8>, Error> {
+        cl._read_entry_chunk_range(string_before, offset, length)
+            .await
+    }
+
+    async fn read_stream(
+        &self,
+        cl: &CoLink,
+        string_before: &str,
+        _string_after: &str,
+    ) -> Result<Box<dyn AsyncRead + Send + Unpin>, Error> {
+        cl._read_entry_chunk_stream(string_before).await
+    }
+
+    async fn write_stream(
+        &self,
+        cl: &CoLink,
+        string_before: &str,
+        _string_after: &str,
+        reader: &mut (dyn AsyncRead + Send + Unpin),
+    ) -> Result<String, Error> {
+        cl._write_entry_chunk_stream(string_before, reader, self)
+            .await
+    }
+
+    async fn read_keys(
+        &self,
+        cl: &CoLink,
+        _prefix: &str,
+        string_before: &str,
+        _string_after: &str,
+        include_history: bool,
+    ) -> Result<Vec<StorageEntry>, Error> {
+        cl._read_keys_chunk(string_before, include_history).await
+    }
+}
+
+/// Reads the chunks sent by a background task in order.
+struct ChunkStreamReader {
+    receiver: mpsc::Receiver<Result<Vec<u8>, Error>>,
+    chunk: Vec<u8>,
+    offset: usize,
+}
+
+impl AsyncRead for ChunkStreamReader {
+    fn poll_read(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<std::io::Result<()>> {
+        loop {
+            if self.offset < self.chunk.len() {
+                let offset = self.offset;
+                let size = std::cmp::min(buf.remaining(), self.chunk.len() - offset);
+                buf.put_slice(&self.chunk[offset..offset + size]);
+                self.offset += size;
+                return Poll::Ready(Ok(()));
+            }
+            match ready!(self.receiver.poll_recv(cx)) {
+                Some(Ok(chunk)) => {
+                    self.chunk = chunk;
+                    self.offset = 0;
+                }
+                Some(Err(e)) => {
+                    return Poll::Ready(Err(std::io::Error::new(
+                        std::io::ErrorKind::Other,
+                        e.to_string(),
+                    )))
+                }
+                None => return Poll::Ready(Ok(())),
+            }
+        }
+    }
+}
+
+/// Whether `{key_name}:{suffix}` is an entry written by $chunk for `key_name`: a chunk, a chunk index entry,
+/// the metadata or the lock in compatibility mode.
+pub(crate) fn _is_chunk_suffix(suffix: &str) -> bool {
+    if suffix == "chunk_metadata" || suffix == "chunk_lock" {
+        return true;
+    }
+    if let Some(index) = suffix.strip_prefix("chunk_index:") {
+        return index
+            .split(':')
+            .all(|x| !x.is_empty() && x.bytes().all(|b| b.is_ascii_digit()));
+    }
+    !suffix.is_empty() && suffix.bytes().all(|b| b.is_ascii_digit())
+}
+
+/// All levels of the chunk index of an entry: `levels[0]` holds the chunk timestamps, and each level above holds
+/// the timestamps of the chunk index entries of that level. The flat layout only has level 0.
+struct ChunkIndex {
+    fanout: usize,
+    levels: Vec<Vec<String>>,
+}
+
+impl ChunkIndex {
+    /// The timestamp of index entry `i` of `level` if it holds the same references as `group`.
+    fn unchanged_entry(&self, level: usize, i: usize, group: &[String]) -> Option<&str> {
+        let lower = self.levels.get(level - 1)?;
+        let start = i * self.fanout;
+        let end = std::cmp::min(start + self.fanout, lower.len());
+        if start >= end || lower[start..end] != *group {
+            return None;
+        }
+        self.levels.get(level)?.get(i).map(|x| x.as_str())
+    }
+}
+
+fn _chunk_key(key_name: &str, chunk_id: impl std::fmt::Display) -> String {
+    KeyPath::new(key_name).segment(chunk_id).to_string()
+}
+
+fn _chunk_index_key(key_name: &str, level: usize, i: usize) -> KeyPath {
+    KeyPath::new(key_name)
+        .segment("chunk_index")
+        .segment(level)
+        .segment(i)
+}
+
+/// The key path of the version of a chunk with `timestamp`.
+fn _chunk_key_path(
+    user_id: &str,
+    key_name: &str,
+    chunk_id: usize,
+    timestamp: &str,
+) -> Result<String, Error> {
+    Ok(KeyPath::new(key_name)
+        .segment(chunk_id)
+        .user_id(user_id)
+        .timestamp(timestamp.parse()?)
+        .to_string())
+}
+
+fn _split_timestamps(s: &str) -> Vec<String> {
+    s.split(';')
+        .filter(|x| !x.is_empty())
+        .map(|x| x.to_string())
+        .collect()
+}
+
+/// Parse the metadata into the depth, the fanout and the references in it. The flat layout has depth 0.
+fn _parse_chunk_metadata(metadata: &[u8]) -> Result<(usize, usize, Vec<String>), Error> {
+    let metadata = std::str::from_utf8(metadata)?;
+    if !metadata.starts_with('v') {
+        return Ok((0, 0, _split_timestamps(metadata)));
+    }
+    let mut fields = metadata.splitn(4, ';');
+    let version = fields.next().unwrap();
+    if version != METADATA_VERSION {
+        Err(format!(
+            "Chunk metadata version {} is not supported.",
+            version
+        ))?
+    }
+    let (depth, fanout) = match (fields.next(), fields.next()) {
+        (Some(depth), Some(fanout)) => (depth.parse::<usize>()?, fanout.parse::<usize>()?),
+        _ => Err("Chunk metadata is malformed.")?,
+    };
+    if depth > 0 && fanout < 2 {
+        Err("Chunk metadata is malformed.")?
+    }
+    Ok((
+        depth,
+        fanout,
+        _split_timestamps(fields.next().unwrap_or_default()),
+    ))
+}
+
+/// The number of index entries at `level` for `chunk_len` chunks.
+fn _index_len(chunk_len: usize, fanout: usize, level: usize) -> usize {
+    let mut len = chunk_len;
+    for _ in 0..level {
+        len = (len + fanout - 1) / fanout;
+    }
+    len
+}
+
 impl crate::application::CoLink {
     #[async_recursion]
     async fn _store_chunks(&self, payload: &[u8], key_name: &str) -> Result<Vec<String>, Error> {
@@ -18,7 +295,7 @@ impl crate::application::CoLink {
             };
             let response = self
                 .update_entry(
-                    &format!("{}:{}", key_name, chunk_id),
+                    &_chunk_key(key_name, chunk_id),
                     &payload[offset..offset + chunk_size],
                 )
                 .await?;
@@ -32,24 +309,22 @@ impl crate::application::CoLink {
     #[async_recursion]
     async fn _append_chunks(
         &self,
-        chunk_paths: &str,
+        mut chunk_paths: Vec<String>,
         payload: &[u8],
         key_name: &str,
     ) -> Result<Vec<String>, Error> {
-        let mut chunk_paths = chunk_paths
-            .split(';')
-            .map(|x| x.to_string())
-            .collect::<Vec<String>>();
+        if chunk_paths.is_empty() {
+            return self._store_chunks(payload, key_name).await;
+        }
         let last_chunk_id = chunk_paths.len() - 1;
         let last_chunk_timestamp = chunk_paths[last_chunk_id].clone();
         let mut last_chunk = self
-            .read_entry(&format!(
-                "{}::{}:{}@{}",
-                self.get_user_id()?,
+            .read_entry(&_chunk_key_path(
+                &self.get_user_id()?,
                 key_name,
                 last_chunk_id,
-                last_chunk_timestamp
-            ))
+                &last_chunk_timestamp,
+            )?)
             .await?;
         let mut offset = 0;
         let mut chunk_id = chunk_paths.len();
@@ -61,7 +336,7 @@ impl crate::application::CoLink {
             };
             last_chunk.append(&mut payload[..chunk_size].to_vec());
             let response = self
-                .update_entry(&format!("{}:{}", key_name, last_chunk_id), &last_chunk)
+                .update_entry(&_chunk_key(key_name, last_chunk_id), &last_chunk)
                 .await?;
             chunk_paths[last_chunk_id] = response.split('@').last().unwrap().to_string();
             offset = chunk_size;
@@ -74,7 +349,7 @@ impl crate::application::CoLink {
             };
             let response = self
                 .update_entry(
-                    &format!("{}:{}", key_name, chunk_id),
+                    &_chunk_key(key_name, chunk_id),
                     &payload[offset..offset + chunk_size],
                 )
                 .await?;
@@ -85,16 +360,183 @@ impl crate::application::CoLink {
         Ok(chunk_paths)
     }
 
-    fn _check_chunk_paths_size(&self, chunk_paths: Vec<String>) -> Result<String, Error> {
+    /// Build the metadata for the chunk timestamps. Small entries use the flat layout (`;`-joined timestamps).
+    /// Larger ones use `v2;{depth};{fanout};{references}`, where each level of `{key_name}:chunk_index:{level}:{i}`
+    /// entries holds up to `fanout` timestamps of the level below, and level 0 is the chunks themselves.
+    /// The index entries of `old_index` whose references are unchanged are reused.
+    async fn _store_chunk_metadata(
+        &self,
+        key_name: &str,
+        chunk_paths: Vec<String>,
+        old_index: Option<&ChunkIndex>,
+        chunk_macro: &ChunkStorageMacro,
+    ) -> Result<String, Error> {
         let chunk_paths_string = chunk_paths.join(";");
-        if chunk_paths_string.len() > CHUNK_SIZE {
-            return Err(format!(
-                "File too large: failed to store {} chunks references in metadata",
-                chunk_paths.len()
+        if chunk_paths_string.len() <= chunk_macro.metadata_threshold {
+            return Ok(chunk_paths_string);
+        }
+        let fanout = chunk_macro.metadata_fanout;
+        let old_index = old_index.filter(|old_index| old_index.fanout == fanout);
+        let mut levels = vec![chunk_paths];
+        while levels.len() == 1 || levels[levels.len() - 1].len() > fanout {
+            let depth = levels.len();
+            let mut index_paths = Vec::new();
+            for (i, group) in levels[depth - 1].chunks(fanout).enumerate() {
+                if let Some(timestamp) =
+                    old_index.and_then(|old_index| old_index.unchanged_entry(depth, i, group))
+                {
+                    index_paths.push(timestamp.to_string());
+                    continue;
+                }
+                let response = self
+                    .update_entry(
+                        &_chunk_index_key(key_name, depth, i).to_string(),
+                        group.join(";").as_bytes(),
+                    )
+                    .await?;
+                index_paths.push(response.split('@').last().unwrap().to_string());
+            }
+            levels.push(index_paths);
+        }
+        Ok(format!(
+            "{};{};{};{}",
+            METADATA_VERSION,
+            levels.len() - 1,
+            fanout,
+            levels[levels.len() - 1].join(";")
+        ))
+    }
+
+    async fn _read_chunk_index_entry(
+        &self,
+        key_name: &str,
+        level: usize,
+        i: usize,
+        timestamp: &str,
+    ) -> Result<Vec<String>, Error> {
+        let index = self
+            .read_entry(
+                &_chunk_index_key(key_name, level, i)
+                    .user_id(&self.get_user_id()?)
+                    .timestamp(timestamp.parse()?)
+                    .to_string(),
             )
-            .into());
+            .await?;
+        Ok(_split_timestamps(&String::from_utf8(index)?))
+    }
+
+    /// Resolve the metadata, in either the flat or the hierarchical layout, to all levels of the chunk index.
+    async fn _load_chunk_index(
+        &self,
+        key_name: &str,
+        metadata: &[u8],
+    ) -> Result<ChunkIndex, Error> {
+        let (depth, fanout, references) = _parse_chunk_metadata(metadata)?;
+        let mut levels = vec![references];
+        for level in (1..=depth).rev() {
+            let mut lower_references = Vec::new();
+            for (i, timestamp) in levels[levels.len() - 1].iter().enumerate() {
+                lower_references.extend(
+                    self._read_chunk_index_entry(key_name, level, i, timestamp)
+                        .await?,
+                );
+            }
+            levels.push(lower_references);
+        }
+        levels.reverse();
+        Ok(ChunkIndex { fanout, levels })
+    }
+
+    async fn _load_chunk_paths(
+        &self,
+        key_name: &str,
+        metadata: &[u8],
+    ) -> Result<Vec<String>, Error> {
+        let mut index = self._load_chunk_index(key_name, metadata).await?;
+        Ok(index.levels.swap_remove(0))
+    }
+
+    /// Resolve the metadata to the timestamps of the chunks from `first_chunk_id` to `last_chunk_id`, reading only
+    /// the index entries covering them. Return the id of the first chunk found and the timestamps.
+    async fn _load_chunk_paths_range(
+        &self,
+        key_name: &str,
+        metadata: &[u8],
+        first_chunk_id: usize,
+        last_chunk_id: usize,
+    ) -> Result<(usize, Vec<String>), Error> {
+        let (depth, fanout, mut references) = _parse_chunk_metadata(metadata)?;
+        // the id of the first entry in `references` at the current level
+        let mut start = 0;
+        for level in (1..=depth).rev() {
+            // each entry at the level below covers `span` chunks
+            let span = fanout.saturating_pow(level as u32 - 1);
+            let (first, last) = (first_chunk_id / span, last_chunk_id / span);
+            let mut lower_references = Vec::new();
+            let mut lower_start = None;
+            for (k, timestamp) in references.iter().enumerate() {
+                let i = start + k;
+                if i.saturating_add(1).saturating_mul(fanout) <= first
+                    || i.saturating_mul(fanout) > last
+                {
+                    continue;
+                }
+                let index = self
+                    ._read_chunk_index_entry(key_name, level, i, timestamp)
+                    .await?;
+                for (m, reference) in index.into_iter().enumerate() {
+                    let id = i * fanout + m;
+                    if id >= first && id <= last {
+                        lower_start.get_or_insert(id);
+                        lower_references.push(reference);
+                    }
+                }
+            }
+            references = lower_references;
+            start = lower_start.unwrap_or(0);
         }
-        Ok(chunk_paths_string)
+        if depth == 0 {
+            let end = std::cmp::min(last_chunk_id.saturating_add(1), references.len());
+            start = std::cmp::min(first_chunk_id, end);
+            references = references[start..end].to_vec();
+        }
+        Ok((start, references))
+    }
+
+    /// Delete the chunk index entries that are not used by the metadata of `chunk_len` chunks, e.g. all of them
+    /// after the metadata shrinks back to the flat layout.
+    async fn _delete_stale_chunk_index(
+        &self,
+        key_name: &str,
+        chunk_len: usize,
+        metadata: &[u8],
+    ) -> Result<(), Error> {
+        let (depth, fanout, _) = _parse_chunk_metadata(metadata)?;
+        let prefix = format!("{}:chunk_index:", key_name);
+        let entries = self
+            .read_keys(
+                &KeyPath::new(&prefix)
+                    .user_id(&self.get_user_id()?)
+                    .to_string(),
+                false,
+            )
+            .await?;
+        for entry in entries {
+            let (_, name, _) = _split_key_path(&entry.key_path);
+            let (level, i) = match name
+                .strip_prefix(&prefix)
+                .and_then(|suffix| suffix.split_once(':'))
+                .and_then(|(level, i)| {
+                    Some((level.parse::<usize>().ok()?, i.parse::<usize>().ok()?))
+                }) {
+                Some(res) => res,
+                None => continue,
+            };
+            if level > depth || i >= _index_len(chunk_len, fanout, level) {
+                self.delete_entry(name).await?;
+            }
+        }
+        Ok(())
     }
 
     async fn _store_chunks_compatibility_mode(
@@ -111,7 +553,7 @@ impl crate::application::CoLink {
                 CHUNK_SIZE
             };
             self.update_entry(
-                &format!("{}:{}", key_name, chunk_id),
+                &_chunk_key(key_name, chunk_id),
                 &payload[offset..offset + chunk_size],
             )
             .await?;
@@ -129,7 +571,7 @@ impl crate::application::CoLink {
     ) -> Result<i32, Error> {
         let last_chunk_id = chunk_len - 1;
         let mut last_chunk = self
-            .read_entry(&format!("{}:{}", key_name, last_chunk_id,))
+            .read_entry(&_chunk_key(key_name, last_chunk_id))
             .await?;
         let mut offset = 0;
         let mut chunk_id = chunk_len;
@@ -140,7 +582,7 @@ impl crate::application::CoLink {
                 CHUNK_SIZE - last_chunk.len()
             };
             last_chunk.append(&mut payload[..chunk_size].to_vec());
-            self.update_entry(&format!("{}:{}", key_name, last_chunk_id), &last_chunk)
+            self.update_entry(&_chunk_key(key_name, last_chunk_id), &last_chunk)
                 .await?;
             offset = chunk_size;
         }
@@ -151,7 +593,7 @@ impl crate::application::CoLink {
                 CHUNK_SIZE
             };
             self.update_entry(
-                &format!("{}:{}", key_name, chunk_id),
+                &_chunk_key(key_name, chunk_id),
                 &payload[offset..offset + chunk_size],
             )
             .await?;
@@ -162,12 +604,12 @@ impl crate::application::CoLink {
     }
 
     async fn _delete_chunks_compatibility_mode(&self, key_name: &str) -> Result<String, Error> {
-        let metadata_key = format!("{}:chunk_metadata", key_name);
+        let metadata_key = KeyPath::new(key_name).segment("chunk_metadata").to_string();
         let chunk_len = self.read_entry(&metadata_key).await?;
         let chunk_len = String::from_utf8_lossy(&chunk_len).parse::<i32>()?;
         let res = self.delete_entry(&metadata_key).await?;
         for i in 0..chunk_len {
-            self.delete_entry(&format!("{}:{}", key_name, i)).await?;
+            self.delete_entry(&_chunk_key(key_name, i)).await?;
         }
         Ok(res)
     }
@@ -175,7 +617,10 @@ impl crate::application::CoLink {
     async fn _chunk_lock_compatibility_mode(&self, key_name: &str) -> Result<(), Error> {
         loop {
             if self
-                .create_entry(&format!("{}:chunk_lock", key_name), b"")
+                .create_entry(
+                    &KeyPath::new(key_name).segment("chunk_lock").to_string(),
+                    b"",
+                )
                 .await
                 .is_ok()
             {
@@ -186,7 +631,7 @@ impl crate::application::CoLink {
     }
 
     async fn _chunk_unlock_compatibility_mode(&self, key_name: &str) -> Result<(), Error> {
-        self.delete_entry(&format!("{}:chunk_lock", key_name))
+        self.delete_entry(&KeyPath::new(key_name).segment("chunk_lock").to_string())
             .await?;
         Ok(())
     }
@@ -196,8 +641,9 @@ impl crate::application::CoLink {
         &self,
         key_name: &str,
         payload: &[u8],
+        chunk_macro: &ChunkStorageMacro,
     ) -> Result<String, Error> {
-        let metadata_key = format!("{}:chunk_metadata", key_name);
+        let metadata_key = KeyPath::new(key_name).segment("chunk_metadata").to_string();
         if key_name.contains('$') {
             self._chunk_lock_compatibility_mode(key_name).await?;
             if let Err(e) = self.create_entry(&metadata_key, b"0").await {
@@ -213,28 +659,20 @@ impl crate::application::CoLink {
             self._chunk_unlock_compatibility_mode(key_name).await?;
             return Ok(res);
         }
-        // lock the metadata entry to prevent simultaneous writes
-        let lock_token = self.lock(&metadata_key).await?;
-        // use a closure to prevent locking forever caused by errors
-        let res = async {
-            // create the chunks and store them
-            let chunk_paths = self._store_chunks(payload, key_name).await?;
-            // make sure that the chunk paths are smaller than the maximum entry size
-            let chunk_paths_string = self._check_chunk_paths_size(chunk_paths)?;
-            // store the chunk paths in the metadata entry and update metadata
-            let response = self
-                .create_entry(&metadata_key, chunk_paths_string.as_bytes())
-                .await?;
-            Ok::<String, Error>(response)
-        }
-        .await;
-        self.unlock(lock_token).await?;
-        res
+        // chunks are referenced by timestamps, so writing them cannot affect the current version of the entry
+        let chunk_paths = self._store_chunks(payload, key_name).await?;
+        // use index entries if the chunk paths do not fit in the metadata entry
+        let chunk_paths_string = self
+            ._store_chunk_metadata(key_name, chunk_paths, None, chunk_macro)
+            .await?;
+        // store the chunk paths in the metadata entry, which fails if the entry exists
+        self.create_entry(&metadata_key, chunk_paths_string.as_bytes())
+            .await
     }
 
     #[async_recursion]
     pub(crate) async fn _read_entry_chunk(&self, key_name: &str) -> Result<Vec<u8>, Error> {
-        let metadata_key = format!("{}:chunk_metadata", key_name);
+        let metadata_key = KeyPath::new(key_name).segment("chunk_metadata").to_string();
         if key_name.contains('$') {
             self._chunk_lock_compatibility_mode(key_name).await?;
             let res = async {
@@ -242,7 +680,7 @@ impl crate::application::CoLink {
                 let chunk_len = String::from_utf8_lossy(&chunk_len).parse::<i32>()?;
                 let mut payload = Vec::new();
                 for i in 0..chunk_len {
-                    let mut res = self.read_entry(&format!("{}:{}", key_name, i)).await?;
+                    let mut res = self.read_entry(&_chunk_key(key_name, i)).await?;
                     payload.append(&mut res);
                 }
                 Ok::<Vec<u8>, Error>(payload)
@@ -252,15 +690,41 @@ impl crate::application::CoLink {
             return res;
         }
         let metadata_response = self.read_entry(&metadata_key).await?;
-        let payload_string = String::from_utf8(metadata_response)?;
+        self._read_chunks(key_name, &metadata_response).await
+    }
+
+    /// Read the version of the entry whose metadata was written at `timestamp`, as listed by read_keys with history.
+    /// The chunks and index entries are referenced by their timestamps, so the metadata pins the whole version.
+    #[async_recursion]
+    pub(crate) async fn _read_entry_chunk_version(
+        &self,
+        key_name: &str,
+        timestamp: i64,
+    ) -> Result<Vec<u8>, Error> {
+        if key_name.contains('$') {
+            Err(format!(
+                "Reading an earlier version is not supported for nested $chunk keys: {}",
+                key_name
+            ))?
+        }
+        let metadata_key_path = KeyPath::new(key_name)
+            .segment("chunk_metadata")
+            .user_id(&self.get_user_id()?)
+            .timestamp(timestamp)
+            .to_string();
+        let metadata_response = self.read_entry(&metadata_key_path).await?;
+        self._read_chunks(key_name, &metadata_response).await
+    }
+
+    async fn _read_chunks(&self, key_name: &str, metadata: &[u8]) -> Result<Vec<u8>, Error> {
+        let chunks_paths = self._load_chunk_paths(key_name, metadata).await?;
         let user_id = self.get_user_id()?;
 
         // read the chunks into a single vector
-        let chunks_paths = payload_string.split(';').collect::<Vec<&str>>();
         let mut payload = Vec::new();
         for (i, timestamp) in chunks_paths.iter().enumerate() {
             let mut response = self
-                .read_entry(&format!("{}::{}:{}@{}", user_id, key_name, i, timestamp))
+                .read_entry(&_chunk_key_path(&user_id, key_name, i, timestamp)?)
                 .await?;
             payload.append(&mut response);
         }
@@ -272,8 +736,9 @@ impl crate::application::CoLink {
         &self,
         key_name: &str,
         payload: &[u8],
+        chunk_macro: &ChunkStorageMacro,
     ) -> Result<String, Error> {
-        let metadata_key = format!("{}:chunk_metadata", key_name);
+        let metadata_key = KeyPath::new(key_name).segment("chunk_metadata").to_string();
         if key_name.contains('$') {
             self._chunk_lock_compatibility_mode(key_name).await?;
             let _ = self._delete_chunks_compatibility_mode(key_name).await;
@@ -286,23 +751,39 @@ impl crate::application::CoLink {
             self._chunk_unlock_compatibility_mode(key_name).await?;
             return Ok(res);
         }
-        // lock the metadata entry to prevent simultaneous writes
-        let lock_token = self.lock(&metadata_key).await?;
-        // use a closure to prevent locking forever caused by errors
-        let res = async {
-            // split payload into chunks and update the chunks
-            let chunk_paths = self._store_chunks(payload, key_name).await?;
-            // make sure that the chunk paths are smaller than the maximum entry size
-            let chunk_paths_string = self._check_chunk_paths_size(chunk_paths)?;
-            // update the metadata entry
-            let response = self
-                .update_entry(&metadata_key, chunk_paths_string.as_bytes())
+        // split payload into chunks and update the chunks
+        let chunk_paths = self._store_chunks(payload, key_name).await?;
+        self._replace_chunk_metadata(key_name, chunk_paths, chunk_macro)
+            .await
+    }
+
+    /// Point the metadata entry to the new chunks and delete the chunk index entries the new metadata does not use.
+    async fn _replace_chunk_metadata(
+        &self,
+        key_name: &str,
+        chunk_paths: Vec<String>,
+        chunk_macro: &ChunkStorageMacro,
+    ) -> Result<String, Error> {
+        let metadata_key = KeyPath::new(key_name).segment("chunk_metadata").to_string();
+        let chunk_len = chunk_paths.len();
+        // use index entries if the chunk paths do not fit in the metadata entry
+        let chunk_paths_string = self
+            ._store_chunk_metadata(key_name, chunk_paths, None, chunk_macro)
+            .await?;
+        // update the metadata entry with compare-and-swap, so that concurrent appends are not lost
+        let mut replaced_index = false;
+        let res = self
+            ._update_with(&metadata_key, |metadata| {
+                replaced_index = metadata.map_or(false, |metadata| metadata.starts_with(b"v"));
+                let chunk_paths_string = chunk_paths_string.clone();
+                async move { Ok(chunk_paths_string.into_bytes()) }
+            })
+            .await?;
+        if replaced_index {
+            self._delete_stale_chunk_index(key_name, chunk_len, chunk_paths_string.as_bytes())
                 .await?;
-            Ok::<String, Error>(response)
         }
-        .await;
-        self.unlock(lock_token).await?;
-        res
+        Ok(res)
     }
 
     #[async_recursion]
@@ -310,8 +791,9 @@ impl crate::application::CoLink {
         &self,
         key_name: &str,
         payload: &[u8],
+        chunk_macro: &ChunkStorageMacro,
     ) -> Result<String, Error> {
-        let metadata_key = format!("{}:chunk_metadata", key_name);
+        let metadata_key = KeyPath::new(key_name).segment("chunk_metadata").to_string();
         if key_name.contains('$') {
             self._chunk_lock_compatibility_mode(key_name).await?;
             let chunk_len = self.read_entry(&metadata_key).await?;
@@ -325,27 +807,27 @@ impl crate::application::CoLink {
             self._chunk_unlock_compatibility_mode(key_name).await?;
             return Ok(res);
         }
-        // lock the metadata entry to prevent simultaneous writes
-        let lock_token = self.lock(&metadata_key).await?;
-        // use a closure to prevent locking forever caused by errors
-        let res = async {
+        // the chunks are appended again if the metadata entry is modified concurrently
+        self._update_with(&metadata_key, |metadata| async move {
+            let metadata = match metadata {
+                Some(metadata) => metadata,
+                None => Err(tonic::Status::not_found(format!(
+                    "key {} does not exist.",
+                    key_name
+                )))?,
+            };
             // split payload into chunks and update the chunks
-            let metadata_response = self.read_entry(&metadata_key).await?;
-            let payload_string = String::from_utf8(metadata_response)?;
+            let index = self._load_chunk_index(key_name, &metadata).await?;
             let chunk_paths = self
-                ._append_chunks(&payload_string, payload, key_name)
+                ._append_chunks(index.levels[0].clone(), payload, key_name)
                 .await?;
-            // make sure that the chunk paths are smaller than the maximum entry size
-            let chunk_paths_string = self._check_chunk_paths_size(chunk_paths)?;
-            // update the metadata entry
-            let response = self
-                .update_entry(&metadata_key, chunk_paths_string.as_bytes())
+            // use index entries if the chunk paths do not fit in the metadata entry, only rewriting the changed ones
+            let chunk_paths_string = self
+                ._store_chunk_metadata(key_name, chunk_paths, Some(&index), chunk_macro)
                 .await?;
-            Ok::<String, Error>(response)
-        }
-        .await;
-        self.unlock(lock_token).await?;
-        res
+            Ok(chunk_paths_string.into_bytes())
+        })
+        .await
     }
 
     #[async_recursion]
@@ -356,10 +838,309 @@ impl crate::application::CoLink {
             self._chunk_unlock_compatibility_mode(key_name).await?;
             return res;
         }
-        let metadata_key = format!("{}:chunk_metadata", key_name);
-        let lock_token = self.lock(&metadata_key).await?;
-        let res = self.delete_entry(&metadata_key).await;
-        self.unlock(lock_token).await?;
-        res
+        let metadata_key = KeyPath::new(key_name).segment("chunk_metadata").to_string();
+        // delete the metadata entry with compare-and-swap, so that a concurrent append does not bring it back
+        for _ in 0..CAS_MAX_RETRIES {
+            let res = self
+                .read_entries(&[StorageEntry {
+                    key_name: metadata_key.clone(),
+                    ..Default::default()
+                }])
+                .await?;
+            let metadata_timestamp = get_path_timestamp(&res[0].key_path);
+            if let Some(res) = self
+                ._delete_entry_if(&metadata_key, metadata_timestamp)
+                .await?
+            {
+                self._delete_chunks(key_name).await?;
+                return Ok(res);
+            }
+        }
+        Err(format!(
+            "{} is modified concurrently after {} retries.",
+            key_name, CAS_MAX_RETRIES
+        ))?
+    }
+
+    /// Delete the chunks and the chunk index entries of `key_name`, including the ones left by earlier versions.
+    /// The prefix scan also lists the entries in nested levels, which are skipped by _is_chunk_suffix.
+    async fn _delete_chunks(&self, key_name: &str) -> Result<(), Error> {
+        let key_name_prefix = format!("{}:", key_name);
+        let entries = self
+            .read_keys(
+                &KeyPath::new(&key_name_prefix)
+                    .user_id(&self.get_user_id()?)
+                    .to_string(),
+                false,
+            )
+            .await?;
+        for entry in entries {
+            let (_, name, _) = _split_key_path(&entry.key_path);
+            if let Some(suffix) = name.strip_prefix(&key_name_prefix) {
+                if _is_chunk_suffix(suffix) {
+                    self.delete_entry(name).await?;
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// List the chunked entries under `key_name` as `{user_id}::{key}:$chunk@{timestamp}`, based on their metadata
+    /// entries. With `include_history`, each version of the metadata is listed, and read_entry on a listed key path
+    /// reads that version.
+    ///
+    /// The core read_keys matches the prefix against the key paths as a raw string, so the entries in nested
+    /// levels below `key_name` are listed too, and the trailing `:` keeps out keys like `{key_name}_other`.
+    pub(crate) async fn _read_keys_chunk(
+        &self,
+        key_name: &str,
+        include_history: bool,
+    ) -> Result<Vec<StorageEntry>, Error> {
+        let user_id = self.get_user_id()?;
+        let key_name_prefix = format!("{}:", key_name);
+        let entries = self
+            .read_keys(
+                &KeyPath::new(&key_name_prefix).user_id(&user_id).to_string(),
+                include_history,
+            )
+            .await?;
+        let mut key_list = Vec::new();
+        for entry in entries {
+            let (_, name, timestamp) = _split_key_path(&entry.key_path);
+            let timestamp = timestamp.unwrap_or("0");
+            if let Some(name) = name.strip_suffix(":chunk_metadata") {
+                if name.starts_with(&key_name_prefix) {
+                    key_list.push(StorageEntry {
+                        key_path: KeyPath::new(name)
+                            .storage_macro("chunk")
+                            .user_id(&user_id)
+                            .timestamp(timestamp.parse()?)
+                            .to_string(),
+                        ..Default::default()
+                    });
+                }
+            }
+        }
+        Ok(key_list)
+    }
+
+    /// Read the chunks with bounded concurrency and send them in order, stopping at the first error
+    /// or when the receiver is dropped.
+    async fn _send_chunks(
+        &self,
+        chunk_keys: Vec<String>,
+        sender: &mpsc::Sender<Result<Vec<u8>, Error>>,
+    ) {
+        let mut chunk_keys = chunk_keys.into_iter();
+        let mut tasks = VecDeque::new();
+        loop {
+            while tasks.len() < STREAM_CONCURRENCY {
+                match chunk_keys.next() {
+                    Some(chunk_key) => {
+                        let cl = self.clone();
+                        tasks.push_back(tokio::spawn(
+                            async move { cl.read_entry(&chunk_key).await },
+                        ));
+                    }
+                    None => break,
+                }
+            }
+            let res = match tasks.pop_front() {
+                Some(task) => match task.await {
+                    Ok(res) => res,
+                    Err(e) => Err(e.into()),
+                },
+                None => break,
+            };
+            let is_err = res.is_err();
+            if sender.send(res).await.is_err() || is_err {
+                break;
+            }
+        }
+        for task in tasks {
+            task.abort();
+        }
+    }
+
+    /// Split the stream into chunks and store them with bounded concurrency. Return the timestamps of the chunks.
+    async fn _store_chunks_from_reader(
+        &self,
+        reader: &mut (dyn AsyncRead + Send + Unpin),
+        key_name: &str,
+    ) -> Result<Vec<String>, Error> {
+        let mut chunk_paths = Vec::new();
+        let mut chunk_id = 0;
+        let mut tasks = VecDeque::new();
+        // use a closure to make sure that the running tasks are stopped on errors
+        let res = async {
+            loop {
+                let mut chunk = Vec::with_capacity(CHUNK_SIZE);
+                (&mut *reader)
+                    .take(CHUNK_SIZE as u64)
+                    .read_to_end(&mut chunk)
+                    .await?;
+                if chunk.is_empty() {
+                    break;
+                }
+                if tasks.len() >= STREAM_CONCURRENCY {
+                    let response: String = tasks.pop_front().unwrap().await??;
+                    chunk_paths.push(response.split('@').last().unwrap().to_string());
+                }
+                let cl = self.clone();
+                let chunk_key = _chunk_key(key_name, chunk_id);
+                tasks.push_back(tokio::spawn(async move {
+                    cl.update_entry(&chunk_key, &chunk).await
+                }));
+                chunk_id += 1;
+            }
+            while let Some(task) = tasks.pop_front() {
+                let response: String = task.await??;
+                chunk_paths.push(response.split('@').last().unwrap().to_string());
+            }
+            Ok::<(), Error>(())
+        }
+        .await;
+        for task in tasks {
+            task.abort();
+        }
+        res?;
+        Ok(chunk_paths)
+    }
+
+    /// All chunks except the last one are CHUNK_SIZE bytes, so only the chunks covering the range are read.
+    pub(crate) async fn _read_entry_chunk_range(
+        &self,
+        key_name: &str,
+        offset: u64,
+        length: u64,
+    ) -> Result<Vec<u8>, Error> {
+        let metadata_key = KeyPath::new(key_name).segment("chunk_metadata").to_string();
+        let chunk_size = CHUNK_SIZE as u64;
+        let first_chunk_id = offset / chunk_size;
+        let end = offset.saturating_add(length);
+        if key_name.contains('$') {
+            self._chunk_lock_compatibility_mode(key_name).await?;
+            let res = async {
+                let chunk_len = self.read_entry(&metadata_key).await?;
+                let chunk_len = String::from_utf8_lossy(&chunk_len).parse::<u64>()?;
+                let mut payload = Vec::new();
+                for i in first_chunk_id..chunk_len {
+                    if i * chunk_size >= end {
+                        break;
+                    }
+                    let mut res = self.read_entry(&_chunk_key(key_name, i)).await?;
+                    payload.append(&mut res);
+                }
+                Ok::<Vec<u8>, Error>(payload)
+            }
+            .await;
+            self._chunk_unlock_compatibility_mode(key_name).await?;
+            let payload = res?;
+            return Ok(
+                super::_slice_range(&payload, offset - first_chunk_id * chunk_size, length)
+                    .to_vec(),
+            );
+        }
+        if length == 0 {
+            return Ok(vec![]);
+        }
+        let last_chunk_id = (end - 1) / chunk_size;
+        let metadata_response = self.read_entry(&metadata_key).await?;
+        let (start, chunk_paths) = self
+            ._load_chunk_paths_range(
+                key_name,
+                &metadata_response,
+                usize::try_from(first_chunk_id)?,
+                usize::try_from(last_chunk_id).unwrap_or(usize::MAX),
+            )
+            .await?;
+        let user_id = self.get_user_id()?;
+        let mut payload = Vec::new();
+        for (k, timestamp) in chunk_paths.iter().enumerate() {
+            let i = start + k;
+            let mut res = self
+                .read_entry(&_chunk_key_path(&user_id, key_name, i, timestamp)?)
+                .await?;
+            payload.append(&mut res);
+        }
+        Ok(super::_slice_range(&payload, offset - first_chunk_id * chunk_size, length).to_vec())
+    }
+
+    pub(crate) async fn _read_entry_chunk_stream(
+        &self,
+        key_name: &str,
+    ) -> Result<Box<dyn AsyncRead + Send + Unpin>, Error> {
+        let metadata_key = KeyPath::new(key_name).segment("chunk_metadata").to_string();
+        let (sender, receiver) = mpsc::channel(1);
+        let cl = self.clone();
+        if key_name.contains('$') {
+            self._chunk_lock_compatibility_mode(key_name).await?;
+            let chunk_len = async {
+                let chunk_len = self.read_entry(&metadata_key).await?;
+                Ok::<i32, Error>(String::from_utf8_lossy(&chunk_len).parse::<i32>()?)
+            }
+            .await;
+            let chunk_len = match chunk_len {
+                Ok(chunk_len) => chunk_len,
+                Err(e) => {
+                    self._chunk_unlock_compatibility_mode(key_name).await?;
+                    return Err(e);
+                }
+            };
+            let chunk_keys = (0..chunk_len).map(|i| _chunk_key(key_name, i)).collect();
+            let key_name = key_name.to_string();
+            // the chunk lock is held until all chunks are read or the reader is dropped
+            tokio::spawn(async move {
+                cl._send_chunks(chunk_keys, &sender).await;
+                if let Err(e) = cl._chunk_unlock_compatibility_mode(&key_name).await {
+                    let _ = sender.send(Err(e)).await;
+                }
+            });
+        } else {
+            let metadata_response = self.read_entry(&metadata_key).await?;
+            let chunk_paths = self._load_chunk_paths(key_name, &metadata_response).await?;
+            let user_id = self.get_user_id()?;
+            let chunk_keys = chunk_paths
+                .iter()
+                .enumerate()
+                .map(|(i, timestamp)| _chunk_key_path(&user_id, key_name, i, timestamp))
+                .collect::<Result<_, Error>>()?;
+            tokio::spawn(async move {
+                cl._send_chunks(chunk_keys, &sender).await;
+            });
+        }
+        Ok(Box::new(ChunkStreamReader {
+            receiver,
+            chunk: Vec::new(),
+            offset: 0,
+        }))
+    }
+
+    pub(crate) async fn _write_entry_chunk_stream(
+        &self,
+        key_name: &str,
+        reader: &mut (dyn AsyncRead + Send + Unpin),
+        chunk_macro: &ChunkStorageMacro,
+    ) -> Result<String, Error> {
+        let metadata_key = KeyPath::new(key_name).segment("chunk_metadata").to_string();
+        if key_name.contains('$') {
+            self._chunk_lock_compatibility_mode(key_name).await?;
+            // use a closure to prevent locking forever caused by errors
+            let res = async {
+                let _ = self._delete_chunks_compatibility_mode(key_name).await;
+                let chunk_len = self
+                    ._store_chunks_from_reader(reader, key_name)
+                    .await?
+                    .len();
+                self.update_entry(&metadata_key, chunk_len.to_string().as_bytes())
+                    .await
+            }
+            .await;
+            self._chunk_unlock_compatibility_mode(key_name).await?;
+            return res;
+        }
+        let chunk_paths = self._store_chunks_from_reader(reader, key_name).await?;
+        self._replace_chunk_metadata(key_name, chunk_paths, chunk_macro)
+            .await
     }
 }
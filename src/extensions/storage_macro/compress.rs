@@ -0,0 +1,186 @@
+use super::StorageMacro;
+use crate::{application::CoLink, utils::is_not_found_error, KeyPath};
+use async_recursion::async_recursion;
+use async_trait::async_trait;
+use std::io::{Read, Write};
+
+type Error = Box<dyn std::error::Error + Send + Sync + 'static>;
+
+const ZSTD_MAGIC: [u8; 4] = [0x28, 0xb5, 0x2f, 0xfd];
+const GZIP_MAGIC: [u8; 2] = [0x1f, 0x8b];
+
+pub(crate) struct CompressStorageMacro;
+
+#[async_trait]
+impl StorageMacro for CompressStorageMacro {
+    async fn create(
+        &self,
+        cl: &CoLink,
+        string_before: &str,
+        string_after: &str,
+        payload: &[u8],
+    ) -> Result<String, Error> {
+        cl._create_entry_compress(string_before, string_after, payload)
+            .await
+    }
+
+    async fn read(
+        &self,
+        cl: &CoLink,
+        string_before: &str,
+        string_after: &str,
+    ) -> Result<Vec<u8>, Error> {
+        cl._read_entry_compress(string_before, string_after).await
+    }
+
+    async fn update(
+        &self,
+        cl: &CoLink,
+        string_before: &str,
+        string_after: &str,
+        payload: &[u8],
+    ) -> Result<String, Error> {
+        cl._update_entry_compress(string_before, string_after, payload)
+            .await
+    }
+
+    async fn delete(
+        &self,
+        cl: &CoLink,
+        string_before: &str,
+        string_after: &str,
+    ) -> Result<String, Error> {
+        cl._delete_entry_compress(string_before, string_after).await
+    }
+
+    async fn append(
+        &self,
+        cl: &CoLink,
+        string_before: &str,
+        string_after: &str,
+        payload: &[u8],
+    ) -> Result<String, Error> {
+        cl._append_entry_compress(string_before, string_after, payload)
+            .await
+    }
+}
+
+fn _compress(codec: &str, payload: &[u8]) -> Result<Vec<u8>, Error> {
+    match codec {
+        "zstd" => Ok(zstd::stream::encode_all(payload, 0)?),
+        "gzip" => {
+            let mut encoder =
+                flate2::write::GzEncoder::new(Vec::new(), flate2::Compression::default());
+            encoder.write_all(payload)?;
+            Ok(encoder.finish()?)
+        }
+        _ => Err(format!("Storage Macro: unsupported codec {}.", codec))?,
+    }
+}
+
+/// The stored data is a sequence of zstd/gzip frames, one for each write or append, so frames
+/// written with different codecs can be read back together.
+fn _decompress(mut data: &[u8]) -> Result<Vec<u8>, Error> {
+    let mut payload = Vec::new();
+    while !data.is_empty() {
+        if data.starts_with(&ZSTD_MAGIC) {
+            let mut decoder = zstd::stream::read::Decoder::with_buffer(data)?.single_frame();
+            decoder.read_to_end(&mut payload)?;
+            data = decoder.finish();
+        } else if data.starts_with(&GZIP_MAGIC) {
+            let mut decoder = flate2::bufread::GzDecoder::new(data);
+            decoder.read_to_end(&mut payload)?;
+            data = decoder.into_inner();
+        } else {
+            Err("Storage Macro: the stored data is not compressed by $compress.")?
+        }
+    }
+    Ok(payload)
+}
+
+impl crate::application::CoLink {
+    /// The codec is read from `{key_name}:codec` and defaults to zstd.
+    async fn _sm_compress_get_codec(&self, key_name: &str) -> Result<String, Error> {
+        match self
+            .read_entry(&KeyPath::new(key_name).segment("codec").to_string())
+            .await
+        {
+            Ok(codec) => Ok(String::from_utf8(codec)?),
+            Err(e) if is_not_found_error(&*e) => Ok("zstd".to_string()),
+            Err(e) => Err(e),
+        }
+    }
+
+    fn _sm_compress_get_key(&self, key_name: &str, key_suffix: &str) -> String {
+        KeyPath::new(key_name)
+            .segment("_compress")
+            .segment(key_suffix)
+            .to_string()
+    }
+
+    #[async_recursion]
+    pub(crate) async fn _create_entry_compress(
+        &self,
+        key_name: &str,
+        key_suffix: &str,
+        payload: &[u8],
+    ) -> Result<String, Error> {
+        let codec = self._sm_compress_get_codec(key_name).await?;
+        let data = _compress(&codec, payload)?;
+        self.create_entry(&self._sm_compress_get_key(key_name, key_suffix), &data)
+            .await
+    }
+
+    #[async_recursion]
+    pub(crate) async fn _read_entry_compress(
+        &self,
+        key_name: &str,
+        key_suffix: &str,
+    ) -> Result<Vec<u8>, Error> {
+        let data = self
+            .read_entry(&self._sm_compress_get_key(key_name, key_suffix))
+            .await?;
+        _decompress(&data)
+    }
+
+    #[async_recursion]
+    pub(crate) async fn _update_entry_compress(
+        &self,
+        key_name: &str,
+        key_suffix: &str,
+        payload: &[u8],
+    ) -> Result<String, Error> {
+        let codec = self._sm_compress_get_codec(key_name).await?;
+        let data = _compress(&codec, payload)?;
+        self.update_entry(&self._sm_compress_get_key(key_name, key_suffix), &data)
+            .await
+    }
+
+    #[async_recursion]
+    pub(crate) async fn _append_entry_compress(
+        &self,
+        key_name: &str,
+        key_suffix: &str,
+        payload: &[u8],
+    ) -> Result<String, Error> {
+        let codec = self._sm_compress_get_codec(key_name).await?;
+        let data = _compress(&codec, payload)?;
+        self.update_entry(
+            &KeyPath::new(&self._sm_compress_get_key(key_name, key_suffix))
+                .storage_macro("append")
+                .to_string(),
+            &data,
+        )
+        .await
+    }
+
+    #[async_recursion]
+    pub(crate) async fn _delete_entry_compress(
+        &self,
+        key_name: &str,
+        key_suffix: &str,
+    ) -> Result<String, Error> {
+        self.delete_entry(&self._sm_compress_get_key(key_name, key_suffix))
+            .await
+    }
+}
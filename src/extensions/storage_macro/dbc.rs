@@ -1,52 +1,592 @@
+use super::StorageMacro;
+use crate::{
+    application::CoLink, key_path::_split_key_path, utils::is_not_found_error, KeyPath,
+    StorageEntry,
+};
 use async_recursion::async_recursion;
+use async_trait::async_trait;
+use prost::Message;
 use rdbc2;
 
 type Error = Box<dyn std::error::Error + Send + Sync + 'static>;
 
+pub(crate) struct DbcStorageMacro;
+
+#[async_trait]
+impl StorageMacro for DbcStorageMacro {
+    async fn create(
+        &self,
+        cl: &CoLink,
+        string_before: &str,
+        string_after: &str,
+        payload: &[u8],
+    ) -> Result<String, Error> {
+        cl._write_entry_dbc(string_before, string_after, "create", payload)
+            .await
+    }
+
+    async fn read(
+        &self,
+        cl: &CoLink,
+        string_before: &str,
+        string_after: &str,
+    ) -> Result<Vec<u8>, Error> {
+        cl._read_entry_dbc(string_before, string_after).await
+    }
+
+    async fn update(
+        &self,
+        cl: &CoLink,
+        string_before: &str,
+        string_after: &str,
+        payload: &[u8],
+    ) -> Result<String, Error> {
+        cl._write_entry_dbc(string_before, string_after, "update", payload)
+            .await
+    }
+
+    async fn delete(
+        &self,
+        cl: &CoLink,
+        string_before: &str,
+        string_after: &str,
+    ) -> Result<String, Error> {
+        cl._write_entry_dbc(string_before, string_after, "delete", b"")
+            .await
+    }
+
+    async fn read_keys(
+        &self,
+        cl: &CoLink,
+        _prefix: &str,
+        string_before: &str,
+        string_after: &str,
+        include_history: bool,
+    ) -> Result<Vec<StorageEntry>, Error> {
+        if include_history {
+            return Err("Storage Macro DBC: include_history is not supported.".into());
+        }
+        cl._read_keys_dbc(string_before, string_after).await
+    }
+}
+
+/// The row-protobuf encoding of a $dbc query result.
+#[derive(Clone, PartialEq, Message)]
+pub struct DbcQueryResult {
+    #[prost(message, repeated, tag = "1")]
+    pub columns: Vec<DbcColumn>,
+    #[prost(message, repeated, tag = "2")]
+    pub rows: Vec<DbcRow>,
+    #[prost(uint64, tag = "3")]
+    pub affected_row_count: u64,
+}
+
+#[derive(Clone, PartialEq, Message)]
+pub struct DbcColumn {
+    #[prost(string, tag = "1")]
+    pub name: String,
+    #[prost(string, tag = "2")]
+    pub column_type: String,
+}
+
+#[derive(Clone, PartialEq, Message)]
+pub struct DbcRow {
+    #[prost(message, repeated, tag = "1")]
+    pub values: Vec<DbcValue>,
+}
+
+/// Dates and times are stored as strings, e.g. `2023-01-02 03:04:05.000006` and `-12:34:56.000007`.
+#[derive(Clone, PartialEq, Message)]
+pub struct DbcValue {
+    #[prost(oneof = "dbc_value::Value", tags = "1, 2, 3, 4, 5, 6, 7, 8, 9, 10")]
+    pub value: Option<dbc_value::Value>,
+}
+
+pub mod dbc_value {
+    #[derive(Clone, PartialEq, prost::Oneof)]
+    pub enum Value {
+        #[prost(bool, tag = "1")]
+        Null(bool),
+        #[prost(bytes, tag = "2")]
+        Bytes(Vec<u8>),
+        #[prost(string, tag = "3")]
+        String(String),
+        #[prost(bool, tag = "4")]
+        Bool(bool),
+        #[prost(int64, tag = "5")]
+        Int(i64),
+        #[prost(uint64, tag = "6")]
+        Uint(u64),
+        #[prost(float, tag = "7")]
+        Float(f32),
+        #[prost(double, tag = "8")]
+        Double(f64),
+        #[prost(string, tag = "9")]
+        Date(String),
+        #[prost(string, tag = "10")]
+        Time(String),
+    }
+}
+
+fn _format_date(
+    year: u16,
+    month: u8,
+    day: u8,
+    hour: u8,
+    minute: u8,
+    second: u8,
+    us: u32,
+) -> String {
+    format!(
+        "{:04}-{:02}-{:02} {:02}:{:02}:{:02}.{:06}",
+        year, month, day, hour, minute, second, us
+    )
+}
+
+fn _format_time(negative: bool, days: u32, hours: u8, minutes: u8, seconds: u8, us: u32) -> String {
+    format!(
+        "{}{:02}:{:02}:{:02}.{:06}",
+        if negative { "-" } else { "" },
+        days * 24 + hours as u32,
+        minutes,
+        seconds,
+        us
+    )
+}
+
+fn _get_row_values(row: &rdbc2::dbc::Row) -> Vec<&rdbc2::dbc::Value> {
+    let mut values = vec![];
+    while let Some(value) = row.get_value(values.len()) {
+        values.push(value);
+    }
+    values
+}
+
+fn _encode_csv_field(value: &rdbc2::dbc::Value) -> String {
+    use rdbc2::dbc::Value;
+    let field = match value {
+        Value::NULL => return String::new(),
+        Value::Bytes(bytes) => match String::from_utf8(bytes.clone()) {
+            Ok(s) => s,
+            Err(_) => base64::encode(bytes),
+        },
+        Value::String(s) => s.clone(),
+        Value::Bool(b) => b.to_string(),
+        Value::Int(i) => i.to_string(),
+        Value::UInt(u) => u.to_string(),
+        Value::Float(f) => f.to_string(),
+        Value::Double(d) => d.to_string(),
+        Value::Date(year, month, day, hour, minute, second, us) => {
+            _format_date(*year, *month, *day, *hour, *minute, *second, *us)
+        }
+        Value::Time(negative, days, hours, minutes, seconds, us) => {
+            _format_time(*negative, *days, *hours, *minutes, *seconds, *us)
+        }
+    };
+    _escape_csv_field(&field)
+}
+
+fn _escape_csv_field(field: &str) -> String {
+    if field.contains(|c| c == ',' || c == '"' || c == '\n' || c == '\r') {
+        format!("\"{}\"", field.replace('"', "\"\""))
+    } else {
+        field.to_string()
+    }
+}
+
+fn _encode_csv(result: &rdbc2::dbc::QueryResult) -> Vec<u8> {
+    let mut csv = String::new();
+    if let Some(row) = result.rows.first() {
+        let mut columns = vec![];
+        while let Some(column) = row.get_column(columns.len()) {
+            columns.push(_escape_csv_field(&column.name));
+        }
+        csv += &columns.join(",");
+        csv += "\r\n";
+    }
+    for row in &result.rows {
+        let values = _get_row_values(row)
+            .into_iter()
+            .map(_encode_csv_field)
+            .collect::<Vec<String>>();
+        csv += &values.join(",");
+        csv += "\r\n";
+    }
+    csv.into_bytes()
+}
+
+fn _encode_protobuf(result: &rdbc2::dbc::QueryResult) -> Result<Vec<u8>, Error> {
+    use rdbc2::dbc::Value;
+    let mut columns = vec![];
+    if let Some(row) = result.rows.first() {
+        while let Some(column) = row.get_column(columns.len()) {
+            columns.push(DbcColumn {
+                name: column.name.clone(),
+                column_type: serde_json::to_value(&column.column_type)?
+                    .as_str()
+                    .unwrap_or_default()
+                    .to_string(),
+            });
+        }
+    }
+    let rows = result
+        .rows
+        .iter()
+        .map(|row| DbcRow {
+            values: _get_row_values(row)
+                .into_iter()
+                .map(|value| DbcValue {
+                    value: Some(match value {
+                        Value::NULL => dbc_value::Value::Null(true),
+                        Value::Bytes(bytes) => dbc_value::Value::Bytes(bytes.clone()),
+                        Value::String(s) => dbc_value::Value::String(s.clone()),
+                        Value::Bool(b) => dbc_value::Value::Bool(*b),
+                        Value::Int(i) => dbc_value::Value::Int(*i),
+                        Value::UInt(u) => dbc_value::Value::Uint(*u),
+                        Value::Float(f) => dbc_value::Value::Float(*f),
+                        Value::Double(d) => dbc_value::Value::Double(*d),
+                        Value::Date(year, month, day, hour, minute, second, us) => {
+                            dbc_value::Value::Date(_format_date(
+                                *year, *month, *day, *hour, *minute, *second, *us,
+                            ))
+                        }
+                        Value::Time(negative, days, hours, minutes, seconds, us) => {
+                            dbc_value::Value::Time(_format_time(
+                                *negative, *days, *hours, *minutes, *seconds, *us,
+                            ))
+                        }
+                    }),
+                })
+                .collect(),
+        })
+        .collect();
+    let mut buf = vec![];
+    DbcQueryResult {
+        columns,
+        rows,
+        affected_row_count: result.affected_row_count as u64,
+    }
+    .encode(&mut buf)?;
+    Ok(buf)
+}
+
+/// A string literal that cannot change the statement. rdbc2 has no prepared statements, so the string is escaped
+/// for the database: backslashes are escape characters in MySQL but not in SQLite.
+fn _string_to_sql(param: &str, url: &str) -> String {
+    if url.starts_with("mysql://") {
+        format!(
+            "'{}'",
+            param
+                .replace('\\', "\\\\")
+                .replace('\'', "''")
+                .replace('\0', "\\0")
+        )
+    } else {
+        format!("'{}'", param.replace('\'', "''"))
+    }
+}
+
+/// Parameters in the key name are strings. A parameter quoted as in rdbc2, e.g. `'O''Brien'`, is unquoted first.
+fn _key_param_to_sql(param: &str, url: &str) -> String {
+    match param
+        .strip_prefix('\'')
+        .and_then(|param| param.strip_suffix('\''))
+    {
+        Some(param) => _string_to_sql(&param.replace("''", "'"), url),
+        None => _string_to_sql(param, url),
+    }
+}
+
+fn _json_param_to_sql(param: &serde_json::Value, url: &str) -> Result<String, Error> {
+    match param {
+        serde_json::Value::Null => Ok("NULL".to_string()),
+        serde_json::Value::Bool(b) => Ok(if *b { "TRUE" } else { "FALSE" }.to_string()),
+        serde_json::Value::Number(n) => Ok(n.to_string()),
+        serde_json::Value::String(s) => Ok(_string_to_sql(s, url)),
+        _ => Err("Storage Macro DBC: parameters must be null, bool, number or string.")?,
+    }
+}
+
+/// Replace each `?` outside string literals and comments with the next parameter. The parameters are not bound
+/// by the database: they are SQL literals (see _string_to_sql) spliced into the statement. In MySQL, `\` escapes
+/// the next character in string literals and `#` starts a comment.
+fn _bind_params(
+    query: &str,
+    params: &mut std::slice::Iter<String>,
+    url: &str,
+) -> Result<String, Error> {
+    let mysql = url.starts_with("mysql://");
+    let chars = query.chars().collect::<Vec<char>>();
+    let mut res = String::new();
+    let mut quote: Option<char> = None;
+    let mut i = 0;
+    while i < chars.len() {
+        let c = chars[i];
+        let next = chars.get(i + 1).copied();
+        if let Some(q) = quote {
+            res.push(c);
+            if mysql && c == '\\' && q != '`' {
+                if let Some(next) = next {
+                    res.push(next);
+                    i += 1;
+                }
+            } else if c == q {
+                quote = None;
+            }
+            i += 1;
+            continue;
+        }
+        let comment_end = if (c == '-' && next == Some('-')) || (mysql && c == '#') {
+            Some(
+                chars[i..]
+                    .iter()
+                    .position(|x| *x == '\n')
+                    .map_or(chars.len(), |x| i + x),
+            )
+        } else if c == '/' && next == Some('*') {
+            Some(
+                chars[i + 2..]
+                    .windows(2)
+                    .position(|x| x == ['*', '/'])
+                    .map_or(chars.len(), |x| i + 2 + x + 2),
+            )
+        } else {
+            None
+        };
+        if let Some(end) = comment_end {
+            res.extend(&chars[i..end]);
+            i = end;
+            continue;
+        }
+        match c {
+            '\'' | '"' | '`' => quote = Some(c),
+            '?' => match params.next() {
+                Some(param) => {
+                    res += param;
+                    i += 1;
+                    continue;
+                }
+                None => Err("Number of parameters does not match specified query string")?,
+            },
+            _ => {}
+        }
+        res.push(c);
+        i += 1;
+    }
+    Ok(res)
+}
+
+/// create_entry only runs INSERT statements and delete_entry only runs DELETE statements, so that an entry
+/// operation cannot run an unrelated statement. update_entry runs any statement.
+fn _check_statement_operation(statements: &[String], operation: &str) -> Result<(), Error> {
+    let expected = match operation {
+        "create" => "INSERT",
+        "delete" => "DELETE",
+        _ => return Ok(()),
+    };
+    for statement in statements {
+        let keyword = statement
+            .split_whitespace()
+            .next()
+            .unwrap_or_default()
+            .to_uppercase();
+        if keyword != expected {
+            Err(format!(
+                "Storage Macro DBC: {}_entry only runs {} statements, found {}.",
+                operation, expected, keyword
+            ))?
+        }
+    }
+    Ok(())
+}
+
 impl crate::application::CoLink {
+    /// Find the longest prefix of `string_after_dbc` with a stored statement. The rest are parameters.
     #[async_recursion]
-    async fn _search_and_generate_query(
+    async fn _search_statement(
         &self,
         string_before_dbc: &str,
         string_after_dbc: &str,
     ) -> Result<(String, Vec<String>), Error> {
         let split_key_path: Vec<&str> = string_after_dbc.split(':').collect();
         for i in (0..split_key_path.len()).rev() {
-            let current_key_path =
-                format!("{}:{}", string_before_dbc, split_key_path[0..=i].join(":"));
-            let payload = self.read_entry(current_key_path.as_str()).await;
-            if let Ok(payload) = payload {
-                let query_string = String::from_utf8(payload)?;
-                let count = query_string.matches('?').count();
-                if count != split_key_path.len() - (i + 1) {
-                    return Err("Number of parameters does not match specified query string")?;
-                }
+            let current_key_path = KeyPath::new(string_before_dbc)
+                .segment(split_key_path[0..=i].join(":"))
+                .to_string();
+            if let Ok(payload) = self.read_entry(current_key_path.as_str()).await {
                 let params = split_key_path[(i + 1)..]
                     .iter()
                     .map(|x| x.to_string())
                     .collect::<Vec<String>>();
-                return Ok((query_string, params));
+                return Ok((String::from_utf8(payload)?, params));
             }
         }
         Err("no query string found.")?
     }
 
+    async fn _encode_dbc_result(
+        &self,
+        string_before_dbc: &str,
+        result: &rdbc2::dbc::QueryResult,
+    ) -> Result<Vec<u8>, Error> {
+        let encoding = match self
+            .read_entry(
+                &KeyPath::new(string_before_dbc)
+                    .segment("encoding")
+                    .to_string(),
+            )
+            .await
+        {
+            Ok(encoding) => String::from_utf8(encoding)?,
+            Err(e) if is_not_found_error(&*e) => "json".to_string(),
+            Err(e) => return Err(e),
+        };
+        match encoding.as_str() {
+            "json" => Ok(serde_json::to_vec(result)?),
+            "csv" => Ok(_encode_csv(result)),
+            "protobuf" => _encode_protobuf(result),
+            _ => Err(format!(
+                "Storage Macro DBC: unsupported encoding {}.",
+                encoding
+            ))?,
+        }
+    }
+
     #[async_recursion]
     pub(crate) async fn _read_entry_dbc(
         &self,
         string_before_dbc: &str,
         string_after_dbc: &str,
     ) -> Result<Vec<u8>, Error> {
-        let url_key = format!("{}:url", string_before_dbc);
+        let url_key = KeyPath::new(string_before_dbc).segment("url").to_string();
         let url = self.read_entry(url_key.as_str()).await?;
         let url_string = String::from_utf8(url)?;
-        let (query_string, params) = self
-            ._search_and_generate_query(string_before_dbc, string_after_dbc)
+        let (statement, params) = self
+            ._search_statement(string_before_dbc, string_after_dbc)
             .await?;
-        let params: Vec<&str> = params.iter().map(AsRef::as_ref).collect();
+        let params = params
+            .iter()
+            .map(|x| _key_param_to_sql(x, &url_string))
+            .collect::<Vec<String>>();
+        let mut params = params.iter();
+        let query = _bind_params(&statement, &mut params, &url_string)?;
+        if params.next().is_some() {
+            Err("Number of parameters does not match specified query string")?
+        }
+        let result = {
+            let mut database = rdbc2::dbc::Database::new(url_string.as_str())?;
+            database.execute_query(&query)?
+        };
+        self._encode_dbc_result(string_before_dbc, &result).await
+    }
+
+    /// Execute the statement stored in the key name for `operation` (create, update or delete). The parameters are
+    /// taken from the key name first and then from the payload, which is a JSON array. A statement stored as a JSON
+    /// array of statements runs in a transaction. Return the number of affected rows.
+    #[async_recursion]
+    pub(crate) async fn _write_entry_dbc(
+        &self,
+        string_before_dbc: &str,
+        string_after_dbc: &str,
+        operation: &str,
+        payload: &[u8],
+    ) -> Result<String, Error> {
+        let url_key = KeyPath::new(string_before_dbc).segment("url").to_string();
+        let url = self.read_entry(url_key.as_str()).await?;
+        let url_string = String::from_utf8(url)?;
+        let (statement, key_params) = self
+            ._search_statement(string_before_dbc, string_after_dbc)
+            .await?;
+        let mut params = key_params
+            .iter()
+            .map(|x| _key_param_to_sql(x, &url_string))
+            .collect::<Vec<String>>();
+        if !payload.is_empty() {
+            let payload_params: Vec<serde_json::Value> = serde_json::from_slice(payload)?;
+            for param in &payload_params {
+                params.push(_json_param_to_sql(param, &url_string)?);
+            }
+        }
+        let (statements, transaction) = if statement.trim_start().starts_with('[') {
+            (serde_json::from_str::<Vec<String>>(&statement)?, true)
+        } else {
+            (vec![statement], false)
+        };
+        _check_statement_operation(&statements, operation)?;
+        let mut params = params.iter();
+        let mut queries = vec![];
+        for statement in &statements {
+            queries.push(_bind_params(statement, &mut params, &url_string)?);
+        }
+        if params.next().is_some() {
+            Err("Number of parameters does not match specified query string")?
+        }
         let mut database = rdbc2::dbc::Database::new(url_string.as_str())?;
-        let result = database.execute_query_with_params(query_string.as_str(), &params)?;
-        let seralized_result = serde_json::to_vec(&result)?;
-        Ok(seralized_result)
+        if !transaction {
+            let result = database.execute_query(&queries[0])?;
+            return Ok(result.affected_row_count.to_string());
+        }
+        // MySQL does not prepare BEGIN, and rdbc2 sends the statements that cannot be prepared as text
+        database.execute_query("BEGIN")?;
+        // use a closure to roll back the transaction on errors
+        let res = (|| {
+            let mut affected_row_count = 0;
+            for query in &queries {
+                affected_row_count += database.execute_query(query)?.affected_row_count;
+            }
+            Ok::<usize, Error>(affected_row_count)
+        })();
+        match res {
+            Ok(affected_row_count) => {
+                database.execute_query("COMMIT")?;
+                Ok(affected_row_count.to_string())
+            }
+            Err(e) => {
+                database.execute_query("ROLLBACK")?;
+                Err(e)
+            }
+        }
+    }
+
+    /// List the stored statements under `string_after_dbc` as `{user_id}::{string_before_dbc}:$dbc:{name}@{timestamp}`,
+    /// skipping the `url` and `encoding` settings.
+    pub(crate) async fn _read_keys_dbc(
+        &self,
+        string_before_dbc: &str,
+        string_after_dbc: &str,
+    ) -> Result<Vec<StorageEntry>, Error> {
+        let user_id = self.get_user_id()?;
+        let entries = self
+            .read_keys(
+                &KeyPath::new(string_before_dbc)
+                    .segment(string_after_dbc)
+                    .user_id(&user_id)
+                    .to_string(),
+                false,
+            )
+            .await?;
+        let statement_prefix = format!("{}:", string_before_dbc);
+        let mut key_list = Vec::new();
+        for entry in entries {
+            let (_, name, timestamp) = _split_key_path(&entry.key_path);
+            let timestamp = timestamp.unwrap_or("0");
+            let statement_name = match name.strip_prefix(&statement_prefix) {
+                Some(statement_name) => statement_name,
+                None => continue,
+            };
+            if statement_name == "url" || statement_name == "encoding" {
+                continue;
+            }
+            key_list.push(StorageEntry {
+                key_path: KeyPath::new(string_before_dbc)
+                    .storage_macro("dbc")
+                    .segment(statement_name)
+                    .user_id(&user_id)
+                    .timestamp(timestamp.parse()?)
+                    .to_string(),
+                ..Default::default()
+            });
+        }
+        Ok(key_list)
     }
 }
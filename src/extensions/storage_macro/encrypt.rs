@@ -0,0 +1,413 @@
+use super::StorageMacro;
+use crate::{
+    application::CoLink,
+    colink_proto::*,
+    utils::{get_path_timestamp, is_not_found_error},
+    KeyPath,
+};
+use async_recursion::async_recursion;
+use async_trait::async_trait;
+use chacha20poly1305::{
+    aead::{Aead, KeyInit, Payload},
+    ChaCha20Poly1305,
+};
+use rand::RngCore;
+use sha2::{Digest, Sha256};
+use std::{
+    collections::HashMap,
+    sync::{Arc, RwLock},
+};
+
+type Error = Box<dyn std::error::Error + Send + Sync + 'static>;
+
+const KEY_SIZE: usize = 32;
+const NONCE_SIZE: usize = 12;
+// record layout: version (1) | key version (8) | nonce (12) | ciphertext length (4) | ciphertext
+const RECORD_VERSION: u8 = 1;
+const HEADER_SIZE: usize = 1 + 8 + NONCE_SIZE + 4;
+
+pub(crate) struct EncryptStorageMacro;
+
+#[async_trait]
+impl StorageMacro for EncryptStorageMacro {
+    async fn create(
+        &self,
+        cl: &CoLink,
+        string_before: &str,
+        string_after: &str,
+        payload: &[u8],
+    ) -> Result<String, Error> {
+        cl._create_entry_encrypt(string_before, string_after, payload)
+            .await
+    }
+
+    async fn read(
+        &self,
+        cl: &CoLink,
+        string_before: &str,
+        string_after: &str,
+    ) -> Result<Vec<u8>, Error> {
+        cl._read_entry_encrypt(string_before, string_after).await
+    }
+
+    async fn update(
+        &self,
+        cl: &CoLink,
+        string_before: &str,
+        string_after: &str,
+        payload: &[u8],
+    ) -> Result<String, Error> {
+        cl._update_entry_encrypt(string_before, string_after, payload)
+            .await
+    }
+
+    async fn delete(
+        &self,
+        cl: &CoLink,
+        string_before: &str,
+        string_after: &str,
+    ) -> Result<String, Error> {
+        cl._delete_entry_encrypt(string_before, string_after).await
+    }
+
+    async fn append(
+        &self,
+        cl: &CoLink,
+        string_before: &str,
+        string_after: &str,
+        payload: &[u8],
+    ) -> Result<String, Error> {
+        cl._append_entry_encrypt(string_before, string_after, payload)
+            .await
+    }
+}
+
+pub(crate) type EncryptionKeyRegistry = Arc<RwLock<HashMap<String, Vec<u8>>>>;
+
+/// The configuration name of a key, which is the part before the first storage macro, e.g. `name` for both
+/// `name:$encrypt` and `name:$redis:redis_key:$encrypt`.
+fn _encryption_config_name(key_name: &str) -> String {
+    key_name
+        .split(':')
+        .take_while(|s| !s.contains('$'))
+        .collect::<Vec<&str>>()
+        .join(":")
+}
+
+/// The entry holding the data keys of `name`, which is also the associated data of the wrapped data keys.
+fn _encryption_key_entry(name: &str) -> String {
+    KeyPath::new(name).segment("encryption_key").to_string()
+}
+
+fn _check_encryption_key(key: &[u8]) -> Result<(), Error> {
+    if key.len() != KEY_SIZE {
+        Err(format!(
+            "Storage Macro: the encryption key must be {} bytes, found {} bytes.",
+            KEY_SIZE,
+            key.len()
+        ))?
+    }
+    Ok(())
+}
+
+impl crate::application::CoLink {
+    /// Set the master key of `key_name:$encrypt`. The master key is only kept in this CoLink object and its
+    /// clones, and is never sent to the core. It encrypts the data keys, which are stored in
+    /// `key_name:encryption_key`.
+    pub fn set_encryption_key(&self, key_name: &str, key: &[u8]) -> Result<(), Error> {
+        _check_encryption_key(key)?;
+        self.encryption_keys
+            .write()
+            .unwrap()
+            .insert(key_name.to_string(), key.to_vec());
+        Ok(())
+    }
+
+    /// Derive the master key of `key_name:$encrypt` from the user's secret key.
+    pub fn set_encryption_key_from_secret_key(
+        &self,
+        key_name: &str,
+        secret_key: &secp256k1::SecretKey,
+    ) -> Result<(), Error> {
+        let mut hasher = Sha256::new();
+        hasher.update(b"colink-storage-macro-encrypt");
+        hasher.update(secret_key.secret_bytes());
+        hasher.update(key_name.as_bytes());
+        self.set_encryption_key(key_name, &hasher.finalize())
+    }
+
+    /// Replace the data key of `key_name:$encrypt` with a random one. New writes use the new data key. Existing
+    /// records are not re-encrypted: they stay readable because the previous data keys are kept in the history
+    /// of `key_name:encryption_key`, so deleting that entry makes all data under `key_name:$encrypt` unreadable.
+    pub async fn rotate_encryption_key(&self, key_name: &str) -> Result<String, Error> {
+        let wrapped_key = self._sm_encrypt_new_data_key(key_name)?;
+        self.update_entry(&_encryption_key_entry(key_name), &wrapped_key)
+            .await
+    }
+
+    fn _sm_encrypt_get_master_key(&self, name: &str) -> Result<ChaCha20Poly1305, Error> {
+        match self.encryption_keys.read().unwrap().get(name) {
+            Some(key) => Ok(ChaCha20Poly1305::new_from_slice(key)?),
+            None => Err(format!(
+                "Storage Macro: the encryption key of {} is not set.",
+                name
+            ))?,
+        }
+    }
+
+    /// Generate a data key and encrypt it with the master key.
+    fn _sm_encrypt_new_data_key(&self, name: &str) -> Result<Vec<u8>, Error> {
+        let master_key = self._sm_encrypt_get_master_key(name)?;
+        let mut key = [0u8; KEY_SIZE];
+        rand::thread_rng().fill_bytes(&mut key);
+        let mut nonce = [0u8; NONCE_SIZE];
+        rand::thread_rng().fill_bytes(&mut nonce);
+        let aad = _encryption_key_entry(name);
+        let wrapped_key = match master_key.encrypt(
+            (&nonce).into(),
+            Payload {
+                msg: &key,
+                aad: aad.as_bytes(),
+            },
+        ) {
+            Ok(wrapped_key) => wrapped_key,
+            Err(_) => Err("Storage Macro: encryption failed.")?,
+        };
+        Ok([&nonce[..], &wrapped_key].concat())
+    }
+
+    /// Decrypt a data key with the master key.
+    fn _sm_encrypt_unwrap_data_key(
+        &self,
+        name: &str,
+        wrapped_key: &[u8],
+    ) -> Result<Vec<u8>, Error> {
+        let master_key = self._sm_encrypt_get_master_key(name)?;
+        if wrapped_key.len() < NONCE_SIZE {
+            Err("Storage Macro: the data key is malformed.")?
+        }
+        let nonce: [u8; NONCE_SIZE] = wrapped_key[..NONCE_SIZE].try_into()?;
+        let aad = _encryption_key_entry(name);
+        let key = match master_key.decrypt(
+            (&nonce).into(),
+            Payload {
+                msg: &wrapped_key[NONCE_SIZE..],
+                aad: aad.as_bytes(),
+            },
+        ) {
+            Ok(key) => key,
+            Err(_) => Err(format!(
+                "Storage Macro: failed to decrypt the data key in {}, the encryption key is wrong.",
+                aad
+            ))?,
+        };
+        _check_encryption_key(&key)?;
+        Ok(key)
+    }
+
+    /// Return the version and the current data key, and create the first data key if there is none.
+    async fn _sm_encrypt_get_current_key(&self, key_name: &str) -> Result<(i64, Vec<u8>), Error> {
+        let name = _encryption_config_name(key_name);
+        let key_entry = _encryption_key_entry(&name);
+        let entry = match self
+            .read_entries(&[StorageEntry {
+                key_name: key_entry.clone(),
+                ..Default::default()
+            }])
+            .await
+        {
+            Ok(mut res) => res.remove(0),
+            Err(e) if is_not_found_error(&*e) => {
+                let wrapped_key = self._sm_encrypt_new_data_key(&name)?;
+                match self.create_entry(&key_entry, &wrapped_key).await {
+                    Ok(key_path) => StorageEntry {
+                        key_path,
+                        payload: wrapped_key,
+                        ..Default::default()
+                    },
+                    // the first data key is created concurrently
+                    Err(_) => self
+                        .read_entries(&[StorageEntry {
+                            key_name: key_entry.clone(),
+                            ..Default::default()
+                        }])
+                        .await?
+                        .remove(0),
+                }
+            }
+            Err(e) => return Err(e),
+        };
+        let key = self._sm_encrypt_unwrap_data_key(&name, &entry.payload)?;
+        Ok((get_path_timestamp(&entry.key_path), key))
+    }
+
+    async fn _sm_encrypt_get_key(&self, key_name: &str, version: i64) -> Result<Vec<u8>, Error> {
+        let name = _encryption_config_name(key_name);
+        let key_entry = _encryption_key_entry(&name);
+        let wrapped_key = match self
+            .read_entry(
+                &KeyPath::new(&key_entry)
+                    .user_id(&self.get_user_id()?)
+                    .timestamp(version)
+                    .to_string(),
+            )
+            .await
+        {
+            Ok(wrapped_key) => wrapped_key,
+            Err(e) if is_not_found_error(&*e) => Err(format!(
+                "Storage Macro: encryption key {}@{} not found.",
+                key_entry, version
+            ))?,
+            Err(e) => return Err(e),
+        };
+        self._sm_encrypt_unwrap_data_key(&name, &wrapped_key)
+    }
+
+    fn _sm_encrypt_get_key_name(&self, key_name: &str, key_suffix: &str) -> String {
+        KeyPath::new(key_name)
+            .segment("_encrypt")
+            .segment(key_suffix)
+            .to_string()
+    }
+
+    /// The key name is used as associated data, so records cannot be moved to another key without being detected.
+    async fn _sm_encrypt(
+        &self,
+        key_name: &str,
+        key_suffix: &str,
+        payload: &[u8],
+    ) -> Result<Vec<u8>, Error> {
+        let (version, key) = self._sm_encrypt_get_current_key(key_name).await?;
+        let cipher = ChaCha20Poly1305::new_from_slice(&key)?;
+        let mut nonce = [0u8; NONCE_SIZE];
+        rand::thread_rng().fill_bytes(&mut nonce);
+        let aad = self._sm_encrypt_get_key_name(key_name, key_suffix);
+        let ciphertext = match cipher.encrypt(
+            (&nonce).into(),
+            Payload {
+                msg: payload,
+                aad: aad.as_bytes(),
+            },
+        ) {
+            Ok(ciphertext) => ciphertext,
+            Err(_) => Err("Storage Macro: encryption failed.")?,
+        };
+        let mut record = Vec::with_capacity(HEADER_SIZE + ciphertext.len());
+        record.push(RECORD_VERSION);
+        record.extend_from_slice(&version.to_be_bytes());
+        record.extend_from_slice(&nonce);
+        record.extend_from_slice(&u32::try_from(ciphertext.len())?.to_be_bytes());
+        record.extend_from_slice(&ciphertext);
+        Ok(record)
+    }
+
+    async fn _sm_decrypt(
+        &self,
+        key_name: &str,
+        key_suffix: &str,
+        mut data: &[u8],
+    ) -> Result<Vec<u8>, Error> {
+        let aad = self._sm_encrypt_get_key_name(key_name, key_suffix);
+        let tampered = format!(
+            "Storage Macro: failed to decrypt {}, the data has been tampered with or the key is wrong.",
+            aad
+        );
+        let mut payload = Vec::new();
+        let mut cached_key: Option<(i64, Vec<u8>)> = None;
+        while !data.is_empty() {
+            if data.len() < HEADER_SIZE || data[0] != RECORD_VERSION {
+                Err(tampered.clone())?
+            }
+            let version = i64::from_be_bytes(data[1..9].try_into()?);
+            let nonce: [u8; NONCE_SIZE] = data[9..9 + NONCE_SIZE].try_into()?;
+            let len = u32::from_be_bytes(data[9 + NONCE_SIZE..HEADER_SIZE].try_into()?) as usize;
+            if data.len() < HEADER_SIZE + len {
+                Err(tampered.clone())?
+            }
+            let key = match &cached_key {
+                Some((cached_version, key)) if *cached_version == version => key.clone(),
+                _ => {
+                    let key = self._sm_encrypt_get_key(key_name, version).await?;
+                    cached_key = Some((version, key.clone()));
+                    key
+                }
+            };
+            let cipher = ChaCha20Poly1305::new_from_slice(&key)?;
+            match cipher.decrypt(
+                (&nonce).into(),
+                Payload {
+                    msg: &data[HEADER_SIZE..HEADER_SIZE + len],
+                    aad: aad.as_bytes(),
+                },
+            ) {
+                Ok(mut res) => payload.append(&mut res),
+                Err(_) => Err(tampered.clone())?,
+            }
+            data = &data[HEADER_SIZE + len..];
+        }
+        Ok(payload)
+    }
+
+    #[async_recursion]
+    pub(crate) async fn _create_entry_encrypt(
+        &self,
+        key_name: &str,
+        key_suffix: &str,
+        payload: &[u8],
+    ) -> Result<String, Error> {
+        let data = self._sm_encrypt(key_name, key_suffix, payload).await?;
+        self.create_entry(&self._sm_encrypt_get_key_name(key_name, key_suffix), &data)
+            .await
+    }
+
+    #[async_recursion]
+    pub(crate) async fn _read_entry_encrypt(
+        &self,
+        key_name: &str,
+        key_suffix: &str,
+    ) -> Result<Vec<u8>, Error> {
+        let data = self
+            .read_entry(&self._sm_encrypt_get_key_name(key_name, key_suffix))
+            .await?;
+        self._sm_decrypt(key_name, key_suffix, &data).await
+    }
+
+    #[async_recursion]
+    pub(crate) async fn _update_entry_encrypt(
+        &self,
+        key_name: &str,
+        key_suffix: &str,
+        payload: &[u8],
+    ) -> Result<String, Error> {
+        let data = self._sm_encrypt(key_name, key_suffix, payload).await?;
+        self.update_entry(&self._sm_encrypt_get_key_name(key_name, key_suffix), &data)
+            .await
+    }
+
+    #[async_recursion]
+    pub(crate) async fn _append_entry_encrypt(
+        &self,
+        key_name: &str,
+        key_suffix: &str,
+        payload: &[u8],
+    ) -> Result<String, Error> {
+        let data = self._sm_encrypt(key_name, key_suffix, payload).await?;
+        self.update_entry(
+            &KeyPath::new(&self._sm_encrypt_get_key_name(key_name, key_suffix))
+                .storage_macro("append")
+                .to_string(),
+            &data,
+        )
+        .await
+    }
+
+    #[async_recursion]
+    pub(crate) async fn _delete_entry_encrypt(
+        &self,
+        key_name: &str,
+        key_suffix: &str,
+    ) -> Result<String, Error> {
+        self.delete_entry(&self._sm_encrypt_get_key_name(key_name, key_suffix))
+            .await
+    }
+}
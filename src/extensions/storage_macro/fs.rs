@@ -1,27 +1,278 @@
+use super::StorageMacro;
+use crate::{application::CoLink, utils::is_not_found_error, KeyPath, StorageEntry};
 use async_recursion::async_recursion;
-use std::path::PathBuf;
-use tokio::io::AsyncWriteExt;
+use async_trait::async_trait;
+use std::path::{Path, PathBuf};
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncSeekExt, AsyncWriteExt};
 
 type Error = Box<dyn std::error::Error + Send + Sync + 'static>;
 
+const TEMP_FILE_PREFIX: &str = ".colink-fs-tmp-"; // temporary files of atomic writes, hidden from read_keys
+
+pub(crate) struct FsStorageMacro;
+
+#[async_trait]
+impl StorageMacro for FsStorageMacro {
+    async fn create(
+        &self,
+        cl: &CoLink,
+        string_before: &str,
+        string_after: &str,
+        payload: &[u8],
+    ) -> Result<String, Error> {
+        cl._create_entry_fs(string_before, string_after, payload)
+            .await
+    }
+
+    async fn read(
+        &self,
+        cl: &CoLink,
+        string_before: &str,
+        string_after: &str,
+    ) -> Result<Vec<u8>, Error> {
+        cl._read_entry_fs(string_before, string_after).await
+    }
+
+    async fn update(
+        &self,
+        cl: &CoLink,
+        string_before: &str,
+        string_after: &str,
+        payload: &[u8],
+    ) -> Result<String, Error> {
+        cl._update_entry_fs(string_before, string_after, payload)
+            .await
+    }
+
+    async fn delete(
+        &self,
+        cl: &CoLink,
+        string_before: &str,
+        string_after: &str,
+    ) -> Result<String, Error> {
+        cl._delete_entry_fs(string_before, string_after).await
+    }
+
+    async fn append(
+        &self,
+        cl: &CoLink,
+        string_before: &str,
+        string_after: &str,
+        payload: &[u8],
+    ) -> Result<String, Error> {
+        cl._append_entry_fs(string_before, string_after, payload)
+            .await
+    }
+
+    async fn read_range(
+        &self,
+        cl: &CoLink,
+        string_before: &str,
+        string_after: &str,
+        offset: u64,
+        length: u64,
+    ) -> Result<Vec<u8>, Error> {
+        let path = cl._sm_fs_get_path(string_before, string_after).await?;
+        let mut file = tokio::fs::File::open(path).await?;
+        file.seek(std::io::SeekFrom::Start(offset)).await?;
+        let mut data = Vec::new();
+        file.take(length).read_to_end(&mut data).await?;
+        Ok(data)
+    }
+
+    async fn read_stream(
+        &self,
+        cl: &CoLink,
+        string_before: &str,
+        string_after: &str,
+    ) -> Result<Box<dyn AsyncRead + Send + Unpin>, Error> {
+        let path = cl._sm_fs_get_path(string_before, string_after).await?;
+        Ok(Box::new(tokio::fs::File::open(path).await?))
+    }
+
+    async fn write_stream(
+        &self,
+        cl: &CoLink,
+        string_before: &str,
+        string_after: &str,
+        reader: &mut (dyn AsyncRead + Send + Unpin),
+    ) -> Result<String, Error> {
+        cl._write_entry_fs_atomic(string_before, string_after, reader)
+            .await
+    }
+
+    async fn read_keys(
+        &self,
+        cl: &CoLink,
+        prefix: &str,
+        string_before: &str,
+        string_after: &str,
+        include_history: bool,
+    ) -> Result<Vec<StorageEntry>, Error> {
+        if include_history {
+            return Err("Storage Macro: include_history is not supported.".into());
+        }
+        let key_list = cl._read_keys_fs(string_before, string_after).await?;
+        Ok(key_list
+            .into_iter()
+            .map(|key| StorageEntry {
+                key_path: format!("{}:{}@0", prefix, key),
+                ..Default::default()
+            })
+            .collect())
+    }
+}
+
+/// Reject `path` if it resolves to a location outside `base` through symlinks. The deepest existing ancestor of
+/// `path` is resolved, so that paths can be checked before their files and directories are created.
+async fn _sm_fs_check_symlinks(base: &Path, path: &Path) -> Result<(), Error> {
+    let base = match tokio::fs::canonicalize(base).await {
+        Ok(base) => base,
+        // nothing under the base path exists yet
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(()),
+        Err(e) => return Err(e.into()),
+    };
+    let mut existing = path.to_path_buf();
+    loop {
+        match tokio::fs::canonicalize(&existing).await {
+            Ok(resolved) => {
+                if !resolved.starts_with(&base) {
+                    Err(format!(
+                        "Storage Macro FS: {} is outside the base path.",
+                        path.display()
+                    ))?
+                }
+                return Ok(());
+            }
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => {
+                if !existing.pop() {
+                    return Ok(());
+                }
+            }
+            Err(e) => return Err(e.into()),
+        }
+    }
+}
+
 impl crate::application::CoLink {
+    /// The key suffix is a `:`-separated path relative to the base path in `{path_key_name}:path`.
+    /// `.` and `..` are resolved, and paths outside the base path, also through symlinks, are rejected.
     async fn _sm_fs_get_path(
         &self,
         path_key_name: &str,
         path_suffix: &str,
     ) -> Result<PathBuf, Error> {
-        let path_key = format!("{}:path", path_key_name);
-        let mut path = String::from_utf8(self.read_entry(&path_key).await?)?;
-        if !path_suffix.is_empty() {
-            let path_suffix = path_suffix.replace(':', "/");
-            path += "/";
-            path += &path_suffix;
+        let path_key = KeyPath::new(path_key_name).segment("path").to_string();
+        let mut path = PathBuf::from(String::from_utf8(self.read_entry(&path_key).await?)?);
+        if path_suffix.is_empty() {
+            return Ok(path);
         }
-        let path = PathBuf::from(path);
-        tokio::fs::create_dir_all(path.parent().unwrap()).await?;
+        let mut segments: Vec<&str> = Vec::new();
+        for segment in path_suffix.split(':') {
+            match segment {
+                "" | "." => {}
+                ".." => {
+                    if segments.pop().is_none() {
+                        Err(format!(
+                            "Storage Macro FS: {} is outside the base path.",
+                            path_suffix
+                        ))?
+                    }
+                }
+                _ => {
+                    if segment.contains(['/', '\\', '\0']) {
+                        Err(format!(
+                            "Storage Macro FS: invalid path segment {}.",
+                            segment
+                        ))?
+                    }
+                    segments.push(segment);
+                }
+            }
+        }
+        let base = path.clone();
+        path.extend(segments);
+        _sm_fs_check_symlinks(&base, &path).await?;
         Ok(path)
     }
 
+    /// The permissions of new files, e.g. `600`, are read from `{path_key_name}:permissions`.
+    async fn _sm_fs_get_permissions(&self, path_key_name: &str) -> Result<Option<u32>, Error> {
+        match self
+            .read_entry(
+                &KeyPath::new(path_key_name)
+                    .segment("permissions")
+                    .to_string(),
+            )
+            .await
+        {
+            Ok(permissions) => Ok(Some(u32::from_str_radix(
+                String::from_utf8(permissions)?.trim(),
+                8,
+            )?)),
+            Err(e) if is_not_found_error(&*e) => Ok(None),
+            Err(e) => Err(e),
+        }
+    }
+
+    async fn _sm_fs_create_parent(&self, path: &Path) -> Result<(), Error> {
+        if let Some(parent) = path.parent() {
+            tokio::fs::create_dir_all(parent).await?;
+        }
+        Ok(())
+    }
+
+    async fn _sm_fs_set_permissions(
+        &self,
+        path: &Path,
+        permissions: Option<u32>,
+    ) -> Result<(), Error> {
+        #[cfg(unix)]
+        if let Some(permissions) = permissions {
+            use std::os::unix::fs::PermissionsExt;
+            tokio::fs::set_permissions(path, std::fs::Permissions::from_mode(permissions)).await?;
+        }
+        #[cfg(not(unix))]
+        let _ = (path, permissions);
+        Ok(())
+    }
+
+    /// Write to a temporary file in the same directory and rename it, so that readers never see a partial file.
+    pub(crate) async fn _write_entry_fs_atomic(
+        &self,
+        path_key_name: &str,
+        path_suffix: &str,
+        reader: &mut (dyn AsyncRead + Send + Unpin),
+    ) -> Result<String, Error> {
+        let path = self._sm_fs_get_path(path_key_name, path_suffix).await?;
+        let permissions = self._sm_fs_get_permissions(path_key_name).await?;
+        self._sm_fs_create_parent(&path).await?;
+        let file_name = match path.file_name() {
+            Some(file_name) => file_name.to_string_lossy().to_string(),
+            None => Err("Storage Macro FS: invalid file path.")?,
+        };
+        let temp_path = path.with_file_name(format!(
+            "{}{}-{}",
+            TEMP_FILE_PREFIX,
+            uuid::Uuid::new_v4(),
+            file_name
+        ));
+        let res = async {
+            let mut file = tokio::fs::File::create(&temp_path).await?;
+            tokio::io::copy(reader, &mut file).await?;
+            file.sync_all().await?;
+            drop(file);
+            self._sm_fs_set_permissions(&temp_path, permissions).await?;
+            tokio::fs::rename(&temp_path, &path).await?;
+            Ok::<String, Error>("ok".to_string())
+        }
+        .await;
+        if res.is_err() {
+            let _ = tokio::fs::remove_file(&temp_path).await;
+        }
+        res
+    }
+
     #[async_recursion]
     pub(crate) async fn _create_entry_fs(
         &self,
@@ -30,12 +281,15 @@ impl crate::application::CoLink {
         payload: &[u8],
     ) -> Result<String, Error> {
         let path = self._sm_fs_get_path(path_key_name, path_suffix).await?;
+        let permissions = self._sm_fs_get_permissions(path_key_name).await?;
+        self._sm_fs_create_parent(&path).await?;
         let mut file = tokio::fs::OpenOptions::new()
             .write(true)
             .create_new(true)
-            .open(path)
+            .open(&path)
             .await?;
         file.write_all(payload).await?;
+        self._sm_fs_set_permissions(&path, permissions).await?;
         Ok("ok".to_string())
     }
 
@@ -57,10 +311,8 @@ impl crate::application::CoLink {
         path_suffix: &str,
         payload: &[u8],
     ) -> Result<String, Error> {
-        let path = self._sm_fs_get_path(path_key_name, path_suffix).await?;
-        let mut file = tokio::fs::File::create(path).await?;
-        file.write_all(payload).await?;
-        Ok("ok".to_string())
+        self._write_entry_fs_atomic(path_key_name, path_suffix, &mut &payload[..])
+            .await
     }
 
     #[async_recursion]
@@ -97,6 +349,8 @@ impl crate::application::CoLink {
         Ok("ok".to_string())
     }
 
+    /// List the files and directories in the directory. With `{path_key_name}:recursive_read_keys` set to `true`,
+    /// list the files in all subdirectories instead, as `:`-separated paths.
     #[async_recursion]
     pub(crate) async fn _read_keys_fs(
         &self,
@@ -104,10 +358,34 @@ impl crate::application::CoLink {
         prefix: &str,
     ) -> Result<Vec<String>, Error> {
         let path = self._sm_fs_get_path(path_key_name, prefix).await?;
+        let recursive = match self
+            .read_entry(
+                &KeyPath::new(path_key_name)
+                    .segment("recursive_read_keys")
+                    .to_string(),
+            )
+            .await
+        {
+            Ok(recursive) => recursive == b"true",
+            Err(e) if is_not_found_error(&*e) => false,
+            Err(e) => return Err(e),
+        };
         let mut key_list: Vec<String> = Vec::new();
-        let mut dir = tokio::fs::read_dir(path).await?;
-        while let Some(entry) = dir.next_entry().await? {
-            key_list.push(entry.file_name().to_string_lossy().to_string());
+        let mut dirs = vec![(path, String::new())];
+        while let Some((path, key_prefix)) = dirs.pop() {
+            let mut dir = tokio::fs::read_dir(path).await?;
+            while let Some(entry) = dir.next_entry().await? {
+                let file_name = entry.file_name().to_string_lossy().to_string();
+                if file_name.starts_with(TEMP_FILE_PREFIX) {
+                    continue;
+                }
+                let key = format!("{}{}", key_prefix, file_name);
+                if recursive && entry.file_type().await?.is_dir() {
+                    dirs.push((entry.path(), format!("{}:", key)));
+                } else {
+                    key_list.push(key);
+                }
+            }
         }
         Ok(key_list)
     }
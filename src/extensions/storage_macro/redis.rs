@@ -1,16 +1,325 @@
+use super::StorageMacro;
+use crate::{application::CoLink, utils::is_not_found_error, KeyPath, StorageEntry};
 use async_recursion::async_recursion;
-use redis::{aio::Connection, AsyncCommands};
+use async_trait::async_trait;
+use redis::{
+    aio::{ConnectionLike, MultiplexedConnection},
+    cluster::ClusterClientBuilder,
+    cluster_async::ClusterConnection,
+    cluster_routing::{Route, RoutingInfo, SingleNodeRoutingInfo, SlotAddr},
+    AsyncCommands, Cmd, FromRedisValue, Pipeline, RedisFuture, Value,
+};
+use std::{
+    collections::HashMap,
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc, Mutex,
+    },
+    time::Duration,
+};
 
 type Error = Box<dyn std::error::Error + Send + Sync + 'static>;
 
+pub(crate) struct RedisStorageMacro;
+
+#[async_trait]
+impl StorageMacro for RedisStorageMacro {
+    async fn create(
+        &self,
+        cl: &CoLink,
+        string_before: &str,
+        string_after: &str,
+        payload: &[u8],
+    ) -> Result<String, Error> {
+        cl._create_entry_redis(string_before, string_after, payload)
+            .await
+    }
+
+    async fn read(
+        &self,
+        cl: &CoLink,
+        string_before: &str,
+        string_after: &str,
+    ) -> Result<Vec<u8>, Error> {
+        cl._read_entry_redis(string_before, string_after).await
+    }
+
+    async fn update(
+        &self,
+        cl: &CoLink,
+        string_before: &str,
+        string_after: &str,
+        payload: &[u8],
+    ) -> Result<String, Error> {
+        cl._update_entry_redis(string_before, string_after, payload)
+            .await
+    }
+
+    async fn update_with_ttl(
+        &self,
+        cl: &CoLink,
+        string_before: &str,
+        string_after: &str,
+        payload: &[u8],
+        ttl: Duration,
+    ) -> Result<String, Error> {
+        cl._update_entry_with_ttl_redis(string_before, string_after, payload, ttl)
+            .await
+    }
+
+    async fn delete(
+        &self,
+        cl: &CoLink,
+        string_before: &str,
+        string_after: &str,
+    ) -> Result<String, Error> {
+        cl._delete_entry_redis(string_before, string_after).await
+    }
+
+    async fn append(
+        &self,
+        cl: &CoLink,
+        string_before: &str,
+        string_after: &str,
+        payload: &[u8],
+    ) -> Result<String, Error> {
+        cl._append_entry_redis(string_before, string_after, payload)
+            .await
+    }
+
+    async fn read_range(
+        &self,
+        cl: &CoLink,
+        string_before: &str,
+        string_after: &str,
+        offset: u64,
+        length: u64,
+    ) -> Result<Vec<u8>, Error> {
+        cl._read_entry_range_redis(string_before, string_after, offset, length)
+            .await
+    }
+
+    async fn read_keys(
+        &self,
+        cl: &CoLink,
+        prefix: &str,
+        string_before: &str,
+        string_after: &str,
+        include_history: bool,
+    ) -> Result<Vec<StorageEntry>, Error> {
+        if include_history {
+            return Err("Storage Macro: include_history is not supported.".into());
+        }
+        let key_list = cl._read_keys_redis(string_before, string_after).await?;
+        Ok(key_list
+            .into_iter()
+            .map(|key| StorageEntry {
+                key_path: format!("{}:{}@0", prefix, key),
+                ..Default::default()
+            })
+            .collect())
+    }
+}
+
+pub(crate) type RedisConnectionPool = Arc<Mutex<RedisConnections>>;
+
+#[derive(Default)]
+pub(crate) struct RedisConnections {
+    // the settings of each key path, so that they are only read when a connection is made
+    configs: HashMap<String, RedisConfig>,
+    connections: HashMap<RedisConfig, RedisConnection>,
+}
+
+/// The connection settings of a $redis key.
+#[derive(Clone, PartialEq, Eq, Hash)]
+pub(crate) struct RedisConfig {
+    url: String,
+    cluster: bool,
+    tls_ca: Option<Vec<u8>>,
+    // the PEM encoded client certificate and client key
+    tls_client: Option<(Vec<u8>, Vec<u8>)>,
+}
+
+#[derive(Clone)]
+enum RedisConnectionInner {
+    Single(MultiplexedConnection),
+    Cluster(ClusterConnection),
+}
+
+/// A pooled connection, which is replaced after a connection error.
+#[derive(Clone)]
+pub(crate) struct RedisConnection {
+    inner: RedisConnectionInner,
+    broken: Arc<AtomicBool>,
+}
+
+impl RedisConnection {
+    fn check_result<T>(broken: &AtomicBool, res: redis::RedisResult<T>) -> redis::RedisResult<T> {
+        if let Err(e) = &res {
+            if e.is_io_error() || e.is_connection_dropped() || e.is_connection_refusal() {
+                broken.store(true, Ordering::Relaxed);
+            }
+        }
+        res
+    }
+}
+
+impl ConnectionLike for RedisConnection {
+    fn req_packed_command<'a>(&'a mut self, cmd: &'a Cmd) -> RedisFuture<'a, Value> {
+        Box::pin(async move {
+            let res = match &mut self.inner {
+                RedisConnectionInner::Single(con) => con.req_packed_command(cmd).await,
+                RedisConnectionInner::Cluster(con) => con.req_packed_command(cmd).await,
+            };
+            Self::check_result(&self.broken, res)
+        })
+    }
+
+    fn req_packed_commands<'a>(
+        &'a mut self,
+        cmd: &'a Pipeline,
+        offset: usize,
+        count: usize,
+    ) -> RedisFuture<'a, Vec<Value>> {
+        Box::pin(async move {
+            let res = match &mut self.inner {
+                RedisConnectionInner::Single(con) => {
+                    con.req_packed_commands(cmd, offset, count).await
+                }
+                RedisConnectionInner::Cluster(con) => {
+                    con.req_packed_commands(cmd, offset, count).await
+                }
+            };
+            Self::check_result(&self.broken, res)
+        })
+    }
+
+    fn get_db(&self) -> i64 {
+        match &self.inner {
+            RedisConnectionInner::Single(con) => con.get_db(),
+            RedisConnectionInner::Cluster(con) => con.get_db(),
+        }
+    }
+}
+
 impl crate::application::CoLink {
-    async fn _get_con_from_stored_credentials(&self, key_path: &str) -> Result<Connection, Error> {
-        let redis_url_key = format!("{}:redis_url", key_path);
-        let redis_url = self.read_entry(redis_url_key.as_str()).await?;
-        let redis_url_string = String::from_utf8(redis_url)?;
-        let client = redis::Client::open(redis_url_string)?;
-        let con = client.get_async_connection().await?;
-        Ok(con)
+    /// Get a connection to the redis in `{key_path}:redis_url` from the pool shared by the clones of this CoLink.
+    /// Connections are shared by the keys with the same url, cluster mode and TLS settings. The settings are read
+    /// when the key path is first used and again when its connection is replaced after an error.
+    async fn _get_con_from_stored_credentials(
+        &self,
+        key_path: &str,
+    ) -> Result<RedisConnection, Error> {
+        let pooled_con = {
+            let pool = self.redis_connections.lock().unwrap();
+            pool.configs
+                .get(key_path)
+                .and_then(|config| pool.connections.get(config))
+                .cloned()
+        };
+        if let Some(con) = pooled_con {
+            if !con.broken.load(Ordering::Relaxed) {
+                return Ok(con);
+            }
+        }
+        let config = self._read_redis_config(key_path).await?;
+        let pooled_con = self
+            .redis_connections
+            .lock()
+            .unwrap()
+            .connections
+            .get(&config)
+            .cloned();
+        let con = match pooled_con {
+            Some(con) if !con.broken.load(Ordering::Relaxed) => con,
+            _ => self._connect_redis(&config).await?,
+        };
+        let mut pool = self.redis_connections.lock().unwrap();
+        pool.configs.insert(key_path.to_string(), config.clone());
+        // another task may have connected in the meantime
+        match pool.connections.get(&config) {
+            Some(existing) if !existing.broken.load(Ordering::Relaxed) => Ok(existing.clone()),
+            _ => {
+                pool.connections.insert(config, con.clone());
+                Ok(con)
+            }
+        }
+    }
+
+    /// `{key_path}:redis_url` is a comma-separated list of the initial nodes for clusters, which are enabled by
+    /// `{key_path}:redis_cluster`. The PEM encoded CA certificate, client certificate and client key for rediss://
+    /// urls are read from `{key_path}:redis_tls_ca`, `{key_path}:redis_tls_cert` and `{key_path}:redis_tls_key`.
+    async fn _read_redis_config(&self, key_path: &str) -> Result<RedisConfig, Error> {
+        let url = self
+            .read_entry(&KeyPath::new(key_path).segment("redis_url").to_string())
+            .await?;
+        let cluster = self._read_redis_setting(key_path, "redis_cluster").await?;
+        let tls_ca = self._read_redis_setting(key_path, "redis_tls_ca").await?;
+        let tls_cert = self._read_redis_setting(key_path, "redis_tls_cert").await?;
+        let tls_key = self._read_redis_setting(key_path, "redis_tls_key").await?;
+        Ok(RedisConfig {
+            url: String::from_utf8(url)?,
+            cluster: cluster.map_or(false, |cluster| cluster == b"true"),
+            tls_ca,
+            tls_client: match (tls_cert, tls_key) {
+                (Some(client_cert), Some(client_key)) => Some((client_cert, client_key)),
+                _ => None,
+            },
+        })
+    }
+
+    async fn _read_redis_setting(
+        &self,
+        key_path: &str,
+        name: &str,
+    ) -> Result<Option<Vec<u8>>, Error> {
+        match self
+            .read_entry(&KeyPath::new(key_path).segment(name).to_string())
+            .await
+        {
+            Ok(setting) => Ok(Some(setting)),
+            Err(e) if is_not_found_error(&*e) => Ok(None),
+            Err(e) => Err(e),
+        }
+    }
+
+    async fn _connect_redis(&self, config: &RedisConfig) -> Result<RedisConnection, Error> {
+        let certs = if config.tls_ca.is_some() || config.tls_client.is_some() {
+            Some(redis::TlsCertificates {
+                client_tls: config.tls_client.clone().map(|(client_cert, client_key)| {
+                    redis::ClientTlsConfig {
+                        client_cert,
+                        client_key,
+                    }
+                }),
+                root_cert: config.tls_ca.clone(),
+            })
+        } else {
+            None
+        };
+        let inner = if config.cluster {
+            let mut builder = ClusterClientBuilder::new(
+                config
+                    .url
+                    .split(',')
+                    .map(|x| x.trim())
+                    .collect::<Vec<&str>>(),
+            );
+            if let Some(certs) = certs {
+                builder = builder.certs(certs);
+            }
+            RedisConnectionInner::Cluster(builder.build()?.get_async_connection().await?)
+        } else {
+            let client = match certs {
+                Some(certs) => redis::Client::build_with_tls(config.url.as_str(), certs)?,
+                None => redis::Client::open(config.url.as_str())?,
+            };
+            RedisConnectionInner::Single(client.get_multiplexed_tokio_connection().await?)
+        };
+        Ok(RedisConnection {
+            inner,
+            broken: Arc::new(AtomicBool::new(false)),
+        })
     }
 
     #[async_recursion]
@@ -38,8 +347,34 @@ impl crate::application::CoLink {
         let response: Option<Vec<u8>> = con.get(key_name).await?;
         match response {
             Some(response) => Ok(response),
-            None => Err("key does not exist.")?,
+            None => Err(tonic::Status::not_found("key does not exist."))?,
+        }
+    }
+
+    #[async_recursion]
+    pub(crate) async fn _read_entry_range_redis(
+        &self,
+        address: &str,
+        key_name: &str,
+        offset: u64,
+        length: u64,
+    ) -> Result<Vec<u8>, Error> {
+        let mut con = self._get_con_from_stored_credentials(address).await?;
+        let (exists, response): (bool, Vec<u8>) = if length == 0 {
+            (con.exists(key_name).await?, Vec::new())
+        } else {
+            let end = std::cmp::min(offset.saturating_add(length - 1), isize::MAX as u64);
+            redis::pipe()
+                .atomic()
+                .exists(key_name)
+                .getrange(key_name, offset as isize, end as isize)
+                .query_async(&mut con)
+                .await?
+        };
+        if !exists {
+            Err(tonic::Status::not_found("key does not exist."))?
         }
+        Ok(response)
     }
 
     #[async_recursion]
@@ -54,6 +389,26 @@ impl crate::application::CoLink {
         Ok(response)
     }
 
+    /// Use SET with PX, so that redis removes the key when it expires.
+    #[async_recursion]
+    pub(crate) async fn _update_entry_with_ttl_redis(
+        &self,
+        address: &str,
+        key_name: &str,
+        payload: &[u8],
+        ttl: Duration,
+    ) -> Result<String, Error> {
+        let mut con = self._get_con_from_stored_credentials(address).await?;
+        let response = con
+            .pset_ex(
+                key_name,
+                payload,
+                std::cmp::max(ttl.as_millis(), 1) as usize,
+            )
+            .await?;
+        Ok(response)
+    }
+
     #[async_recursion]
     pub(crate) async fn _append_entry_redis(
         &self,
@@ -69,6 +424,18 @@ impl crate::application::CoLink {
         Ok(response)
     }
 
+    #[async_recursion]
+    pub(crate) async fn _atomic_add_redis(
+        &self,
+        address: &str,
+        key_name: &str,
+        delta: i64,
+    ) -> Result<i64, Error> {
+        let mut con = self._get_con_from_stored_credentials(address).await?;
+        let response: i64 = con.incr(key_name, delta).await?;
+        Ok(response)
+    }
+
     #[async_recursion]
     pub(crate) async fn _delete_entry_redis(
         &self,
@@ -78,7 +445,7 @@ impl crate::application::CoLink {
         let mut con = self._get_con_from_stored_credentials(address).await?;
         let response: i32 = con.del(key_name).await?;
         if response == 0 {
-            Err("key does not exist.")?
+            Err(tonic::Status::not_found("key does not exist."))?
         }
         Ok(response.to_string())
     }
@@ -90,11 +457,235 @@ impl crate::application::CoLink {
         prefix: &str,
     ) -> Result<Vec<String>, Error> {
         let mut con = self._get_con_from_stored_credentials(address).await?;
-        let res: Vec<String> = con.keys(format!("{}:*", prefix)).await?;
-        let mut key_list: Vec<String> = Vec::new();
-        for key in res {
-            key_list.push(key[prefix.len() + 1..].to_string());
+        // use SCAN instead of KEYS, which blocks the server while it goes through all keys
+        let pattern = format!("{}:*", _escape_redis_pattern(prefix));
+        let mut keys: Vec<String> = Vec::new();
+        let broken = con.broken.clone();
+        match &mut con.inner {
+            RedisConnectionInner::Single(_) => {
+                let mut iter = con.scan_match::<_, String>(pattern).await?;
+                while let Some(key) = iter.next_item().await {
+                    keys.push(key);
+                }
+            }
+            RedisConnectionInner::Cluster(cluster) => {
+                // SCAN only goes through the keys of the node it runs on, so it runs on every primary,
+                // which is reached by routing to one of its slots
+                let slots = RedisConnection::check_result(
+                    &broken,
+                    cluster
+                        .route_command(
+                            &redis::cmd("CLUSTER").arg("SLOTS").clone(),
+                            RoutingInfo::SingleNode(SingleNodeRoutingInfo::Random),
+                        )
+                        .await,
+                )?;
+                let mut primaries = HashMap::new();
+                for slot_range in Vec::<Vec<Value>>::from_redis_value(&slots)? {
+                    if slot_range.len() < 3 {
+                        Err("Storage Macro Redis: CLUSTER SLOTS returned an invalid reply.")?
+                    }
+                    let start = u16::from_redis_value(&slot_range[0])?;
+                    let (host, port): (String, u16) = match &slot_range[2] {
+                        Value::Bulk(node) if node.len() >= 2 => (
+                            String::from_redis_value(&node[0])?,
+                            u16::from_redis_value(&node[1])?,
+                        ),
+                        _ => Err("Storage Macro Redis: CLUSTER SLOTS returned an invalid reply.")?,
+                    };
+                    primaries
+                        .entry(format!("{}:{}", host, port))
+                        .or_insert(start);
+                }
+                for slot in primaries.into_values() {
+                    let mut cursor = 0u64;
+                    loop {
+                        let res = RedisConnection::check_result(
+                            &broken,
+                            cluster
+                                .route_command(
+                                    redis::cmd("SCAN").arg(cursor).arg("MATCH").arg(&pattern),
+                                    RoutingInfo::SingleNode(SingleNodeRoutingInfo::SpecificNode(
+                                        Route::new(slot, SlotAddr::Master),
+                                    )),
+                                )
+                                .await,
+                        )?;
+                        let (next_cursor, batch): (u64, Vec<String>) =
+                            FromRedisValue::from_redis_value(&res)?;
+                        keys.extend(batch);
+                        if next_cursor == 0 {
+                            break;
+                        }
+                        cursor = next_cursor;
+                    }
+                }
+            }
+        }
+        // SCAN may return a key more than once
+        keys.sort();
+        keys.dedup();
+        Ok(keys
+            .into_iter()
+            .map(|key| key[prefix.len() + 1..].to_string())
+            .collect())
+    }
+
+    /// Split a $redis key into the prefix holding the connection settings and the redis key.
+    fn _parse_redis_key(&self, key_name: &str) -> Result<(String, String), Error> {
+        let (string_before, macro_type, string_after) = self._parse_macro(key_name);
+        if macro_type != "redis" {
+            Err(format!("{} is not a $redis key.", key_name))?
+        }
+        Ok((string_before, string_after))
+    }
+
+    /// Set `field` of the redis hash `key_name`, which is a $redis key.
+    pub async fn redis_hset(&self, key_name: &str, field: &str, value: &[u8]) -> Result<(), Error> {
+        let (address, key_name) = self._parse_redis_key(key_name)?;
+        let mut con = self._get_con_from_stored_credentials(&address).await?;
+        con.hset(key_name, field, value).await?;
+        Ok(())
+    }
+
+    pub async fn redis_hget(&self, key_name: &str, field: &str) -> Result<Vec<u8>, Error> {
+        let (address, key_name) = self._parse_redis_key(key_name)?;
+        let mut con = self._get_con_from_stored_credentials(&address).await?;
+        let response: Option<Vec<u8>> = con.hget(key_name, field).await?;
+        match response {
+            Some(response) => Ok(response),
+            None => Err(tonic::Status::not_found("field does not exist."))?,
+        }
+    }
+
+    /// Return whether the field existed.
+    pub async fn redis_hdel(&self, key_name: &str, field: &str) -> Result<bool, Error> {
+        let (address, key_name) = self._parse_redis_key(key_name)?;
+        let mut con = self._get_con_from_stored_credentials(&address).await?;
+        let response: i32 = con.hdel(key_name, field).await?;
+        Ok(response > 0)
+    }
+
+    pub async fn redis_hgetall(&self, key_name: &str) -> Result<Vec<(String, Vec<u8>)>, Error> {
+        let (address, key_name) = self._parse_redis_key(key_name)?;
+        let mut con = self._get_con_from_stored_credentials(&address).await?;
+        Ok(con.hgetall(key_name).await?)
+    }
+
+    /// Push `value` to the head of the redis list `key_name`. Return the length of the list.
+    pub async fn redis_lpush(&self, key_name: &str, value: &[u8]) -> Result<usize, Error> {
+        let (address, key_name) = self._parse_redis_key(key_name)?;
+        let mut con = self._get_con_from_stored_credentials(&address).await?;
+        Ok(con.lpush(key_name, value).await?)
+    }
+
+    /// Push `value` to the tail of the redis list `key_name`. Return the length of the list.
+    pub async fn redis_rpush(&self, key_name: &str, value: &[u8]) -> Result<usize, Error> {
+        let (address, key_name) = self._parse_redis_key(key_name)?;
+        let mut con = self._get_con_from_stored_credentials(&address).await?;
+        Ok(con.rpush(key_name, value).await?)
+    }
+
+    pub async fn redis_lpop(&self, key_name: &str) -> Result<Option<Vec<u8>>, Error> {
+        let (address, key_name) = self._parse_redis_key(key_name)?;
+        let mut con = self._get_con_from_stored_credentials(&address).await?;
+        Ok(con.lpop(key_name, None).await?)
+    }
+
+    pub async fn redis_rpop(&self, key_name: &str) -> Result<Option<Vec<u8>>, Error> {
+        let (address, key_name) = self._parse_redis_key(key_name)?;
+        let mut con = self._get_con_from_stored_credentials(&address).await?;
+        Ok(con.rpop(key_name, None).await?)
+    }
+
+    /// Negative indices count from the tail of the list, e.g. `redis_lrange(key_name, 0, -1)` returns the whole list.
+    pub async fn redis_lrange(
+        &self,
+        key_name: &str,
+        start: isize,
+        stop: isize,
+    ) -> Result<Vec<Vec<u8>>, Error> {
+        let (address, key_name) = self._parse_redis_key(key_name)?;
+        let mut con = self._get_con_from_stored_credentials(&address).await?;
+        Ok(con.lrange(key_name, start, stop).await?)
+    }
+
+    /// Add `member` to the redis set `key_name`. Return whether it was not in the set.
+    pub async fn redis_sadd(&self, key_name: &str, member: &[u8]) -> Result<bool, Error> {
+        let (address, key_name) = self._parse_redis_key(key_name)?;
+        let mut con = self._get_con_from_stored_credentials(&address).await?;
+        let response: i32 = con.sadd(key_name, member).await?;
+        Ok(response > 0)
+    }
+
+    /// Return whether `member` was in the set.
+    pub async fn redis_srem(&self, key_name: &str, member: &[u8]) -> Result<bool, Error> {
+        let (address, key_name) = self._parse_redis_key(key_name)?;
+        let mut con = self._get_con_from_stored_credentials(&address).await?;
+        let response: i32 = con.srem(key_name, member).await?;
+        Ok(response > 0)
+    }
+
+    pub async fn redis_sismember(&self, key_name: &str, member: &[u8]) -> Result<bool, Error> {
+        let (address, key_name) = self._parse_redis_key(key_name)?;
+        let mut con = self._get_con_from_stored_credentials(&address).await?;
+        Ok(con.sismember(key_name, member).await?)
+    }
+
+    pub async fn redis_smembers(&self, key_name: &str) -> Result<Vec<Vec<u8>>, Error> {
+        let (address, key_name) = self._parse_redis_key(key_name)?;
+        let mut con = self._get_con_from_stored_credentials(&address).await?;
+        Ok(con.smembers(key_name).await?)
+    }
+
+    /// Add `member` to the redis sorted set `key_name`, or update its score. Return whether it was not in the set.
+    pub async fn redis_zadd(
+        &self,
+        key_name: &str,
+        member: &[u8],
+        score: f64,
+    ) -> Result<bool, Error> {
+        let (address, key_name) = self._parse_redis_key(key_name)?;
+        let mut con = self._get_con_from_stored_credentials(&address).await?;
+        let response: i32 = con.zadd(key_name, member, score).await?;
+        Ok(response > 0)
+    }
+
+    /// Return whether `member` was in the sorted set.
+    pub async fn redis_zrem(&self, key_name: &str, member: &[u8]) -> Result<bool, Error> {
+        let (address, key_name) = self._parse_redis_key(key_name)?;
+        let mut con = self._get_con_from_stored_credentials(&address).await?;
+        let response: i32 = con.zrem(key_name, member).await?;
+        Ok(response > 0)
+    }
+
+    pub async fn redis_zscore(&self, key_name: &str, member: &[u8]) -> Result<Option<f64>, Error> {
+        let (address, key_name) = self._parse_redis_key(key_name)?;
+        let mut con = self._get_con_from_stored_credentials(&address).await?;
+        Ok(con.zscore(key_name, member).await?)
+    }
+
+    /// Return the members with scores between `min` and `max` (inclusive) in ascending order of their scores.
+    pub async fn redis_zrange_by_score(
+        &self,
+        key_name: &str,
+        min: f64,
+        max: f64,
+    ) -> Result<Vec<(Vec<u8>, f64)>, Error> {
+        let (address, key_name) = self._parse_redis_key(key_name)?;
+        let mut con = self._get_con_from_stored_credentials(&address).await?;
+        Ok(con.zrangebyscore_withscores(key_name, min, max).await?)
+    }
+}
+
+/// Escape the glob-style special characters so that the prefix is matched literally.
+fn _escape_redis_pattern(prefix: &str) -> String {
+    let mut pattern = String::with_capacity(prefix.len());
+    for c in prefix.chars() {
+        if matches!(c, '*' | '?' | '[' | ']' | '\\') {
+            pattern.push('\\');
         }
-        Ok(key_list)
+        pattern.push(c);
     }
+    pattern
 }
@@ -0,0 +1,599 @@
+use super::StorageMacro;
+use crate::{application::CoLink, KeyPath, StorageEntry};
+use async_recursion::async_recursion;
+use async_trait::async_trait;
+use hmac::{Hmac, Mac};
+use reqwest::{Method, StatusCode};
+use sha2::{Digest, Sha256};
+use tokio::io::{AsyncRead, AsyncReadExt};
+
+type Error = Box<dyn std::error::Error + Send + Sync + 'static>;
+
+const PART_SIZE: usize = 8 * 1024 * 1024; // payloads larger than one part use multipart uploads
+
+pub(crate) struct S3StorageMacro;
+
+#[async_trait]
+impl StorageMacro for S3StorageMacro {
+    async fn create(
+        &self,
+        cl: &CoLink,
+        string_before: &str,
+        string_after: &str,
+        payload: &[u8],
+    ) -> Result<String, Error> {
+        cl._write_entry_s3(string_before, string_after, &mut &payload[..], true)
+            .await
+    }
+
+    async fn read(
+        &self,
+        cl: &CoLink,
+        string_before: &str,
+        string_after: &str,
+    ) -> Result<Vec<u8>, Error> {
+        cl._read_entry_s3(string_before, string_after).await
+    }
+
+    async fn update(
+        &self,
+        cl: &CoLink,
+        string_before: &str,
+        string_after: &str,
+        payload: &[u8],
+    ) -> Result<String, Error> {
+        cl._write_entry_s3(string_before, string_after, &mut &payload[..], false)
+            .await
+    }
+
+    async fn delete(
+        &self,
+        cl: &CoLink,
+        string_before: &str,
+        string_after: &str,
+    ) -> Result<String, Error> {
+        cl._delete_entry_s3(string_before, string_after).await
+    }
+
+    async fn read_range(
+        &self,
+        cl: &CoLink,
+        string_before: &str,
+        string_after: &str,
+        offset: u64,
+        length: u64,
+    ) -> Result<Vec<u8>, Error> {
+        cl._read_entry_range_s3(string_before, string_after, offset, length)
+            .await
+    }
+
+    async fn write_stream(
+        &self,
+        cl: &CoLink,
+        string_before: &str,
+        string_after: &str,
+        reader: &mut (dyn AsyncRead + Send + Unpin),
+    ) -> Result<String, Error> {
+        cl._write_entry_s3(string_before, string_after, reader, false)
+            .await
+    }
+
+    async fn read_keys(
+        &self,
+        cl: &CoLink,
+        prefix: &str,
+        string_before: &str,
+        string_after: &str,
+        include_history: bool,
+    ) -> Result<Vec<StorageEntry>, Error> {
+        if include_history {
+            return Err("Storage Macro S3: include_history is not supported.".into());
+        }
+        let key_list = cl._read_keys_s3(string_before, string_after).await?;
+        Ok(key_list
+            .into_iter()
+            .map(|key| StorageEntry {
+                key_path: format!("{}:{}@0", prefix, key),
+                ..Default::default()
+            })
+            .collect())
+    }
+}
+
+/// The settings in the storage entries `{key_name}:s3_endpoint`, `s3_bucket`, `s3_region` (defaults to us-east-1),
+/// `s3_access_key` and `s3_secret_key`. Objects are addressed in path style, which works with S3 and MinIO.
+struct S3Config {
+    endpoint: url::Url,
+    bucket: String,
+    region: String,
+    access_key: String,
+    secret_key: String,
+}
+
+struct S3Response {
+    status: StatusCode,
+    etag: Option<String>,
+    body: Vec<u8>,
+}
+
+fn _uri_encode(s: &str, encode_slash: bool) -> String {
+    let mut encoded = String::with_capacity(s.len());
+    for b in s.bytes() {
+        match b {
+            b'A'..=b'Z' | b'a'..=b'z' | b'0'..=b'9' | b'-' | b'_' | b'.' | b'~' => {
+                encoded.push(b as char)
+            }
+            b'/' if !encode_slash => encoded.push('/'),
+            _ => encoded.push_str(&format!("%{:02X}", b)),
+        }
+    }
+    encoded
+}
+
+fn _hmac_sha256(key: &[u8], data: &[u8]) -> Vec<u8> {
+    let mut mac = Hmac::<Sha256>::new_from_slice(key).expect("HMAC can take key of any size");
+    mac.update(data);
+    mac.finalize().into_bytes().to_vec()
+}
+
+/// Return the text of the `tag` elements in an XML response.
+fn _xml_values(body: &[u8], tag: &str) -> Vec<String> {
+    let body = String::from_utf8_lossy(body);
+    let open = format!("<{}>", tag);
+    let close = format!("</{}>", tag);
+    let mut values = Vec::new();
+    let mut rest = &body[..];
+    while let Some(start) = rest.find(&open) {
+        rest = &rest[start + open.len()..];
+        let end = match rest.find(&close) {
+            Some(end) => end,
+            None => break,
+        };
+        values.push(
+            rest[..end]
+                .replace("&lt;", "<")
+                .replace("&gt;", ">")
+                .replace("&quot;", "\"")
+                .replace("&apos;", "'")
+                .replace("&amp;", "&"),
+        );
+        rest = &rest[end + close.len()..];
+    }
+    values
+}
+
+/// The key suffix is a `:`-separated object key, which is stored with `/` as the separator.
+fn _object_key(key_suffix: &str) -> Result<String, Error> {
+    if key_suffix.is_empty() {
+        Err("Storage Macro S3: the object key is empty.")?
+    }
+    Ok(key_suffix.replace(':', "/"))
+}
+
+impl S3Config {
+    /// Sign the request with AWS Signature Version 4 and send it.
+    async fn send(
+        &self,
+        method: Method,
+        object_key: &str,
+        query: &[(&str, &str)],
+        headers: &[(&str, String)],
+        body: Vec<u8>,
+    ) -> Result<S3Response, Error> {
+        let now = chrono::Utc::now();
+        let amz_date = now.format("%Y%m%dT%H%M%SZ").to_string();
+        let date = now.format("%Y%m%d").to_string();
+        let payload_hash = format!("{:x}", Sha256::digest(&body));
+        let host = match (self.endpoint.host_str(), self.endpoint.port()) {
+            (Some(host), Some(port)) => format!("{}:{}", host, port),
+            (Some(host), None) => host.to_string(),
+            (None, _) => Err("Storage Macro S3: invalid endpoint.")?,
+        };
+        let mut path = format!(
+            "{}/{}",
+            self.endpoint.path().trim_end_matches('/'),
+            _uri_encode(&self.bucket, true)
+        );
+        if !object_key.is_empty() {
+            path = format!("{}/{}", path, _uri_encode(object_key, false));
+        }
+        let mut query = query
+            .iter()
+            .map(|(k, v)| (_uri_encode(k, true), _uri_encode(v, true)))
+            .collect::<Vec<(String, String)>>();
+        query.sort();
+        let query = query
+            .iter()
+            .map(|(k, v)| format!("{}={}", k, v))
+            .collect::<Vec<String>>()
+            .join("&");
+        let canonical_request = format!(
+            "{}\n{}\n{}\nhost:{}\nx-amz-content-sha256:{}\nx-amz-date:{}\n\nhost;x-amz-content-sha256;x-amz-date\n{}",
+            method, path, query, host, payload_hash, amz_date, payload_hash
+        );
+        let scope = format!("{}/{}/s3/aws4_request", date, self.region);
+        let string_to_sign = format!(
+            "AWS4-HMAC-SHA256\n{}\n{}\n{:x}",
+            amz_date,
+            scope,
+            Sha256::digest(canonical_request.as_bytes())
+        );
+        let mut signing_key = _hmac_sha256(
+            format!("AWS4{}", self.secret_key).as_bytes(),
+            date.as_bytes(),
+        );
+        for part in [self.region.as_str(), "s3", "aws4_request"] {
+            signing_key = _hmac_sha256(&signing_key, part.as_bytes());
+        }
+        let signature = _hmac_sha256(&signing_key, string_to_sign.as_bytes())
+            .iter()
+            .map(|b| format!("{:02x}", b))
+            .collect::<String>();
+        let authorization = format!(
+            "AWS4-HMAC-SHA256 Credential={}/{}, SignedHeaders=host;x-amz-content-sha256;x-amz-date, Signature={}",
+            self.access_key, scope, signature
+        );
+        let mut url = self.endpoint.clone();
+        url.set_path(&path);
+        url.set_query(if query.is_empty() { None } else { Some(&query) });
+        let mut request = reqwest::Client::new()
+            .request(method, url)
+            .header("x-amz-content-sha256", payload_hash)
+            .header("x-amz-date", amz_date)
+            .header("authorization", authorization);
+        for (name, value) in headers {
+            request = request.header(*name, value);
+        }
+        let response = request.body(body).send().await?;
+        let status = response.status();
+        let etag = response
+            .headers()
+            .get("etag")
+            .and_then(|etag| etag.to_str().ok())
+            .map(|etag| etag.to_string());
+        Ok(S3Response {
+            status,
+            etag,
+            body: response.bytes().await?.to_vec(),
+        })
+    }
+
+    fn error(response: &S3Response) -> Error {
+        let message = _xml_values(&response.body, "Message");
+        format!(
+            "Storage Macro S3: request failed with status {}: {}",
+            response.status,
+            message.first().map(|x| x.as_str()).unwrap_or_default()
+        )
+        .into()
+    }
+
+    async fn exists(&self, object_key: &str) -> Result<bool, Error> {
+        let response = self
+            .send(Method::HEAD, object_key, &[], &[], Vec::new())
+            .await?;
+        match response.status {
+            StatusCode::NOT_FOUND => Ok(false),
+            status if status.is_success() => Ok(true),
+            _ => Err(Self::error(&response)),
+        }
+    }
+
+    async fn put_object(
+        &self,
+        object_key: &str,
+        payload: Vec<u8>,
+        create: bool,
+    ) -> Result<(), Error> {
+        let headers = if create {
+            vec![("if-none-match", "*".to_string())]
+        } else {
+            vec![]
+        };
+        let response = self
+            .send(Method::PUT, object_key, &[], &headers, payload)
+            .await?;
+        match response.status {
+            StatusCode::PRECONDITION_FAILED => Err("key already exists.")?,
+            status if status.is_success() => Ok(()),
+            _ => Err(Self::error(&response)),
+        }
+    }
+
+    /// Upload `first_part` and the rest of the reader in parts of PART_SIZE. The upload is aborted on errors.
+    async fn multipart_upload(
+        &self,
+        object_key: &str,
+        first_part: Vec<u8>,
+        reader: &mut (dyn AsyncRead + Send + Unpin),
+        create: bool,
+    ) -> Result<(), Error> {
+        let response = self
+            .send(
+                Method::POST,
+                object_key,
+                &[("uploads", "")],
+                &[],
+                Vec::new(),
+            )
+            .await?;
+        if !response.status.is_success() {
+            return Err(Self::error(&response));
+        }
+        let upload_id = match _xml_values(&response.body, "UploadId").pop() {
+            Some(upload_id) => upload_id,
+            None => Err("Storage Macro S3: UploadId is not found in the response.")?,
+        };
+        // use a closure to abort the upload on errors
+        let res = async {
+            let mut parts = String::new();
+            let mut part_number = 0;
+            let mut part = first_part;
+            while !part.is_empty() {
+                part_number += 1;
+                let response = self
+                    .send(
+                        Method::PUT,
+                        object_key,
+                        &[
+                            ("partNumber", &part_number.to_string()),
+                            ("uploadId", &upload_id),
+                        ],
+                        &[],
+                        part,
+                    )
+                    .await?;
+                if !response.status.is_success() {
+                    return Err(Self::error(&response));
+                }
+                let etag = match response.etag {
+                    Some(etag) => etag,
+                    None => Err("Storage Macro S3: ETag is not found in the response.")?,
+                };
+                parts.push_str(&format!(
+                    "<Part><PartNumber>{}</PartNumber><ETag>{}</ETag></Part>",
+                    part_number, etag
+                ));
+                part = Vec::with_capacity(PART_SIZE);
+                (&mut *reader)
+                    .take(PART_SIZE as u64)
+                    .read_to_end(&mut part)
+                    .await?;
+            }
+            let headers = if create {
+                vec![("if-none-match", "*".to_string())]
+            } else {
+                vec![]
+            };
+            let response = self
+                .send(
+                    Method::POST,
+                    object_key,
+                    &[("uploadId", &upload_id)],
+                    &headers,
+                    format!(
+                        "<CompleteMultipartUpload>{}</CompleteMultipartUpload>",
+                        parts
+                    )
+                    .into_bytes(),
+                )
+                .await?;
+            // CompleteMultipartUpload may report errors in the body of a 200 response
+            if response.status == StatusCode::PRECONDITION_FAILED {
+                Err("key already exists.")?
+            }
+            if !response.status.is_success() || !_xml_values(&response.body, "Code").is_empty() {
+                return Err(Self::error(&response));
+            }
+            Ok::<(), Error>(())
+        }
+        .await;
+        if res.is_err() {
+            let _ = self
+                .send(
+                    Method::DELETE,
+                    object_key,
+                    &[("uploadId", &upload_id)],
+                    &[],
+                    Vec::new(),
+                )
+                .await;
+        }
+        res
+    }
+}
+
+impl crate::application::CoLink {
+    async fn _sm_s3_get_config(&self, key_name: &str) -> Result<S3Config, Error> {
+        let mut settings = Vec::new();
+        for setting in ["s3_endpoint", "s3_bucket", "s3_access_key", "s3_secret_key"] {
+            match self
+                .read_entry(&KeyPath::new(key_name).segment(setting).to_string())
+                .await
+            {
+                Ok(value) => settings.push(String::from_utf8(value)?),
+                Err(_) => Err(format!(
+                    "Storage Macro S3: {}:{} is not set.",
+                    key_name, setting
+                ))?,
+            }
+        }
+        let region = match self
+            .read_entry(&KeyPath::new(key_name).segment("s3_region").to_string())
+            .await
+        {
+            Ok(region) => String::from_utf8(region)?,
+            Err(_) => "us-east-1".to_string(),
+        };
+        Ok(S3Config {
+            endpoint: url::Url::parse(&settings[0])?,
+            bucket: settings[1].clone(),
+            region,
+            access_key: settings[2].clone(),
+            secret_key: settings[3].clone(),
+        })
+    }
+
+    /// Payloads up to PART_SIZE are written with a single PUT, and larger ones with a multipart upload.
+    pub(crate) async fn _write_entry_s3(
+        &self,
+        key_name: &str,
+        key_suffix: &str,
+        reader: &mut (dyn AsyncRead + Send + Unpin),
+        create: bool,
+    ) -> Result<String, Error> {
+        let config = self._sm_s3_get_config(key_name).await?;
+        let object_key = _object_key(key_suffix)?;
+        let mut first_part = Vec::with_capacity(PART_SIZE);
+        (&mut *reader)
+            .take(PART_SIZE as u64)
+            .read_to_end(&mut first_part)
+            .await?;
+        let mut second_part = Vec::new();
+        (&mut *reader)
+            .take(PART_SIZE as u64)
+            .read_to_end(&mut second_part)
+            .await?;
+        if second_part.is_empty() {
+            config.put_object(&object_key, first_part, create).await?;
+        } else {
+            if create && config.exists(&object_key).await? {
+                Err("key already exists.")?
+            }
+            let mut reader = (&second_part[..]).chain(reader);
+            config
+                .multipart_upload(&object_key, first_part, &mut reader, create)
+                .await?;
+        }
+        Ok("ok".to_string())
+    }
+
+    #[async_recursion]
+    pub(crate) async fn _read_entry_s3(
+        &self,
+        key_name: &str,
+        key_suffix: &str,
+    ) -> Result<Vec<u8>, Error> {
+        let config = self._sm_s3_get_config(key_name).await?;
+        let response = config
+            .send(Method::GET, &_object_key(key_suffix)?, &[], &[], Vec::new())
+            .await?;
+        match response.status {
+            StatusCode::NOT_FOUND => Err(tonic::Status::not_found("key does not exist."))?,
+            status if status.is_success() => Ok(response.body),
+            _ => Err(S3Config::error(&response)),
+        }
+    }
+
+    #[async_recursion]
+    pub(crate) async fn _read_entry_range_s3(
+        &self,
+        key_name: &str,
+        key_suffix: &str,
+        offset: u64,
+        length: u64,
+    ) -> Result<Vec<u8>, Error> {
+        let config = self._sm_s3_get_config(key_name).await?;
+        let object_key = _object_key(key_suffix)?;
+        if length == 0 {
+            if !config.exists(&object_key).await? {
+                Err(tonic::Status::not_found("key does not exist."))?
+            }
+            return Ok(Vec::new());
+        }
+        let range = format!("bytes={}-{}", offset, offset.saturating_add(length - 1));
+        let response = config
+            .send(
+                Method::GET,
+                &object_key,
+                &[],
+                &[("range", range)],
+                Vec::new(),
+            )
+            .await?;
+        match response.status {
+            StatusCode::NOT_FOUND => Err(tonic::Status::not_found("key does not exist."))?,
+            StatusCode::RANGE_NOT_SATISFIABLE => Ok(Vec::new()),
+            StatusCode::PARTIAL_CONTENT => Ok(response.body),
+            // the whole object is returned if the range is ignored
+            status if status.is_success() => {
+                Ok(super::_slice_range(&response.body, offset, length).to_vec())
+            }
+            _ => Err(S3Config::error(&response)),
+        }
+    }
+
+    #[async_recursion]
+    pub(crate) async fn _delete_entry_s3(
+        &self,
+        key_name: &str,
+        key_suffix: &str,
+    ) -> Result<String, Error> {
+        let config = self._sm_s3_get_config(key_name).await?;
+        let object_key = _object_key(key_suffix)?;
+        // DeleteObject succeeds on missing objects, so check the existence first
+        if !config.exists(&object_key).await? {
+            Err(tonic::Status::not_found("key does not exist."))?
+        }
+        let response = config
+            .send(Method::DELETE, &object_key, &[], &[], Vec::new())
+            .await?;
+        if !response.status.is_success() {
+            return Err(S3Config::error(&response));
+        }
+        Ok("ok".to_string())
+    }
+
+    /// List the objects and the `/`-delimited common prefixes under the key suffix with ListObjectsV2.
+    #[async_recursion]
+    pub(crate) async fn _read_keys_s3(
+        &self,
+        key_name: &str,
+        key_suffix: &str,
+    ) -> Result<Vec<String>, Error> {
+        let config = self._sm_s3_get_config(key_name).await?;
+        let prefix = if key_suffix.is_empty() {
+            String::new()
+        } else {
+            format!("{}/", _object_key(key_suffix)?)
+        };
+        let mut key_list = Vec::new();
+        let mut continuation_token: Option<String> = None;
+        loop {
+            let mut query = vec![
+                ("delimiter", "/"),
+                ("list-type", "2"),
+                ("prefix", prefix.as_str()),
+            ];
+            if let Some(token) = &continuation_token {
+                query.push(("continuation-token", token.as_str()));
+            }
+            let response = config
+                .send(Method::GET, "", &query, &[], Vec::new())
+                .await?;
+            if !response.status.is_success() {
+                return Err(S3Config::error(&response));
+            }
+            // the requested prefix itself is also listed in a Prefix tag, which is skipped as an empty name
+            for key in _xml_values(&response.body, "Key")
+                .into_iter()
+                .chain(_xml_values(&response.body, "Prefix"))
+            {
+                let name = key
+                    .strip_prefix(&prefix)
+                    .unwrap_or(&key)
+                    .trim_end_matches('/');
+                if !name.is_empty() {
+                    key_list.push(name.replace('/', ":"));
+                }
+            }
+            continuation_token = _xml_values(&response.body, "NextContinuationToken").pop();
+            if continuation_token.is_none()
+                || _xml_values(&response.body, "IsTruncated").pop().as_deref() != Some("true")
+            {
+                break;
+            }
+        }
+        Ok(key_list)
+    }
+}
@@ -0,0 +1,281 @@
+use super::StorageMacro;
+use crate::{application::CoLink, KeyPath, StorageEntry};
+use async_recursion::async_recursion;
+use async_trait::async_trait;
+use rusqlite::{params, Connection, OptionalExtension};
+use std::{
+    collections::HashMap,
+    sync::{Arc, Mutex},
+};
+
+type Error = Box<dyn std::error::Error + Send + Sync + 'static>;
+
+pub(crate) struct SqliteStorageMacro;
+
+#[async_trait]
+impl StorageMacro for SqliteStorageMacro {
+    async fn create(
+        &self,
+        cl: &CoLink,
+        string_before: &str,
+        string_after: &str,
+        payload: &[u8],
+    ) -> Result<String, Error> {
+        cl._create_entry_sqlite(string_before, string_after, payload)
+            .await
+    }
+
+    async fn read(
+        &self,
+        cl: &CoLink,
+        string_before: &str,
+        string_after: &str,
+    ) -> Result<Vec<u8>, Error> {
+        cl._read_entry_sqlite(string_before, string_after).await
+    }
+
+    async fn update(
+        &self,
+        cl: &CoLink,
+        string_before: &str,
+        string_after: &str,
+        payload: &[u8],
+    ) -> Result<String, Error> {
+        cl._update_entry_sqlite(string_before, string_after, payload)
+            .await
+    }
+
+    async fn delete(
+        &self,
+        cl: &CoLink,
+        string_before: &str,
+        string_after: &str,
+    ) -> Result<String, Error> {
+        cl._delete_entry_sqlite(string_before, string_after).await
+    }
+
+    async fn append(
+        &self,
+        cl: &CoLink,
+        string_before: &str,
+        string_after: &str,
+        payload: &[u8],
+    ) -> Result<String, Error> {
+        cl._append_entry_sqlite(string_before, string_after, payload)
+            .await
+    }
+
+    async fn read_keys(
+        &self,
+        cl: &CoLink,
+        prefix: &str,
+        string_before: &str,
+        string_after: &str,
+        include_history: bool,
+    ) -> Result<Vec<StorageEntry>, Error> {
+        if include_history {
+            return Err("Storage Macro: include_history is not supported.".into());
+        }
+        let key_list = cl._read_keys_sqlite(string_before, string_after).await?;
+        Ok(key_list
+            .into_iter()
+            .map(|key| StorageEntry {
+                key_path: format!("{}:{}@0", prefix, key),
+                ..Default::default()
+            })
+            .collect())
+    }
+}
+
+/// The open connections by database path, shared by the clones of a CoLink object.
+pub(crate) type SqliteConnectionPool = Arc<Mutex<HashMap<String, Arc<Mutex<Connection>>>>>;
+
+/// Return the connection to the database in `path`, and open it and create the table on first use.
+fn _sqlite_connection(
+    pool: &SqliteConnectionPool,
+    path: &str,
+) -> Result<Arc<Mutex<Connection>>, Error> {
+    let mut pool = pool.lock().unwrap();
+    if let Some(conn) = pool.get(path) {
+        return Ok(conn.clone());
+    }
+    let conn = Connection::open(path)?;
+    conn.busy_timeout(std::time::Duration::from_secs(10))?;
+    conn.execute(
+        "CREATE TABLE IF NOT EXISTS colink_storage (key TEXT PRIMARY KEY, value BLOB NOT NULL)",
+        [],
+    )?;
+    let conn = Arc::new(Mutex::new(conn));
+    pool.insert(path.to_string(), conn.clone());
+    Ok(conn)
+}
+
+impl crate::application::CoLink {
+    /// Run `f` on the database in `{path_key_name}:path` in a blocking thread.
+    async fn _sm_sqlite_execute<T, F>(&self, path_key_name: &str, f: F) -> Result<T, Error>
+    where
+        T: Send + 'static,
+        F: FnOnce(&mut Connection) -> Result<T, Error> + Send + 'static,
+    {
+        let path_key = KeyPath::new(path_key_name).segment("path").to_string();
+        let path = String::from_utf8(self.read_entry(&path_key).await?)?;
+        let pool = self.sqlite_connections.clone();
+        tokio::task::spawn_blocking(move || {
+            let conn = _sqlite_connection(&pool, &path)?;
+            let mut conn = conn.lock().unwrap();
+            f(&mut conn)
+        })
+        .await?
+    }
+
+    #[async_recursion]
+    pub(crate) async fn _create_entry_sqlite(
+        &self,
+        path_key_name: &str,
+        key_name: &str,
+        payload: &[u8],
+    ) -> Result<String, Error> {
+        let key_name = key_name.to_string();
+        let payload = payload.to_vec();
+        self._sm_sqlite_execute(path_key_name, move |conn| {
+            let res = conn.execute(
+                "INSERT OR IGNORE INTO colink_storage (key, value) VALUES (?1, ?2)",
+                params![key_name, payload],
+            )?;
+            if res == 0 {
+                Err(format!("key {} already exists.", key_name))?
+            }
+            Ok("ok".to_string())
+        })
+        .await
+    }
+
+    #[async_recursion]
+    pub(crate) async fn _read_entry_sqlite(
+        &self,
+        path_key_name: &str,
+        key_name: &str,
+    ) -> Result<Vec<u8>, Error> {
+        let key_name = key_name.to_string();
+        self._sm_sqlite_execute(path_key_name, move |conn| {
+            let res: Option<Vec<u8>> = conn
+                .query_row(
+                    "SELECT value FROM colink_storage WHERE key = ?1",
+                    params![key_name],
+                    |row| row.get(0),
+                )
+                .optional()?;
+            match res {
+                Some(payload) => Ok(payload),
+                None => Err(tonic::Status::not_found(format!(
+                    "key {} does not exist.",
+                    key_name
+                )))?,
+            }
+        })
+        .await
+    }
+
+    #[async_recursion]
+    pub(crate) async fn _update_entry_sqlite(
+        &self,
+        path_key_name: &str,
+        key_name: &str,
+        payload: &[u8],
+    ) -> Result<String, Error> {
+        let key_name = key_name.to_string();
+        let payload = payload.to_vec();
+        self._sm_sqlite_execute(path_key_name, move |conn| {
+            conn.execute(
+                "INSERT OR REPLACE INTO colink_storage (key, value) VALUES (?1, ?2)",
+                params![key_name, payload],
+            )?;
+            Ok("ok".to_string())
+        })
+        .await
+    }
+
+    #[async_recursion]
+    pub(crate) async fn _append_entry_sqlite(
+        &self,
+        path_key_name: &str,
+        key_name: &str,
+        payload: &[u8],
+    ) -> Result<String, Error> {
+        let key_name = key_name.to_string();
+        let mut payload = payload.to_vec();
+        self._sm_sqlite_execute(path_key_name, move |conn| {
+            let tx = conn.transaction_with_behavior(rusqlite::TransactionBehavior::Immediate)?;
+            let res: Option<Vec<u8>> = tx
+                .query_row(
+                    "SELECT value FROM colink_storage WHERE key = ?1",
+                    params![key_name],
+                    |row| row.get(0),
+                )
+                .optional()?;
+            let mut data = match res {
+                Some(data) => data,
+                None => Err(tonic::Status::not_found(format!(
+                    "key {} does not exist.",
+                    key_name
+                )))?,
+            };
+            data.append(&mut payload);
+            tx.execute(
+                "UPDATE colink_storage SET value = ?2 WHERE key = ?1",
+                params![key_name, data],
+            )?;
+            tx.commit()?;
+            Ok("ok".to_string())
+        })
+        .await
+    }
+
+    #[async_recursion]
+    pub(crate) async fn _delete_entry_sqlite(
+        &self,
+        path_key_name: &str,
+        key_name: &str,
+    ) -> Result<String, Error> {
+        let key_name = key_name.to_string();
+        self._sm_sqlite_execute(path_key_name, move |conn| {
+            let res = conn.execute(
+                "DELETE FROM colink_storage WHERE key = ?1",
+                params![key_name],
+            )?;
+            if res == 0 {
+                Err(tonic::Status::not_found(format!(
+                    "key {} does not exist.",
+                    key_name
+                )))?
+            }
+            Ok("ok".to_string())
+        })
+        .await
+    }
+
+    #[async_recursion]
+    pub(crate) async fn _read_keys_sqlite(
+        &self,
+        path_key_name: &str,
+        prefix: &str,
+    ) -> Result<Vec<String>, Error> {
+        let prefix = if prefix.is_empty() {
+            String::new()
+        } else {
+            format!("{}:", prefix)
+        };
+        self._sm_sqlite_execute(path_key_name, move |conn| {
+            let mut stmt = conn.prepare(
+                "SELECT key FROM colink_storage WHERE substr(key, 1, length(?1)) = ?1 AND length(key) > length(?1)",
+            )?;
+            let keys = stmt.query_map(params![prefix], |row| row.get::<_, String>(0))?;
+            let mut key_list: Vec<String> = Vec::new();
+            for key in keys {
+                key_list.push(key?[prefix.len()..].to_string());
+            }
+            Ok(key_list)
+        })
+        .await
+    }
+}
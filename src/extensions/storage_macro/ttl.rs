@@ -0,0 +1,381 @@
+use super::StorageMacro;
+use crate::{application::CoLink, utils::is_not_found_error, KeyPath};
+use async_recursion::async_recursion;
+use async_trait::async_trait;
+use std::time::Duration;
+use tracing::error;
+
+type Error = Box<dyn std::error::Error + Send + Sync + 'static>;
+
+pub(crate) struct TtlStorageMacro;
+
+#[async_trait]
+impl StorageMacro for TtlStorageMacro {
+    async fn create(
+        &self,
+        cl: &CoLink,
+        string_before: &str,
+        string_after: &str,
+        payload: &[u8],
+    ) -> Result<String, Error> {
+        let ttl = cl._sm_ttl_get_default(string_before).await?;
+        cl._write_entry_ttl(string_before, string_after, payload, ttl, true)
+            .await
+    }
+
+    async fn read(
+        &self,
+        cl: &CoLink,
+        string_before: &str,
+        string_after: &str,
+    ) -> Result<Vec<u8>, Error> {
+        cl._read_entry_ttl(string_before, string_after).await
+    }
+
+    async fn update(
+        &self,
+        cl: &CoLink,
+        string_before: &str,
+        string_after: &str,
+        payload: &[u8],
+    ) -> Result<String, Error> {
+        let ttl = cl._sm_ttl_get_default(string_before).await?;
+        cl._write_entry_ttl(string_before, string_after, payload, ttl, false)
+            .await
+    }
+
+    async fn update_with_ttl(
+        &self,
+        cl: &CoLink,
+        string_before: &str,
+        string_after: &str,
+        payload: &[u8],
+        ttl: Duration,
+    ) -> Result<String, Error> {
+        cl._write_entry_ttl(string_before, string_after, payload, ttl, false)
+            .await
+    }
+
+    async fn delete(
+        &self,
+        cl: &CoLink,
+        string_before: &str,
+        string_after: &str,
+    ) -> Result<String, Error> {
+        cl._delete_entry_ttl(string_before, string_after).await
+    }
+
+    async fn append(
+        &self,
+        cl: &CoLink,
+        string_before: &str,
+        string_after: &str,
+        payload: &[u8],
+    ) -> Result<String, Error> {
+        cl._append_entry_ttl(string_before, string_after, payload)
+            .await
+    }
+}
+
+/// The stored record is the expiry time in milliseconds (i64, big-endian) followed by the payload.
+fn _encode_ttl_record(expiry: i64, payload: &[u8]) -> Vec<u8> {
+    let mut record = Vec::with_capacity(8 + payload.len());
+    record.extend_from_slice(&expiry.to_be_bytes());
+    record.extend_from_slice(payload);
+    record
+}
+
+fn _decode_ttl_record(record: &[u8]) -> Result<(i64, &[u8]), Error> {
+    if record.len() < 8 {
+        Err("Storage Macro TTL: the stored data is not written by $ttl.")?
+    }
+    Ok((
+        i64::from_be_bytes(record[..8].try_into().unwrap()),
+        &record[8..],
+    ))
+}
+
+/// The index entry of an expiring key under `_internal:ttl_expiry`. The key is escaped into a single segment,
+/// so that a $ttl key like `a:$ttl:b` and a key without storage macros like `a:_ttl:b` have different entries.
+fn _ttl_index_key(key_name: &str) -> String {
+    KeyPath::new("_internal:ttl_expiry")
+        .escaped(key_name)
+        .to_string()
+}
+
+fn _ttl_key_name(key_name: &str, key_suffix: &str) -> String {
+    KeyPath::new(key_name)
+        .storage_macro("ttl")
+        .segment(key_suffix)
+        .to_string()
+}
+
+impl crate::application::CoLink {
+    /// Update an entry that expires after `ttl`. Expired entries are removed when they are read or by
+    /// sweep_expired_entries. For $redis, the expiry is set natively.
+    ///
+    /// Keys without storage macros are stored as `{key_name}:$ttl`, so `read_entry(key_name)` does not find them:
+    /// e.g. after `update_entry_with_ttl("foo", ..)`, read, append or delete the entry through `"foo:$ttl"`.
+    pub async fn update_entry_with_ttl(
+        &self,
+        key_name: &str,
+        payload: &[u8],
+        ttl: Duration,
+    ) -> Result<String, Error> {
+        let key_name = if key_name.contains('$') {
+            key_name.to_string()
+        } else {
+            KeyPath::new(key_name).storage_macro("ttl").to_string()
+        };
+        self._sm_update_entry_with_ttl(&key_name, payload, ttl)
+            .await
+    }
+
+    /// Remove all expired $ttl entries. Return the number of removed entries.
+    pub async fn sweep_expired_entries(&self) -> Result<usize, Error> {
+        let entries = self
+            .read_keys(
+                &KeyPath::new("_internal:ttl_expiry:")
+                    .user_id(&self.get_user_id()?)
+                    .to_string(),
+                false,
+            )
+            .await?;
+        let now = chrono::Utc::now().timestamp_millis();
+        let mut removed = 0;
+        for entry in entries {
+            let index = match self.read_entry(&entry.key_path).await {
+                Ok(index) => String::from_utf8(index)?,
+                // the entry has been removed since the keys were listed
+                Err(e) if is_not_found_error(&*e) => continue,
+                Err(e) => return Err(e),
+            };
+            let (expiry, key_name) = match index.split_once(';') {
+                Some((expiry, key_name)) => (expiry.parse::<i64>()?, key_name.to_string()),
+                None => continue,
+            };
+            if expiry > now {
+                continue;
+            }
+            if !key_name.contains('$') {
+                if self._expire_entry(&key_name).await? {
+                    removed += 1;
+                }
+                continue;
+            }
+            let (string_before, _, string_after) = self._parse_macro(&key_name);
+            if self
+                ._expire_entry_ttl(&string_before, &string_after)
+                .await?
+            {
+                removed += 1;
+            }
+        }
+        Ok(removed)
+    }
+
+    /// Run sweep_expired_entries every `interval` in the background. Errors are logged and the sweep is retried
+    /// in the next interval.
+    pub fn spawn_ttl_sweeper(&self, interval: Duration) -> tokio::task::JoinHandle<()> {
+        let cl = self.clone();
+        tokio::spawn(async move {
+            loop {
+                tokio::time::sleep(interval).await;
+                if let Err(e) = cl.sweep_expired_entries().await {
+                    error!("TTL sweeper: {}.", e);
+                }
+            }
+        })
+    }
+
+    /// Let sweep_expired_entries delete the existing entry `key_name`, which has no storage macros, after `ttl`.
+    /// Unlike $ttl entries, the entry can still be read until it is swept, and it is deleted even if it has been
+    /// updated since.
+    pub(crate) async fn _expire_entry_after(
+        &self,
+        key_name: &str,
+        ttl: Duration,
+    ) -> Result<(), Error> {
+        let expiry = chrono::Utc::now()
+            .timestamp_millis()
+            .saturating_add(ttl.as_millis() as i64);
+        self.update_entry(
+            &_ttl_index_key(key_name),
+            format!("{};{}", expiry, key_name).as_bytes(),
+        )
+        .await?;
+        Ok(())
+    }
+
+    /// Delete the entry recorded by _expire_entry_after. Return whether it was deleted.
+    async fn _expire_entry(&self, key_name: &str) -> Result<bool, Error> {
+        let deleted = match self.delete_entry(key_name).await {
+            Ok(_) => true,
+            Err(e) if is_not_found_error(&*e) => false,
+            Err(e) => return Err(e),
+        };
+        self.delete_entry(&_ttl_index_key(key_name)).await?;
+        Ok(deleted)
+    }
+
+    /// The default TTL in seconds is read from `{key_name}:ttl`.
+    async fn _sm_ttl_get_default(&self, key_name: &str) -> Result<Duration, Error> {
+        match self
+            .read_entry(&KeyPath::new(key_name).segment("ttl").to_string())
+            .await
+        {
+            Ok(ttl) => Ok(Duration::from_secs(String::from_utf8(ttl)?.parse::<u64>()?)),
+            Err(e) if is_not_found_error(&*e) => Err(format!(
+                "Storage Macro TTL: {}:ttl is not set, use update_entry_with_ttl instead.",
+                key_name
+            ))?,
+            Err(e) => Err(e),
+        }
+    }
+
+    fn _sm_ttl_get_key(&self, key_name: &str, key_suffix: &str) -> String {
+        KeyPath::new(key_name)
+            .segment("_ttl")
+            .segment(key_suffix)
+            .to_string()
+    }
+
+    fn _sm_ttl_get_index_key(&self, key_name: &str, key_suffix: &str) -> String {
+        _ttl_index_key(&_ttl_key_name(key_name, key_suffix))
+    }
+
+    #[async_recursion]
+    pub(crate) async fn _write_entry_ttl(
+        &self,
+        key_name: &str,
+        key_suffix: &str,
+        payload: &[u8],
+        ttl: Duration,
+        create: bool,
+    ) -> Result<String, Error> {
+        let stored_key = self._sm_ttl_get_key(key_name, key_suffix);
+        let expiry = chrono::Utc::now()
+            .timestamp_millis()
+            .saturating_add(ttl.as_millis() as i64);
+        let lock = self.lock(&stored_key).await?;
+        // use a closure to prevent locking forever caused by errors
+        let res = async {
+            if create {
+                if let Ok(record) = self.read_entry(&stored_key).await {
+                    let (old_expiry, _) = _decode_ttl_record(&record)?;
+                    if old_expiry > chrono::Utc::now().timestamp_millis() {
+                        Err("key already exists.")?
+                    }
+                }
+            }
+            let res = self
+                .update_entry(&stored_key, &_encode_ttl_record(expiry, payload))
+                .await?;
+            self.update_entry(
+                &self._sm_ttl_get_index_key(key_name, key_suffix),
+                format!("{};{}", expiry, _ttl_key_name(key_name, key_suffix)).as_bytes(),
+            )
+            .await?;
+            Ok::<String, Error>(res)
+        }
+        .await;
+        self.unlock(lock).await?;
+        res
+    }
+
+    #[async_recursion]
+    pub(crate) async fn _read_entry_ttl(
+        &self,
+        key_name: &str,
+        key_suffix: &str,
+    ) -> Result<Vec<u8>, Error> {
+        let record = self
+            .read_entry(&self._sm_ttl_get_key(key_name, key_suffix))
+            .await?;
+        let (expiry, payload) = _decode_ttl_record(&record)?;
+        if expiry <= chrono::Utc::now().timestamp_millis() {
+            self._expire_entry_ttl(key_name, key_suffix).await?;
+            Err(tonic::Status::not_found("key does not exist."))?
+        }
+        Ok(payload.to_vec())
+    }
+
+    #[async_recursion]
+    pub(crate) async fn _append_entry_ttl(
+        &self,
+        key_name: &str,
+        key_suffix: &str,
+        payload: &[u8],
+    ) -> Result<String, Error> {
+        let stored_key = self._sm_ttl_get_key(key_name, key_suffix);
+        let lock = self.lock(&stored_key).await?;
+        // use a closure to prevent locking forever caused by errors
+        let res = async {
+            let record = self.read_entry(&stored_key).await?;
+            let (expiry, data) = _decode_ttl_record(&record)?;
+            if expiry <= chrono::Utc::now().timestamp_millis() {
+                Err(tonic::Status::not_found("key does not exist."))?
+            }
+            let mut data = data.to_vec();
+            data.extend_from_slice(payload);
+            self.update_entry(&stored_key, &_encode_ttl_record(expiry, &data))
+                .await
+        }
+        .await;
+        self.unlock(lock).await?;
+        res
+    }
+
+    #[async_recursion]
+    pub(crate) async fn _delete_entry_ttl(
+        &self,
+        key_name: &str,
+        key_suffix: &str,
+    ) -> Result<String, Error> {
+        let stored_key = self._sm_ttl_get_key(key_name, key_suffix);
+        let lock = self.lock(&stored_key).await?;
+        // use a closure to prevent locking forever caused by errors
+        let res = async {
+            let record = self.read_entry(&stored_key).await?;
+            let (expiry, _) = _decode_ttl_record(&record)?;
+            let res = self.delete_entry(&stored_key).await?;
+            let _ = self
+                .delete_entry(&self._sm_ttl_get_index_key(key_name, key_suffix))
+                .await;
+            if expiry <= chrono::Utc::now().timestamp_millis() {
+                Err(tonic::Status::not_found("key does not exist."))?
+            }
+            Ok::<String, Error>(res)
+        }
+        .await;
+        self.unlock(lock).await?;
+        res
+    }
+
+    /// Delete the entry if it has expired. Return whether it was deleted.
+    async fn _expire_entry_ttl(&self, key_name: &str, key_suffix: &str) -> Result<bool, Error> {
+        let stored_key = self._sm_ttl_get_key(key_name, key_suffix);
+        let index_key = self._sm_ttl_get_index_key(key_name, key_suffix);
+        let lock = self.lock(&stored_key).await?;
+        // use a closure to prevent locking forever caused by errors
+        let res = async {
+            // the entry may have been updated since it was found expired
+            let expiry = match self.read_entry(&stored_key).await {
+                Ok(record) => _decode_ttl_record(&record)?.0,
+                Err(_) => {
+                    let _ = self.delete_entry(&index_key).await;
+                    return Ok(false);
+                }
+            };
+            if expiry > chrono::Utc::now().timestamp_millis() {
+                return Ok(false);
+            }
+            self.delete_entry(&stored_key).await?;
+            let _ = self.delete_entry(&index_key).await;
+            Ok::<bool, Error>(true)
+        }
+        .await;
+        self.unlock(lock).await?;
+        res
+    }
+}
@@ -1,12 +1,18 @@
-use crate::colink_proto::*;
+use crate::{colink_proto::*, KeyPath};
 use colink_remote_storage::*;
 use prost::Message;
+#[cfg(feature = "storage_macro")]
+use tracing::error;
 mod colink_remote_storage {
     include!(concat!(env!("OUT_DIR"), "/colink_remote_storage.rs"));
 }
 
 type Error = Box<dyn std::error::Error + Send + Sync + 'static>;
 
+/// Received variables are removed by the TTL sweeper (see spawn_ttl_sweeper) after this time.
+#[cfg(feature = "storage_macro")]
+const VARIABLE_TRANSFER_TTL: std::time::Duration = std::time::Duration::from_secs(24 * 60 * 60);
+
 impl crate::application::CoLink {
     #[deprecated(note = "please use `send_variable_with_remote_storage` instead")]
     pub async fn set_variable_with_remote_storage(
@@ -32,12 +38,12 @@ impl crate::application::CoLink {
         for p in receivers {
             if p.user_id == self.get_user_id()? {
                 self.create_entry(
-                    &format!(
-                        "_remote_storage:private:{}:_variable_transfer:{}:{}",
-                        p.user_id,
-                        self.get_task_id()?,
-                        key
-                    ),
+                    &KeyPath::new("_remote_storage:private")
+                        .escaped(&p.user_id)
+                        .segment("_variable_transfer")
+                        .escaped(&self.get_task_id()?)
+                        .escaped(key)
+                        .to_string(),
                     payload,
                 )
                 .await?;
@@ -49,7 +55,10 @@ impl crate::application::CoLink {
             }
         }
         let params = CreateParams {
-            remote_key_name: format!("_variable_transfer:{}:{}", self.get_task_id()?, key),
+            remote_key_name: KeyPath::new("_variable_transfer")
+                .escaped(&self.get_task_id()?)
+                .escaped(key)
+                .to_string(),
             payload: payload.to_vec(),
             ..Default::default()
         };
@@ -74,13 +83,21 @@ impl crate::application::CoLink {
         key: &str,
         sender: &Participant,
     ) -> Result<Vec<u8>, Error> {
-        let key = format!(
-            "_remote_storage:private:{}:_variable_transfer:{}:{}",
-            sender.user_id,
-            self.get_task_id()?,
-            key
-        );
+        let key = KeyPath::new("_remote_storage:private")
+            .escaped(&sender.user_id)
+            .segment("_variable_transfer")
+            .escaped(&self.get_task_id()?)
+            .escaped(key)
+            .to_string();
         let res = self.read_or_wait(&key).await?;
+        // the variable has been received, so a failure to record its expiry only keeps it stored
+        #[cfg(feature = "storage_macro")]
+        if let Err(e) = self._expire_entry_after(&key, VARIABLE_TRANSFER_TTL).await {
+            error!(
+                "Failed to set the expiry of the received variable {}: {}.",
+                key, e
+            );
+        }
         Ok(res)
     }
 }
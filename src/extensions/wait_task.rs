@@ -1,4 +1,4 @@
-use crate::{colink_proto::*, utils::get_path_timestamp};
+use crate::{colink_proto::*, utils::get_path_timestamp, KeyPath};
 use prost::Message;
 use tracing::debug;
 
@@ -6,7 +6,7 @@ type Error = Box<dyn std::error::Error + Send + Sync + 'static>;
 
 impl crate::application::CoLink {
     pub async fn wait_task(&self, task_id: &str) -> Result<(), Error> {
-        let task_key = format!("_internal:tasks:{}", task_id);
+        let task_key = KeyPath::new("_internal:tasks").escaped(task_id).to_string();
         let start_timestamp = match self
             .read_entries(&[StorageEntry {
                 key_name: task_key.clone(),
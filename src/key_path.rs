@@ -0,0 +1,199 @@
+use std::fmt;
+
+type Error = Box<dyn std::error::Error + Send + Sync + 'static>;
+
+/// Escape a value, e.g. a user id, so that it is a single key segment: `%`, `:`, `$` and `@` are percent-encoded.
+pub fn escape_key_segment(value: &str) -> String {
+    let mut escaped = String::with_capacity(value.len());
+    for c in value.chars() {
+        match c {
+            '%' => escaped.push_str("%25"),
+            ':' => escaped.push_str("%3A"),
+            '$' => escaped.push_str("%24"),
+            '@' => escaped.push_str("%40"),
+            c => escaped.push(c),
+        }
+    }
+    escaped
+}
+
+/// Reverse escape_key_segment.
+pub fn unescape_key_segment(segment: &str) -> String {
+    segment
+        .replace("%3A", ":")
+        .replace("%24", "$")
+        .replace("%40", "@")
+        .replace("%25", "%")
+}
+
+fn _validate_storage_macro_segment(segment: &str, key: &str) -> Result<(), Error> {
+    if !segment.contains('$') {
+        return Ok(());
+    }
+    match segment.strip_prefix('$') {
+        Some(name)
+            if !name.is_empty() && name.chars().all(|c| c.is_ascii_alphanumeric() || c == '_') =>
+        {
+            Ok(())
+        }
+        _ => Err(format!(
+            "Invalid storage macro segment {} in key: {}",
+            segment, key
+        ))?,
+    }
+}
+
+/// Check the segment that selects the storage macro of a key name, which is the last segment containing `$`.
+/// The other segments belong to the storage macro, e.g. the file names of $fs and the parameters of $dbc.
+pub(crate) fn validate_storage_macro_segment(key_name: &str) -> Result<(), Error> {
+    match key_name.rsplit(':').find(|x| x.contains('$')) {
+        Some(segment) => _validate_storage_macro_segment(segment, key_name),
+        None => Ok(()),
+    }
+}
+
+/// Split a key path `{user_id}::{key_name}@{timestamp}` into the user id, the key name and the timestamp,
+/// where the user id and the timestamp are optional.
+pub(crate) fn _split_key_path(key_path: &str) -> (Option<&str>, &str, Option<&str>) {
+    let (user_id, rest) = match key_path.split_once("::") {
+        Some((user_id, rest)) => (Some(user_id), rest),
+        None => (None, key_path),
+    };
+    match rest.rsplit_once('@') {
+        Some((key_name, timestamp)) => (user_id, key_name, Some(timestamp)),
+        None => (user_id, rest, None),
+    }
+}
+
+/// A storage key, `{user_id}::{key_name}@{timestamp}` with optional user id and timestamp, where the key name is
+/// a list of `:`-separated segments. Values like user ids, task ids and variable names are added with `escaped`
+/// so that they cannot change the structure of the key, and `$` segments name storage macros. Escaping leaves
+/// the ids generated by the core unchanged, so keys shared with the core, like `_internal:tasks:{task_id}`,
+/// keep their layout.
+///
+/// ```
+/// use colink::KeyPath;
+/// let key = KeyPath::new("app:users").escaped("user:1").segment("profile");
+/// assert_eq!(key.to_string(), "app:users:user%3A1:profile");
+/// ```
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct KeyPath {
+    user_id: Option<String>,
+    segments: Vec<String>,
+    timestamp: Option<i64>,
+}
+
+impl KeyPath {
+    /// Start a key name with literal `:`-separated segments.
+    pub fn new(key_name: &str) -> Self {
+        Self::default().segment(key_name)
+    }
+
+    /// Append literal `:`-separated segments, or a value without escaping it.
+    pub fn segment(mut self, segments: impl fmt::Display) -> Self {
+        let segments = segments.to_string();
+        if !segments.is_empty() {
+            self.segments
+                .extend(segments.split(':').map(|x| x.to_string()));
+        }
+        self
+    }
+
+    /// Append a value as a single escaped segment.
+    pub fn escaped(mut self, value: &str) -> Self {
+        self.segments.push(escape_key_segment(value));
+        self
+    }
+
+    /// Append a `$` segment for the storage macro `name`.
+    pub fn storage_macro(mut self, name: &str) -> Self {
+        self.segments.push(format!("${}", name));
+        self
+    }
+
+    pub fn user_id(mut self, user_id: &str) -> Self {
+        self.user_id = Some(user_id.to_string());
+        self
+    }
+
+    pub fn timestamp(mut self, timestamp: i64) -> Self {
+        self.timestamp = Some(timestamp);
+        self
+    }
+
+    /// Parse a key name or a key path `{user_id}::{key_name}@{timestamp}`, and validate it.
+    pub fn parse(key_path: &str) -> Result<Self, Error> {
+        let (user_id, key_name, timestamp) = _split_key_path(key_path);
+        let timestamp = match timestamp.map(|x| x.parse::<i64>()) {
+            Some(Ok(timestamp)) => Some(timestamp),
+            Some(Err(_)) => Err(format!("Invalid timestamp in key path: {}", key_path))?,
+            None => None,
+        };
+        let key = Self {
+            user_id: user_id.map(|x| x.to_string()),
+            segments: key_name.split(':').map(|x| x.to_string()).collect(),
+            timestamp,
+        };
+        key.validate()?;
+        Ok(key)
+    }
+
+    /// Check that the key name is not empty, has no empty segments, and that each `$` segment is `$` followed
+    /// by a storage macro name of letters, digits and `_`.
+    pub fn validate(&self) -> Result<(), Error> {
+        if let Some(user_id) = &self.user_id {
+            if user_id.is_empty() || user_id.contains(':') {
+                Err(format!("Invalid user id in key: {}", self))?
+            }
+        }
+        if self.segments.is_empty() {
+            Err("Key name is empty.")?
+        }
+        for segment in &self.segments {
+            if segment.is_empty() {
+                Err(format!("Empty segment in key: {}", self))?
+            }
+            if segment.contains('@') {
+                Err(format!("Invalid segment {} in key: {}", segment, self))?
+            }
+            _validate_storage_macro_segment(segment, &self.to_string())?;
+        }
+        Ok(())
+    }
+
+    pub fn get_user_id(&self) -> Option<&str> {
+        self.user_id.as_deref()
+    }
+
+    pub fn get_timestamp(&self) -> Option<i64> {
+        self.timestamp
+    }
+
+    /// The segments of the key name, still escaped.
+    pub fn get_segments(&self) -> &[String] {
+        &self.segments
+    }
+
+    /// The key name, without the user id and the timestamp.
+    pub fn key_name(&self) -> String {
+        self.segments.join(":")
+    }
+
+    /// Whether the key name contains a storage macro segment.
+    pub fn has_storage_macro(&self) -> bool {
+        self.segments.iter().any(|x| x.starts_with('$'))
+    }
+}
+
+impl fmt::Display for KeyPath {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        if let Some(user_id) = &self.user_id {
+            write!(f, "{}::", user_id)?;
+        }
+        write!(f, "{}", self.segments.join(":"))?;
+        if let Some(timestamp) = self.timestamp {
+            write!(f, "@{}", timestamp)?;
+        }
+        Ok(())
+    }
+}
@@ -1,6 +1,7 @@
 #![allow(clippy::derive_partial_eq_without_eq)]
 #![allow(clippy::uninlined_format_args)]
 mod application;
+mod key_path;
 mod protocol;
 mod colink_proto {
     tonic::include_proto!("colink");
@@ -9,6 +10,7 @@ pub use application::{
     decode_jwt_without_validation, generate_user, prepare_import_user_signature, CoLink,
 };
 pub use colink_proto::*;
+pub use key_path::{escape_key_segment, unescape_key_segment, KeyPath};
 pub use protocol::{
     CoLinkProtocol, CoLinkProtocolCommandLineArgs, ProtocolEntry, _colink_parse_args,
     _protocol_start, async_trait,
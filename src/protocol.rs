@@ -1,4 +1,4 @@
-use crate::{application::*, utils::get_path_timestamp};
+use crate::{application::*, utils::get_path_timestamp, KeyPath};
 pub use async_trait::async_trait;
 use clap::Parser;
 use prost::Message;
@@ -52,7 +52,9 @@ impl CoLinkProtocol {
                 let res = self
                     .cl
                     .read_entries(&[StorageEntry {
-                        key_name: format!("_internal:tasks:{}", task_id.task_id),
+                        key_name: KeyPath::new("_internal:tasks")
+                            .escaped(&task_id.task_id)
+                            .to_string(),
                         ..Default::default()
                     }])
                     .await;
@@ -88,7 +90,9 @@ impl CoLinkProtocol {
                 None => "anonymous",
             };
             cl.update_entry(
-                &format!("_internal:task_po_mapping:{}", task.task_id),
+                &KeyPath::new("_internal:task_po_mapping")
+                    .escaped(&task.task_id)
+                    .to_string(),
                 instance_id.as_bytes(),
             )
             .await?;
@@ -115,7 +119,10 @@ impl CoLinkProtocol {
     }
 
     async fn get_subscriber(&self) -> Result<CoLinkSubscriber, Error> {
-        let operator_mq_key = format!("_internal:protocols:{}:operator_mq", self.protocol_and_role);
+        let operator_mq_key = KeyPath::new("_internal:protocols")
+            .segment(&self.protocol_and_role)
+            .segment("operator_mq")
+            .to_string();
         let lock = self.cl.lock(&operator_mq_key).await?;
         let res = self
             .cl
@@ -130,11 +137,11 @@ impl CoLinkProtocol {
                 String::from_utf8(operator_mq_entry.payload.clone()).unwrap()
             }
             Err(_) => {
-                let list_key = format!("_internal:protocols:{}:started", self.protocol_and_role);
-                let latest_key = format!(
-                    "_internal:protocols:{}:started:latest",
-                    self.protocol_and_role
-                );
+                let list_key = KeyPath::new("_internal:protocols")
+                    .segment(&self.protocol_and_role)
+                    .segment("started");
+                let latest_key = list_key.clone().segment("latest").to_string();
+                let list_key = list_key.to_string();
                 let res = self
                     .cl
                     .read_entries(&[StorageEntry {
@@ -194,8 +201,10 @@ pub fn _protocol_start(
                 .build()
                 .unwrap()
                 .block_on(async move {
-                    let is_initialized_key =
-                        format!("_internal:protocols:{}:_is_initialized", protocol_name);
+                    let is_initialized_key = KeyPath::new("_internal:protocols")
+                        .segment(&protocol_name)
+                        .segment("_is_initialized")
+                        .to_string();
                     let lock = cl.lock(&is_initialized_key).await?;
                     let res = cl.read_entry(&is_initialized_key).await;
                     if res.is_err() || res.unwrap()[0] == 0 {
@@ -232,8 +241,10 @@ pub fn _protocol_start(
         .unwrap()
         .block_on(async move {
             for protocol_name in protocols {
-                let is_initialized_key =
-                    format!("_internal:protocols:{}:_is_initialized", protocol_name);
+                let is_initialized_key = KeyPath::new("_internal:protocols")
+                    .segment(&protocol_name)
+                    .segment("_is_initialized")
+                    .to_string();
                 cl_clone.update_entry(&is_initialized_key, &[1]).await?;
             }
             Ok::<(), Box<dyn std::error::Error + Send + Sync + 'static>>(())
@@ -271,10 +282,10 @@ pub fn _protocol_start(
                             let timestamp = chrono::Utc::now().timestamp_nanos();
                             let _ = cl
                                 .update_entry(
-                                    &format!(
-                                        "_internal:protocol_operator_instances:{}:heartbeat",
-                                        instance_id
-                                    ),
+                                    &KeyPath::new("_internal:protocol_operator_instances")
+                                        .escaped(&instance_id)
+                                        .segment("heartbeat")
+                                        .to_string(),
                                     &timestamp.to_le_bytes(),
                                 )
                                 .await;
@@ -13,3 +13,15 @@ pub fn get_colink_home() -> Result<String, String> {
     };
     Ok(colink_home)
 }
+
+/// Whether an error returned by the storage functions means that the entry does not exist: the core and the
+/// storage macros report missing keys with the NotFound status, and $fs with the NotFound io error.
+pub(crate) fn is_not_found_error(e: &(dyn std::error::Error + Send + Sync + 'static)) -> bool {
+    if let Some(status) = e.downcast_ref::<tonic::Status>() {
+        return status.code() == tonic::Code::NotFound;
+    }
+    if let Some(e) = e.downcast_ref::<std::io::Error>() {
+        return e.kind() == std::io::ErrorKind::NotFound;
+    }
+    false
+}
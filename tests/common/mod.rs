@@ -3,6 +3,7 @@ use colink::{
     extensions::instant_server::{InstantRegistry, InstantServer},
     CoLink,
 };
+use rand::Rng;
 
 pub async fn set_up_test_env(
     num: usize,
@@ -31,3 +32,55 @@ pub async fn set_up_test_env_single_user() -> Result<
     let cl = is.get_colink().switch_to_generated_user().await?;
     Ok((ir, is, cl))
 }
+
+pub fn random_payload(size: usize) -> Vec<u8> {
+    rand::thread_rng()
+        .sample_iter(&rand::distributions::Standard)
+        .take(size)
+        .collect()
+}
+
+/// Create, read, update and delete `key_name` with payloads from `make_payload`.
+pub async fn test_storage_macro_crud(
+    cl: &CoLink,
+    key_name: &str,
+    make_payload: fn(usize) -> Vec<u8>,
+) -> Result<(), Box<dyn std::error::Error + Send + Sync + 'static>> {
+    let payload = make_payload(5e6 as usize);
+    cl.create_entry(key_name, &payload).await?;
+    assert!(cl.create_entry(key_name, b"").await.is_err());
+    let data = cl.read_entry(key_name).await?;
+    assert_eq!(data, payload);
+    let new_payload = make_payload(3e6 as usize);
+    cl.update_entry(key_name, &new_payload).await?;
+    let data = cl.read_entry(key_name).await?;
+    assert_eq!(data, new_payload);
+    cl.delete_entry(key_name).await?;
+    assert!(cl.read_entry(key_name).await.is_err());
+    assert!(cl.delete_entry(key_name).await.is_err());
+    Ok(())
+}
+
+/// Append to `key_name` with `$append` and check the result.
+pub async fn test_storage_macro_append(
+    cl: &CoLink,
+    key_name: &str,
+    make_payload: fn(usize) -> Vec<u8>,
+) -> Result<(), Box<dyn std::error::Error + Send + Sync + 'static>> {
+    let payload0 = make_payload(2e6 as usize);
+    let payload1 = make_payload(10);
+    cl.create_entry(key_name, &payload0).await?;
+    cl.update_entry(&format!("{}:$append", key_name), &payload1)
+        .await?;
+    let data = cl.read_entry(key_name).await?;
+    assert_eq!(data, [payload0, payload1].concat());
+    cl.delete_entry(key_name).await?;
+    Ok(())
+}
+
+/// A new empty directory for a test.
+pub fn temp_dir(name: &str) -> Result<String, Box<dyn std::error::Error + Send + Sync + 'static>> {
+    let dir = std::env::temp_dir().join(format!("{}-{}", name, uuid::Uuid::new_v4()));
+    std::fs::create_dir_all(&dir)?;
+    Ok(dir.to_string_lossy().to_string())
+}
@@ -0,0 +1,55 @@
+mod common;
+use colink::CoLink;
+use common::*;
+use std::thread;
+
+#[tokio::test]
+async fn test_atomic_counter() -> Result<(), Box<dyn std::error::Error + Send + Sync + 'static>> {
+    let (_ir, _is, cl) = set_up_test_env_single_user().await?;
+
+    test_atomic_add(&cl, "example_atomic_counter").await?;
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_atomic_counter_redis(
+) -> Result<(), Box<dyn std::error::Error + Send + Sync + 'static>> {
+    let (_ir, _is, cl) = set_up_test_env_single_user().await?;
+
+    cl.create_entry("test_atomic_counter_redis:redis_url", b"redis://127.0.0.1")
+        .await?;
+    test_atomic_add(&cl, "test_atomic_counter_redis:$redis:counter").await?;
+    cl.delete_entry("test_atomic_counter_redis:$redis:counter")
+        .await?;
+
+    Ok(())
+}
+
+async fn test_atomic_add(
+    cl: &CoLink,
+    key_name: &str,
+) -> Result<(), Box<dyn std::error::Error + Send + Sync + 'static>> {
+    assert_eq!(cl.atomic_get(key_name).await?, 0);
+    let test_num = 10;
+    let mut ths = vec![];
+    for _ in 0..test_num {
+        let cl = cl.clone();
+        let key_name = key_name.to_string();
+        ths.push(thread::spawn(move || {
+            tokio::runtime::Builder::new_multi_thread()
+                .enable_all()
+                .build()
+                .unwrap()
+                .block_on(async move {
+                    cl.atomic_add(&key_name, 2).await.unwrap();
+                });
+        }));
+    }
+    for th in ths {
+        th.join().unwrap();
+    }
+    assert_eq!(cl.atomic_get(key_name).await?, 2 * test_num);
+    assert_eq!(cl.atomic_add(key_name, -test_num).await?, test_num);
+    Ok(())
+}
@@ -0,0 +1,92 @@
+mod common;
+use colink::utils::get_path_timestamp;
+use common::*;
+use std::thread;
+
+#[tokio::test]
+async fn test_update_entry_if() -> Result<(), Box<dyn std::error::Error + Send + Sync + 'static>> {
+    let (_ir, _is, cl) = set_up_test_env_single_user().await?;
+
+    let key_path = cl.create_entry("example_cas", b"0").await?;
+    let new_key_path = cl.update_entry_if("example_cas", &key_path, b"1").await?;
+    // the old version is rejected once the entry has been modified
+    assert!(cl
+        .update_entry_if("example_cas", &key_path, b"2")
+        .await
+        .is_err());
+    cl.update_entry_if(
+        "example_cas",
+        &get_path_timestamp(&new_key_path).to_string(),
+        b"2",
+    )
+    .await?;
+    assert_eq!(cl.read_entry("example_cas").await?, b"2");
+    // malformed versions are rejected
+    assert!(cl
+        .update_entry_if("example_cas", "example_cas@latest", b"3")
+        .await
+        .is_err());
+
+    assert_eq!(cl.create_or_get("example_cas", b"3").await?, b"2");
+    assert_eq!(cl.create_or_get("example_create_or_get", b"3").await?, b"3");
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_counter_with_update_with(
+) -> Result<(), Box<dyn std::error::Error + Send + Sync + 'static>> {
+    let (_ir, _is, cl) = set_up_test_env_single_user().await?;
+
+    let test_num = 10;
+    let mut ths = vec![];
+    for _ in 0..test_num {
+        let cl = cl.clone();
+        ths.push(thread::spawn(move || {
+            tokio::runtime::Builder::new_multi_thread()
+                .enable_all()
+                .build()
+                .unwrap()
+                .block_on(async move {
+                    cl.update_with("example_update_with_counter", |old| {
+                        let num = match old {
+                            Some(old) => i32::from_le_bytes(<[u8; 4]>::try_from(old).unwrap()),
+                            None => 0,
+                        };
+                        (num + 1).to_le_bytes().to_vec()
+                    })
+                    .await
+                    .unwrap();
+                });
+        }));
+    }
+    for th in ths {
+        th.join().unwrap();
+    }
+    let num = cl.read_entry("example_update_with_counter").await?;
+    assert_eq!(
+        i32::from_le_bytes(<[u8; 4]>::try_from(num).unwrap()),
+        test_num
+    );
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_update_with_under_lock(
+) -> Result<(), Box<dyn std::error::Error + Send + Sync + 'static>> {
+    let (_ir, _is, cl) = set_up_test_env_single_user().await?;
+
+    // compare-and-swap does not take the lock of the entry, so it can be used by a lock holder
+    let lock = cl.lock("example_update_with_lock").await?;
+    cl.update_with("example_update_with_lock", |_| b"0".to_vec())
+        .await?;
+    cl.update_with("example_update_with_lock", |old| {
+        [old.unwrap_or_default(), b"1"].concat()
+    })
+    .await?;
+    cl.unlock(lock).await?;
+    assert_eq!(cl.read_entry("example_update_with_lock").await?, b"01");
+
+    Ok(())
+}
@@ -49,3 +49,50 @@ async fn test_counter_with_lock() -> Result<(), Box<dyn std::error::Error + Send
 
     Ok(())
 }
+
+#[tokio::test]
+async fn test_lock_fencing_token() -> Result<(), Box<dyn std::error::Error + Send + Sync + 'static>>
+{
+    let (_ir, _is, cl) = set_up_test_env_single_user().await?;
+
+    let old_lock = cl.lock("example_fencing_lock").await?;
+    // simulate a takeover of the lock while the old holder is paused
+    cl.delete_entry("_lock:example_fencing_lock").await?;
+    let new_lock = cl.lock("example_fencing_lock").await?;
+    assert!(new_lock.fencing_token() > old_lock.fencing_token());
+    cl.update_entry_if_fenced("example_fencing_entry", b"new", &new_lock)
+        .await?;
+    assert!(cl
+        .update_entry_if_fenced("example_fencing_entry", b"old", &old_lock)
+        .await
+        .is_err());
+    assert_eq!(cl.read_entry("example_fencing_entry").await?, b"new");
+    cl.unlock(new_lock).await?;
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_lock_escaped_key() -> Result<(), Box<dyn std::error::Error + Send + Sync + 'static>> {
+    let (_ir, _is, cl) = set_up_test_env_single_user().await?;
+
+    for key in [
+        colink::KeyPath::new("example_lock_escaped")
+            .escaped("user:1$x")
+            .to_string(),
+        "example_lock_escaped:user:1$x".to_string(),
+    ] {
+        let lock = cl.lock(&key).await?;
+        // the lock is held until it is released
+        assert!(
+            tokio::time::timeout(std::time::Duration::from_millis(200), cl.lock(&key))
+                .await
+                .is_err()
+        );
+        cl.unlock(lock).await?;
+        let lock = cl.lock(&key).await?;
+        cl.unlock(lock).await?;
+    }
+
+    Ok(())
+}
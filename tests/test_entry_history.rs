@@ -0,0 +1,41 @@
+mod common;
+use colink::utils::get_path_timestamp;
+use common::*;
+
+#[tokio::test]
+async fn test_entry_history() -> Result<(), Box<dyn std::error::Error + Send + Sync + 'static>> {
+    let (_ir, _is, cl) = set_up_test_env_single_user().await?;
+
+    let key_path0 = cl.create_entry("example_history", b"0").await?;
+    let key_path1 = cl.update_entry("example_history", b"1").await?;
+    let key_path2 = cl.update_entry("example_history", b"2").await?;
+    // entries under the key and keys sharing the prefix are not versions of the key
+    cl.create_entry("example_history:child", b"child").await?;
+    cl.create_entry("example_history_other", b"other").await?;
+    let versions = cl.list_versions("example_history").await?;
+    assert_eq!(
+        versions,
+        vec![key_path0.clone(), key_path1.clone(), key_path2]
+    );
+
+    let timestamp1 = get_path_timestamp(&key_path1);
+    assert_eq!(cl.read_entry_at("example_history", timestamp1).await?, b"1");
+    assert_eq!(
+        cl.read_entry_at("example_history", timestamp1 - 1).await?,
+        b"0"
+    );
+    assert!(cl
+        .read_entry_at("example_history", get_path_timestamp(&key_path0) - 1)
+        .await
+        .is_err());
+
+    let key_path3 = cl.revert_entry("example_history", timestamp1).await?;
+    assert_eq!(cl.read_entry("example_history").await?, b"1");
+    assert_eq!(cl.list_versions("example_history").await?.len(), 4);
+    assert_eq!(
+        cl.list_versions("example_history").await?.last(),
+        Some(&key_path3)
+    );
+
+    Ok(())
+}
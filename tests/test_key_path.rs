@@ -0,0 +1,47 @@
+use colink::{escape_key_segment, unescape_key_segment, KeyPath};
+
+#[test]
+fn test_key_path() -> Result<(), Box<dyn std::error::Error + Send + Sync + 'static>> {
+    let key = KeyPath::new("app:users")
+        .escaped("a:b$c@d%e")
+        .segment("profile");
+    assert_eq!(key.to_string(), "app:users:a%3Ab%24c%40d%25e:profile");
+    assert_eq!(unescape_key_segment(&key.get_segments()[2]), "a:b$c@d%e");
+    assert_eq!(unescape_key_segment(&escape_key_segment("%3A")), "%3A");
+    key.validate()?;
+    // ids generated by the core are not changed by escaping
+    let key = KeyPath::new("_internal:tasks").escaped("0b5f4a1e-7c2d-4e3b-9a8f-6d1c2b3a4e5f");
+    assert_eq!(
+        key.to_string(),
+        "_internal:tasks:0b5f4a1e-7c2d-4e3b-9a8f-6d1c2b3a4e5f"
+    );
+    // segment appends literal segments
+    let key = KeyPath::new("app").segment("a:b").segment(1);
+    assert_eq!(key.to_string(), "app:a:b:1");
+
+    let key = KeyPath::new("storage")
+        .storage_macro("chunk")
+        .user_id("user")
+        .timestamp(42);
+    assert_eq!(key.to_string(), "user::storage:$chunk@42");
+    assert!(key.has_storage_macro());
+    let parsed = KeyPath::parse("user::storage:$chunk@42")?;
+    assert_eq!(parsed, key);
+    assert_eq!(parsed.get_user_id(), Some("user"));
+    assert_eq!(parsed.get_timestamp(), Some(42));
+    assert_eq!(parsed.key_name(), "storage:$chunk");
+    assert_eq!(KeyPath::parse("a:b")?.get_user_id(), None);
+
+    assert!(KeyPath::parse("a:$").is_err());
+    assert!(KeyPath::parse("a:b$c").is_err());
+    assert!(KeyPath::parse("a:").is_err());
+    assert!(KeyPath::parse("::b").is_err());
+    assert!(KeyPath::parse("user::a::b").is_err());
+    assert!(KeyPath::parse("user::a@latest").is_err());
+    assert!(KeyPath::new("a")
+        .storage_macro("re dis")
+        .validate()
+        .is_err());
+
+    Ok(())
+}
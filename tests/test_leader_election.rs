@@ -0,0 +1,28 @@
+mod common;
+use common::*;
+
+#[tokio::test]
+async fn test_leader_election() -> Result<(), Box<dyn std::error::Error + Send + Sync + 'static>> {
+    let (_ir, _is, cl) = set_up_test_env_single_user().await?;
+
+    let leader = cl
+        .elect_leader_with_lease_duration("example_group", 2000)
+        .await?;
+    assert!(leader.is_leader());
+    let cl_clone = cl.clone();
+    let follower = tokio::spawn(async move {
+        cl_clone
+            .elect_leader_with_lease_duration("example_group", 2000)
+            .await
+    });
+    // the leader keeps renewing its lease, so the follower has to wait
+    tokio::time::sleep(tokio::time::Duration::from_millis(5000)).await;
+    assert!(!follower.is_finished());
+    assert!(leader.is_leader());
+    leader.resign().await?;
+    let new_leader = follower.await??;
+    assert!(new_leader.is_leader());
+    new_leader.resign().await?;
+
+    Ok(())
+}
@@ -0,0 +1,70 @@
+mod common;
+use common::*;
+
+#[tokio::test]
+async fn test_prefix_operations() -> Result<(), Box<dyn std::error::Error + Send + Sync + 'static>>
+{
+    let (_ir, _is, cl) = set_up_test_env_single_user().await?;
+    let user_id = cl.get_user_id()?;
+
+    cl.create_entry("test_prefix_operations", b"root").await?;
+    cl.create_entry("test_prefix_operations:a", b"a").await?;
+    cl.create_entry("test_prefix_operations:b:c", b"c").await?;
+    // larger than one chunk
+    let large_payload = vec![7; 3 * 1024 * 1024];
+    cl.create_entry("test_prefix_operations:large:$chunk", &large_payload)
+        .await?;
+    // a key sharing the prefix, which is not under it
+    cl.create_entry("test_prefix_operations_other", b"other")
+        .await?;
+
+    assert_eq!(
+        cl.copy_prefix("test_prefix_operations", "test_prefix_operations_copy")
+            .await?,
+        4
+    );
+    assert_eq!(cl.read_entry("test_prefix_operations_copy").await?, b"root");
+    assert_eq!(
+        cl.read_entry("test_prefix_operations_copy:b:c").await?,
+        b"c"
+    );
+    assert_eq!(
+        cl.read_entry("test_prefix_operations_copy:large:$chunk")
+            .await?,
+        large_payload
+    );
+    assert!(cl
+        .read_entry("test_prefix_operations_copy_other")
+        .await
+        .is_err());
+
+    cl.rename_entry(
+        "test_prefix_operations_copy:a",
+        "test_prefix_operations_copy:renamed",
+    )
+    .await?;
+    assert!(cl
+        .read_entry("test_prefix_operations_copy:a")
+        .await
+        .is_err());
+    assert_eq!(
+        cl.read_entry("test_prefix_operations_copy:renamed").await?,
+        b"a"
+    );
+
+    assert_eq!(cl.delete_prefix("test_prefix_operations").await?, 4);
+    assert_eq!(cl.delete_prefix("test_prefix_operations_copy").await?, 4);
+    // the chunks are deleted with the chunked entries
+    for prefix in ["test_prefix_operations", "test_prefix_operations_copy"] {
+        let keys = cl
+            .read_keys(&format!("{}::{}:", user_id, prefix), false)
+            .await?;
+        assert!(keys.is_empty());
+    }
+    assert_eq!(
+        cl.read_entry("test_prefix_operations_other").await?,
+        b"other"
+    );
+
+    Ok(())
+}
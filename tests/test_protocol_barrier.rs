@@ -0,0 +1,77 @@
+#![allow(unused_variables)]
+mod common;
+use colink::{CoLink, Participant, ProtocolEntry};
+use common::*;
+
+struct Member;
+#[colink::async_trait]
+impl ProtocolEntry for Member {
+    async fn start(
+        &self,
+        cl: CoLink,
+        param: Vec<u8>,
+        participants: Vec<Participant>,
+    ) -> Result<(), Box<dyn std::error::Error + Send + Sync + 'static>> {
+        let members: Vec<Participant> = participants
+            .iter()
+            .filter(|p| p.role == "member")
+            .cloned()
+            .collect();
+        cl.barrier("start", &members).await?;
+        let missing = cl
+            .barrier_with_timeout("with_absent_participant", &participants, 3000)
+            .await?;
+        cl.create_entry(
+            &format!("tasks:{}:missing", cl.get_task_id()?),
+            missing.len().to_string().as_bytes(),
+        )
+        .await?;
+        Ok(())
+    }
+}
+
+struct Absent;
+#[colink::async_trait]
+impl ProtocolEntry for Absent {
+    async fn start(
+        &self,
+        cl: CoLink,
+        param: Vec<u8>,
+        participants: Vec<Participant>,
+    ) -> Result<(), Box<dyn std::error::Error + Send + Sync + 'static>> {
+        Ok(())
+    }
+}
+
+#[tokio::test]
+async fn test_barrier() -> Result<(), Box<dyn std::error::Error + Send + Sync + 'static>> {
+    let (ir, iss, cls) = set_up_test_env(4).await?;
+    for cl in &cls {
+        colink::protocol_attach!(
+            cl,
+            ("barrier_test:member", Member),
+            ("barrier_test:absent", Absent)
+        );
+    }
+    let mut participants = vec![];
+    for cl in &cls[0..3] {
+        participants.push(Participant {
+            user_id: cl.get_user_id()?,
+            role: "member".to_string(),
+        });
+    }
+    participants.push(Participant {
+        user_id: cls[3].get_user_id()?,
+        role: "absent".to_string(),
+    });
+    let task_id = cls[0]
+        .run_task("barrier_test", Default::default(), &participants, true)
+        .await?;
+    for cl in &cls[0..3] {
+        let res = cl
+            .read_or_wait(&format!("tasks:{}:missing", task_id))
+            .await?;
+        assert_eq!(res, b"1");
+    }
+    Ok(())
+}
@@ -0,0 +1,67 @@
+mod common;
+use colink::CoLink;
+use common::*;
+use std::time::Duration;
+
+#[tokio::test]
+async fn test_read_cache() -> Result<(), Box<dyn std::error::Error + Send + Sync + 'static>> {
+    let (_ir, _is, cl) = set_up_test_env_single_user().await?;
+    let other_cl = CoLink::new(&cl.get_core_addr()?, &cl.get_jwt()?);
+    let cl = cl.read_cache(&["test_read_cache"], 1024 * 1024, Duration::from_secs(60));
+
+    cl.create_entry("test_read_cache", b"0").await?;
+    assert_eq!(cl.read_entry("test_read_cache").await?, b"0");
+    // local writes invalidate the cached entry immediately
+    cl.update_entry("test_read_cache", b"1").await?;
+    assert_eq!(cl.read_entry("test_read_cache").await?, b"1");
+    // writes from elsewhere invalidate it through the subscription
+    other_cl.update_entry("test_read_cache", b"2").await?;
+    let mut retry = 0;
+    while cl.read_entry("test_read_cache").await? != b"2" {
+        retry += 1;
+        assert!(retry < 50);
+        tokio::time::sleep(Duration::from_millis(100)).await;
+    }
+    other_cl.delete_entry("test_read_cache").await?;
+    let mut retry = 0;
+    while cl.read_entry("test_read_cache").await.is_ok() {
+        retry += 1;
+        assert!(retry < 50);
+        tokio::time::sleep(Duration::from_millis(100)).await;
+    }
+
+    // payloads larger than the capacity are not cached
+    let payload = vec![0; 2 * 1024 * 1024];
+    cl.create_entry("test_read_cache_large", &payload).await?;
+    assert_eq!(cl.read_entry("test_read_cache_large").await?, payload);
+    other_cl
+        .update_entry("test_read_cache_large", b"large")
+        .await?;
+    assert_eq!(cl.read_entry("test_read_cache_large").await?, b"large");
+
+    // keys outside the prefixes and coordination entries are not cached
+    cl.create_entry("test_uncached", b"0").await?;
+    assert_eq!(cl.read_entry("test_uncached").await?, b"0");
+    other_cl.update_entry("test_uncached", b"1").await?;
+    assert_eq!(cl.read_entry("test_uncached").await?, b"1");
+    let cl = cl.read_cache(&[""], 1024 * 1024, Duration::from_secs(60));
+    cl.create_entry("_internal:test_read_cache", b"0").await?;
+    assert_eq!(cl.read_entry("_internal:test_read_cache").await?, b"0");
+    other_cl
+        .update_entry("_internal:test_read_cache", b"1")
+        .await?;
+    assert_eq!(cl.read_entry("_internal:test_read_cache").await?, b"1");
+
+    let cl = cl.read_cache(
+        &["test_read_cache"],
+        1024 * 1024,
+        Duration::from_millis(100),
+    );
+    cl.create_entry("test_read_cache_ttl", b"0").await?;
+    assert_eq!(cl.read_entry("test_read_cache_ttl").await?, b"0");
+    other_cl.update_entry("test_read_cache_ttl", b"1").await?;
+    tokio::time::sleep(Duration::from_millis(200)).await;
+    assert_eq!(cl.read_entry("test_read_cache_ttl").await?, b"1");
+
+    Ok(())
+}
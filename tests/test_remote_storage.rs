@@ -1,5 +1,5 @@
 mod common;
-use colink::SubscriptionMessage;
+use colink::{Participant, SubscriptionMessage};
 use common::*;
 
 #[tokio::test]
@@ -64,3 +64,34 @@ async fn test_remote_storage() -> Result<(), Box<dyn std::error::Error + Send +
 
     Ok(())
 }
+
+#[tokio::test]
+async fn test_remote_storage_escaped_names(
+) -> Result<(), Box<dyn std::error::Error + Send + Sync + 'static>> {
+    let (_ir, _iss, mut cls) = set_up_test_env(2).await?;
+    let (mut cl_a, mut cl_b) = (cls.pop().unwrap(), cls.pop().unwrap());
+    // task ids and variable names with `:`, `$` and `@` are single segments of the transfer keys
+    cl_a.set_task_id("task:1$x");
+    cl_b.set_task_id("task:1$x");
+    let key = "output:$chunk@1";
+    let sender = Participant {
+        user_id: cl_a.get_user_id()?,
+        role: "initiator".to_string(),
+    };
+    let receiver = Participant {
+        user_id: cl_b.get_user_id()?,
+        role: "receiver".to_string(),
+    };
+    cl_a.send_variable_with_remote_storage(key, b"hello", &[sender.clone(), receiver])
+        .await?;
+    assert_eq!(
+        cl_a.recv_variable_with_remote_storage(key, &sender).await?,
+        b"hello"
+    );
+    assert_eq!(
+        cl_b.recv_variable_with_remote_storage(key, &sender).await?,
+        b"hello"
+    );
+
+    Ok(())
+}
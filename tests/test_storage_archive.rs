@@ -0,0 +1,56 @@
+mod common;
+use colink::extensions::storage_archive::{ImportConflictStrategy, ImportOptions};
+use common::*;
+
+#[tokio::test]
+async fn test_storage_archive() -> Result<(), Box<dyn std::error::Error + Send + Sync + 'static>> {
+    let (_ir, _is, cl) = set_up_test_env_single_user().await?;
+
+    cl.create_entry("test_storage_archive:a", b"a").await?;
+    cl.create_entry("test_storage_archive:b:c", b"c").await?;
+    // chunked entries are exported with their contents, in several records
+    let large_payload = random_payload(9 * 1024 * 1024);
+    cl.create_entry("test_storage_archive:large:$chunk", &large_payload)
+        .await?;
+
+    let mut archive = Vec::new();
+    assert_eq!(
+        cl.export_storage("test_storage_archive", &mut archive)
+            .await?,
+        3
+    );
+    cl.delete_prefix("test_storage_archive").await?;
+    assert!(cl.read_entry("test_storage_archive:a").await.is_err());
+
+    let options = ImportOptions::default();
+    assert_eq!(cl.import_storage(&archive[..], &options).await?, 3);
+    assert_eq!(cl.read_entry("test_storage_archive:a").await?, b"a");
+    assert_eq!(cl.read_entry("test_storage_archive:b:c").await?, b"c");
+    assert_eq!(
+        cl.read_entry("test_storage_archive:large:$chunk").await?,
+        large_payload
+    );
+
+    cl.update_entry("test_storage_archive:a", b"changed")
+        .await?;
+    // existing entries are kept by default
+    assert_eq!(cl.import_storage(&archive[..], &options).await?, 0);
+    assert_eq!(cl.read_entry("test_storage_archive:a").await?, b"changed");
+    let options = ImportOptions {
+        conflict_strategy: ImportConflictStrategy::Fail,
+    };
+    assert!(cl.import_storage(&archive[..], &options).await.is_err());
+    let options = ImportOptions {
+        conflict_strategy: ImportConflictStrategy::Overwrite,
+    };
+    assert_eq!(cl.import_storage(&archive[..], &options).await?, 3);
+    assert_eq!(cl.read_entry("test_storage_archive:a").await?, b"a");
+
+    assert!(cl
+        .import_storage(&b"not an archive"[..], &options)
+        .await
+        .is_err());
+    cl.delete_prefix("test_storage_archive").await?;
+
+    Ok(())
+}
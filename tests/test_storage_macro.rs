@@ -1,7 +1,11 @@
 mod common;
-use colink::CoLink;
+use colink::{
+    extensions::storage_macro::{ChunkStorageMacro, StorageMacro},
+    CoLink,
+};
 use common::*;
 use rand::Rng;
+use std::{collections::HashMap, sync::Mutex};
 
 #[tokio::test]
 async fn test_storage_macro_chunk() -> Result<(), Box<dyn std::error::Error + Send + Sync + 'static>>
@@ -14,6 +18,180 @@ async fn test_storage_macro_chunk() -> Result<(), Box<dyn std::error::Error + Se
     Ok(())
 }
 
+#[tokio::test]
+async fn test_storage_macro_chunk_index(
+) -> Result<(), Box<dyn std::error::Error + Send + Sync + 'static>> {
+    let (_ir, _is, cl) = set_up_test_env_single_user().await?;
+
+    // build a hierarchical metadata entry by hand, as only huge payloads need one
+    let key_name = "test_storage_macro_chunk_index";
+    let payload = rand::thread_rng()
+        .sample_iter(&rand::distributions::Standard)
+        .take(1024 * 1024 + 10)
+        .collect::<Vec<u8>>();
+    let mut timestamps = vec![];
+    for (i, chunk) in payload.chunks(1024 * 1024).enumerate() {
+        let response = cl
+            .update_entry(&format!("{}:{}", key_name, i), chunk)
+            .await?;
+        timestamps.push(response.split('@').last().unwrap().to_string());
+    }
+    let response = cl
+        .update_entry(
+            &format!("{}:chunk_index:1:0", key_name),
+            timestamps.join(";").as_bytes(),
+        )
+        .await?;
+    let index_timestamp = response.split('@').last().unwrap();
+    cl.create_entry(
+        &format!("{}:chunk_metadata", key_name),
+        format!("v2;1;4096;{}", index_timestamp).as_bytes(),
+    )
+    .await?;
+    let key_name = format!("{}:$chunk", key_name);
+    assert_eq!(cl.read_entry(&key_name).await?, payload);
+    assert_eq!(
+        cl.read_entry_range(&key_name, 1024 * 1024, 10).await?,
+        payload[1024 * 1024..]
+    );
+    cl.update_entry(&format!("{}:$append", key_name), b"append")
+        .await?;
+    assert_eq!(
+        cl.read_entry(&key_name).await?,
+        [payload, b"append".to_vec()].concat()
+    );
+    cl.delete_entry(&key_name).await?;
+
+    cl.create_entry("test_storage_macro_chunk_version:chunk_metadata", b"v3;1;0")
+        .await?;
+    assert!(cl
+        .read_entry("test_storage_macro_chunk_version:$chunk")
+        .await
+        .is_err());
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_storage_macro_chunk_layout(
+) -> Result<(), Box<dyn std::error::Error + Send + Sync + 'static>> {
+    let (_ir, _is, cl) = set_up_test_env_single_user().await?;
+
+    // 3 chunk timestamps fit in 64 bytes, 5 do not
+    cl.register_storage_macro("small_chunk", ChunkStorageMacro::new(64, 2));
+    let key_name = "test_storage_macro_chunk_layout:$small_chunk";
+    let metadata_key = "test_storage_macro_chunk_layout:chunk_metadata";
+    let index_key = "test_storage_macro_chunk_layout:chunk_index:1:0";
+    let payload = random_payload(3 * 1024 * 1024);
+    cl.create_entry(key_name, &payload).await?;
+    assert!(!cl.read_entry(metadata_key).await?.starts_with(b"v"));
+    assert_eq!(cl.read_entry(key_name).await?, payload);
+
+    let appended = random_payload(2 * 1024 * 1024);
+    cl.update_entry(&format!("{}:$append", key_name), &appended)
+        .await?;
+    let payload = [payload, appended].concat();
+    assert!(cl.read_entry(metadata_key).await?.starts_with(b"v2;2;2;"));
+    assert_eq!(cl.read_entry(key_name).await?, payload);
+    assert_eq!(
+        cl.read_entry_range(key_name, 1024 * 1024 + 10, 2 * 1024 * 1024)
+            .await?,
+        payload[1024 * 1024 + 10..3 * 1024 * 1024 + 10]
+    );
+    assert_eq!(
+        cl.read_entry_range(key_name, 4 * 1024 * 1024, 10).await?,
+        payload[4 * 1024 * 1024..4 * 1024 * 1024 + 10]
+    );
+
+    // appending only rewrites the index entries covering the last chunk
+    let index_entry = colink::StorageEntry {
+        key_name: index_key.to_string(),
+        ..Default::default()
+    };
+    let index_path = cl.read_entries(&[index_entry.clone()]).await?[0]
+        .key_path
+        .clone();
+    cl.update_entry(&format!("{}:$append", key_name), b"append")
+        .await?;
+    let payload = [payload, b"append".to_vec()].concat();
+    assert_eq!(
+        cl.read_entries(&[index_entry]).await?[0].key_path,
+        index_path
+    );
+    assert_eq!(cl.read_entry(key_name).await?, payload);
+
+    // the index entries are deleted when the metadata shrinks back to the flat layout
+    cl.update_entry(key_name, b"payload").await?;
+    assert!(!cl.read_entry(metadata_key).await?.starts_with(b"v"));
+    assert!(cl.read_entry(index_key).await.is_err());
+    assert_eq!(cl.read_entry(key_name).await?, b"payload");
+    cl.delete_entry(key_name).await?;
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_storage_macro_chunk_keys(
+) -> Result<(), Box<dyn std::error::Error + Send + Sync + 'static>> {
+    let (_ir, _is, cl) = set_up_test_env_single_user().await?;
+
+    let key_name = "test_storage_macro_chunk_keys";
+    cl.create_entry(&format!("{}:0:$chunk", key_name), &[0; 10])
+        .await?;
+    cl.create_entry(&format!("{}:1:$chunk", key_name), &[1; 2 * 1024 * 1024])
+        .await?;
+    cl.update_entry(&format!("{}:1:$chunk", key_name), &[1; 5])
+        .await?;
+    // read_keys is a raw prefix scan, so nested keys are listed but keys sharing the prefix string are not
+    cl.create_entry(&format!("{}:2:nested:$chunk", key_name), &[2; 20])
+        .await?;
+    cl.create_entry(&format!("{}_other:$chunk", key_name), &[3; 30])
+        .await?;
+    let prefix = format!("{}::{}:$chunk", cl.get_user_id()?, key_name);
+    let keys = cl.read_keys(&prefix, false).await?;
+    assert_eq!(keys.len(), 3);
+    for key in keys {
+        let data = cl.read_entry(&key.key_path).await?;
+        match data.len() {
+            10 => assert!(key.key_path.contains(":0:$chunk@")),
+            5 => assert!(key.key_path.contains(":1:$chunk@")),
+            20 => assert!(key.key_path.contains(":2:nested:$chunk@")),
+            _ => panic!("unexpected entry {}", key.key_path),
+        }
+    }
+    // each version listed in the history is read as it was written
+    let keys = cl.read_keys(&prefix, true).await?;
+    let mut versions = vec![];
+    for key in keys {
+        let (key_name, _) = key.key_path.rsplit_once('@').unwrap();
+        versions.push((key_name.to_string(), cl.read_entry(&key.key_path).await?));
+    }
+    versions.sort();
+    let user_id = cl.get_user_id()?;
+    let mut expected = vec![
+        (format!("{}::{}:0:$chunk", user_id, key_name), vec![0; 10]),
+        (
+            format!("{}::{}:1:$chunk", user_id, key_name),
+            vec![1; 2 * 1024 * 1024],
+        ),
+        (format!("{}::{}:1:$chunk", user_id, key_name), vec![1; 5]),
+        (
+            format!("{}::{}:2:nested:$chunk", user_id, key_name),
+            vec![2; 20],
+        ),
+    ];
+    expected.sort();
+    assert_eq!(versions, expected);
+    cl.delete_entry(&format!("{}:0:$chunk", key_name)).await?;
+    cl.delete_entry(&format!("{}:1:$chunk", key_name)).await?;
+    cl.delete_entry(&format!("{}:2:nested:$chunk", key_name))
+        .await?;
+    cl.delete_entry(&format!("{}_other:$chunk", key_name))
+        .await?;
+
+    Ok(())
+}
+
 #[tokio::test]
 async fn test_storage_macro_redis() -> Result<(), Box<dyn std::error::Error + Send + Sync + 'static>>
 {
@@ -46,6 +224,27 @@ async fn test_storage_macro_fs() -> Result<(), Box<dyn std::error::Error + Send
     let key_name = "test_storage_macro_fs_dir:$fs:test-dir:test-file";
     test_crud(&cl, key_name).await?;
 
+    // symlinks cannot lead outside the base path
+    #[cfg(unix)]
+    {
+        let outside = std::path::PathBuf::from(temp_dir("colink-sm-fs-outside")?);
+        std::fs::write(outside.join("secret"), b"secret")?;
+        let link = std::path::Path::new("/tmp/colink-sm-fs-test/test-dir/link");
+        let _ = std::fs::remove_file(link);
+        std::os::unix::fs::symlink(&outside, link)?;
+        assert!(cl
+            .read_entry("test_storage_macro_fs_dir:$fs:link:secret")
+            .await
+            .is_err());
+        assert!(cl
+            .create_entry("test_storage_macro_fs_dir:$fs:link:new", b"new")
+            .await
+            .is_err());
+        assert!(!outside.join("new").exists());
+        std::fs::remove_file(link)?;
+        std::fs::remove_dir_all(&outside)?;
+    }
+
     Ok(())
 }
 
@@ -291,3 +490,442 @@ async fn test_storage_macro_fs_chunk_append(
 
     Ok(())
 }
+
+#[derive(Default)]
+struct MemoryStorageMacro {
+    entries: Mutex<HashMap<String, Vec<u8>>>,
+}
+
+#[async_trait::async_trait]
+impl StorageMacro for MemoryStorageMacro {
+    async fn create(
+        &self,
+        _cl: &CoLink,
+        string_before: &str,
+        string_after: &str,
+        payload: &[u8],
+    ) -> Result<String, Box<dyn std::error::Error + Send + Sync + 'static>> {
+        let key = format!("{}:{}", string_before, string_after);
+        let mut entries = self.entries.lock().unwrap();
+        if entries.contains_key(&key) {
+            Err("key already exists.")?
+        }
+        entries.insert(key.clone(), payload.to_vec());
+        Ok(key)
+    }
+
+    async fn read(
+        &self,
+        _cl: &CoLink,
+        string_before: &str,
+        string_after: &str,
+    ) -> Result<Vec<u8>, Box<dyn std::error::Error + Send + Sync + 'static>> {
+        let key = format!("{}:{}", string_before, string_after);
+        match self.entries.lock().unwrap().get(&key) {
+            Some(payload) => Ok(payload.clone()),
+            None => Err(tonic::Status::not_found("key does not exist."))?,
+        }
+    }
+
+    async fn update(
+        &self,
+        _cl: &CoLink,
+        string_before: &str,
+        string_after: &str,
+        payload: &[u8],
+    ) -> Result<String, Box<dyn std::error::Error + Send + Sync + 'static>> {
+        let key = format!("{}:{}", string_before, string_after);
+        self.entries
+            .lock()
+            .unwrap()
+            .insert(key.clone(), payload.to_vec());
+        Ok(key)
+    }
+
+    async fn delete(
+        &self,
+        _cl: &CoLink,
+        string_before: &str,
+        string_after: &str,
+    ) -> Result<String, Box<dyn std::error::Error + Send + Sync + 'static>> {
+        let key = format!("{}:{}", string_before, string_after);
+        match self.entries.lock().unwrap().remove(&key) {
+            Some(_) => Ok(key),
+            None => Err(tonic::Status::not_found("key does not exist."))?,
+        }
+    }
+}
+
+#[tokio::test]
+async fn test_storage_macro_custom(
+) -> Result<(), Box<dyn std::error::Error + Send + Sync + 'static>> {
+    let (_ir, _is, cl) = set_up_test_env_single_user().await?;
+
+    cl.register_storage_macro("memory", MemoryStorageMacro::default());
+    let key_name = "test_storage_macro_custom:$memory:key";
+    test_crud(&cl, key_name).await?;
+    let key_name = "test_storage_macro_custom:$memory:key:$chunk";
+    test_crud(&cl, key_name).await?;
+    // append falls back to read and update for storage macros without append
+    let key_name = "test_storage_macro_custom:$memory:append";
+    cl.create_entry(key_name, b"payload").await?;
+    cl.update_entry(&format!("{}:$append", key_name), b"append")
+        .await?;
+    assert_eq!(cl.read_entry(key_name).await?, b"payloadappend");
+    assert!(cl
+        .read_entry("test_storage_macro_custom:$unregistered:key")
+        .await
+        .is_err());
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_storage_macro_stream(
+) -> Result<(), Box<dyn std::error::Error + Send + Sync + 'static>> {
+    let (_ir, _is, cl) = set_up_test_env_single_user().await?;
+
+    test_stream(&cl, "test_storage_macro_stream").await?;
+    test_stream(&cl, "test_storage_macro_stream_chunk:$chunk").await?;
+    cl.create_entry(
+        "test_storage_macro_stream_fs:path",
+        b"/tmp/colink-sm-fs-test/stream-test",
+    )
+    .await?;
+    test_stream(&cl, "test_storage_macro_stream_fs:$fs").await?;
+    test_stream(&cl, "test_storage_macro_stream_fs:$fs:$chunk").await?;
+
+    Ok(())
+}
+
+async fn test_stream(
+    cl: &CoLink,
+    key_name: &str,
+) -> Result<(), Box<dyn std::error::Error + Send + Sync + 'static>> {
+    use tokio::io::AsyncReadExt;
+    let payload = rand::thread_rng()
+        .sample_iter(&rand::distributions::Standard)
+        .take(5e6 as usize)
+        .collect::<Vec<u8>>();
+    cl.write_entry_stream(key_name, std::io::Cursor::new(payload.clone()))
+        .await?;
+    assert_eq!(cl.read_entry(key_name).await?, payload);
+    let mut data = Vec::new();
+    cl.read_entry_stream(key_name)
+        .await?
+        .read_to_end(&mut data)
+        .await?;
+    assert_eq!(data, payload);
+    cl.delete_entry(key_name).await?;
+    assert!(cl.read_entry_stream(key_name).await.is_err());
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_storage_macro_range() -> Result<(), Box<dyn std::error::Error + Send + Sync + 'static>>
+{
+    let (_ir, _is, cl) = set_up_test_env_single_user().await?;
+
+    test_range(&cl, "test_storage_macro_range").await?;
+    test_range(&cl, "test_storage_macro_range_chunk:$chunk").await?;
+    cl.create_entry(
+        "test_storage_macro_range_fs:path",
+        b"/tmp/colink-sm-fs-test/range-test",
+    )
+    .await?;
+    test_range(&cl, "test_storage_macro_range_fs:$fs").await?;
+    cl.create_entry(
+        "test_storage_macro_range_redis:redis_url",
+        b"redis://127.0.0.1",
+    )
+    .await?;
+    test_range(&cl, "test_storage_macro_range_redis:$redis:redis_key").await?;
+    test_range(
+        &cl,
+        "test_storage_macro_range_redis:$redis:redis_chunk:$chunk",
+    )
+    .await?;
+
+    Ok(())
+}
+
+async fn test_range(
+    cl: &CoLink,
+    key_name: &str,
+) -> Result<(), Box<dyn std::error::Error + Send + Sync + 'static>> {
+    let payload = rand::thread_rng()
+        .sample_iter(&rand::distributions::Standard)
+        .take(3e6 as usize)
+        .collect::<Vec<u8>>();
+    cl.create_entry(key_name, &payload).await?;
+    for (offset, length) in [(0, 10), (1024 * 1024 - 5, 10), (2e6 as u64, 2e6 as u64)] {
+        let data = cl.read_entry_range(key_name, offset, length).await?;
+        let end = std::cmp::min(offset + length, payload.len() as u64);
+        assert_eq!(data, payload[offset as usize..end as usize]);
+    }
+    assert!(cl
+        .read_entry_range(key_name, 4e6 as u64, 10)
+        .await?
+        .is_empty());
+    cl.delete_entry(key_name).await?;
+    assert!(cl.read_entry_range(key_name, 0, 10).await.is_err());
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_storage_macro_cas() -> Result<(), Box<dyn std::error::Error + Send + Sync + 'static>>
+{
+    let (_ir, _is, cl) = set_up_test_env_single_user().await?;
+
+    let key_name = "test_storage_macro_cas:$cas";
+    test_crud(&cl, key_name).await?;
+    test_append(&cl, key_name, 5e6 as usize).await?;
+    test_append(&cl, key_name, 10).await?;
+    test_range(&cl, key_name).await?;
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_storage_macro_cas_dedup(
+) -> Result<(), Box<dyn std::error::Error + Send + Sync + 'static>> {
+    use sha2::{Digest, Sha256};
+    let (_ir, _is, cl) = set_up_test_env_single_user().await?;
+
+    let payload = rand::thread_rng()
+        .sample_iter(&rand::distributions::Standard)
+        .take(2621440)
+        .collect::<Vec<u8>>();
+    cl.create_entry("test_storage_macro_cas_a:$cas", &payload)
+        .await?;
+    cl.create_entry("test_storage_macro_cas_b:$cas", &payload)
+        .await?;
+    let hash = format!("{:x}", Sha256::digest(&payload[..1024 * 1024]));
+    let refs = String::from_utf8(cl.read_entry("_cas_store:refs").await?)?;
+    assert!(refs.split(';').any(|x| x == format!("{}=2", hash)));
+
+    cl.delete_entry("test_storage_macro_cas_a:$cas").await?;
+    assert_eq!(cl.cas_gc("test_storage_macro_cas_a:$cas").await?, 0);
+    assert_eq!(
+        cl.read_entry("test_storage_macro_cas_b:$cas").await?,
+        payload
+    );
+    cl.update_entry("test_storage_macro_cas_b:$cas", b"")
+        .await?;
+    assert_eq!(cl.read_entry("test_storage_macro_cas_b:$cas").await?, b"");
+    assert_eq!(cl.cas_gc("test_storage_macro_cas_b:$cas").await?, 3);
+    assert!(cl
+        .read_entry(&format!("_cas_store:chunk:{}", hash))
+        .await
+        .is_err());
+    cl.delete_entry("test_storage_macro_cas_b:$cas").await?;
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_storage_macro_ttl() -> Result<(), Box<dyn std::error::Error + Send + Sync + 'static>>
+{
+    let (_ir, _is, cl) = set_up_test_env_single_user().await?;
+    let ttl = std::time::Duration::from_secs(1);
+
+    cl.update_entry_with_ttl("test_storage_macro_ttl", b"data", ttl)
+        .await?;
+    assert_eq!(cl.read_entry("test_storage_macro_ttl:$ttl").await?, b"data");
+    // keys without storage macros are stored under $ttl
+    assert!(cl.read_entry("test_storage_macro_ttl").await.is_err());
+    cl.create_entry("test_storage_macro_ttl_dir:ttl", b"1")
+        .await?;
+    cl.create_entry("test_storage_macro_ttl_dir:$ttl:a", b"a")
+        .await?;
+    cl.update_entry("test_storage_macro_ttl_dir:$ttl:a:$append", b"b")
+        .await?;
+    assert_eq!(
+        cl.read_entry("test_storage_macro_ttl_dir:$ttl:a").await?,
+        b"ab"
+    );
+    cl.create_entry(
+        "test_storage_macro_ttl_redis:redis_url",
+        b"redis://127.0.0.1",
+    )
+    .await?;
+    cl.update_entry_with_ttl("test_storage_macro_ttl_redis:$redis:key", b"data", ttl)
+        .await?;
+    assert_eq!(
+        cl.read_entry("test_storage_macro_ttl_redis:$redis:key")
+            .await?,
+        b"data"
+    );
+
+    tokio::time::sleep(std::time::Duration::from_millis(1500)).await;
+    assert!(cl.read_entry("test_storage_macro_ttl:$ttl").await.is_err());
+    assert!(cl
+        .read_entry("test_storage_macro_ttl_redis:$redis:key")
+        .await
+        .is_err());
+    assert_eq!(cl.sweep_expired_entries().await?, 1);
+    assert!(cl
+        .read_entry("test_storage_macro_ttl_dir:_ttl:a")
+        .await
+        .is_err());
+    assert_eq!(cl.sweep_expired_entries().await?, 0);
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_storage_macro_redis_pool(
+) -> Result<(), Box<dyn std::error::Error + Send + Sync + 'static>> {
+    let (_ir, _is, cl) = set_up_test_env_single_user().await?;
+
+    cl.create_entry(
+        "test_storage_macro_redis_pool:redis_url",
+        b"redis://127.0.0.1",
+    )
+    .await?;
+    // the clones share the pooled connection
+    let mut handles = vec![];
+    for i in 0..16 {
+        let cl = cl.clone();
+        handles.push(tokio::spawn(async move {
+            let key_name = format!("test_storage_macro_redis_pool:$redis:key_{}", i);
+            cl.update_entry(&key_name, i.to_string().as_bytes()).await?;
+            assert_eq!(cl.read_entry(&key_name).await?, i.to_string().as_bytes());
+            cl.delete_entry(&key_name).await?;
+            Ok::<(), Box<dyn std::error::Error + Send + Sync + 'static>>(())
+        }));
+    }
+    for handle in handles {
+        handle.await??;
+    }
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_storage_macro_redis_types(
+) -> Result<(), Box<dyn std::error::Error + Send + Sync + 'static>> {
+    let (_ir, _is, cl) = set_up_test_env_single_user().await?;
+
+    cl.create_entry(
+        "test_storage_macro_redis_types:redis_url",
+        b"redis://127.0.0.1",
+    )
+    .await?;
+    let key_name = "test_storage_macro_redis_types:$redis:hash";
+    cl.redis_hset(key_name, "a", b"0").await?;
+    cl.redis_hset(key_name, "b", b"1").await?;
+    assert_eq!(cl.redis_hget(key_name, "a").await?, b"0");
+    let mut fields = cl.redis_hgetall(key_name).await?;
+    fields.sort();
+    assert_eq!(
+        fields,
+        [
+            ("a".to_string(), b"0".to_vec()),
+            ("b".to_string(), b"1".to_vec())
+        ]
+    );
+    assert!(cl.redis_hdel(key_name, "a").await?);
+    assert!(cl.redis_hget(key_name, "a").await.is_err());
+    cl.delete_entry(key_name).await?;
+
+    let key_name = "test_storage_macro_redis_types:$redis:list";
+    cl.redis_rpush(key_name, b"1").await?;
+    assert_eq!(cl.redis_lpush(key_name, b"0").await?, 2);
+    assert_eq!(
+        cl.redis_lrange(key_name, 0, -1).await?,
+        [b"0".to_vec(), b"1".to_vec()]
+    );
+    assert_eq!(cl.redis_rpop(key_name).await?, Some(b"1".to_vec()));
+    assert_eq!(cl.redis_lpop(key_name).await?, Some(b"0".to_vec()));
+    assert_eq!(cl.redis_lpop(key_name).await?, None);
+
+    let key_name = "test_storage_macro_redis_types:$redis:set";
+    assert!(cl.redis_sadd(key_name, b"0").await?);
+    assert!(!cl.redis_sadd(key_name, b"0").await?);
+    assert!(cl.redis_sismember(key_name, b"0").await?);
+    assert_eq!(cl.redis_smembers(key_name).await?, [b"0".to_vec()]);
+    assert!(cl.redis_srem(key_name, b"0").await?);
+
+    let key_name = "test_storage_macro_redis_types:$redis:zset";
+    cl.redis_zadd(key_name, b"b", 2.0).await?;
+    cl.redis_zadd(key_name, b"a", 1.0).await?;
+    cl.redis_zadd(key_name, b"c", 3.0).await?;
+    assert_eq!(cl.redis_zscore(key_name, b"a").await?, Some(1.0));
+    assert_eq!(
+        cl.redis_zrange_by_score(key_name, 1.0, 2.0).await?,
+        [(b"a".to_vec(), 1.0), (b"b".to_vec(), 2.0)]
+    );
+    assert!(cl.redis_zrem(key_name, b"a").await?);
+    cl.delete_entry(key_name).await?;
+
+    assert!(cl
+        .redis_hset("test_storage_macro_redis_types", "a", b"0")
+        .await
+        .is_err());
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_storage_macro_fs_safe_path(
+) -> Result<(), Box<dyn std::error::Error + Send + Sync + 'static>> {
+    let (_ir, _is, cl) = set_up_test_env_single_user().await?;
+
+    let base_path = "/tmp/colink-sm-fs-test/safe-path-test";
+    cl.create_entry("test_storage_macro_fs_safe_path:path", base_path.as_bytes())
+        .await?;
+    let key_name = "test_storage_macro_fs_safe_path:$fs";
+    assert!(cl
+        .create_entry(&format!("{}:..:escape", key_name), b"")
+        .await
+        .is_err());
+    assert!(cl
+        .create_entry(&format!("{}:a:..:..:escape", key_name), b"")
+        .await
+        .is_err());
+    cl.update_entry(&format!("{}:a:..:b", key_name), b"b")
+        .await?;
+    assert_eq!(cl.read_entry(&format!("{}:b", key_name)).await?, b"b");
+    cl.delete_entry(&format!("{}:b", key_name)).await?;
+    // reads do not create directories
+    assert!(cl
+        .read_entry(&format!("{}:missing:file", key_name))
+        .await
+        .is_err());
+    assert!(!std::path::Path::new(&format!("{}/missing", base_path)).exists());
+
+    cl.create_entry("test_storage_macro_fs_safe_path:permissions", b"600")
+        .await?;
+    cl.update_entry(&format!("{}:private", key_name), b"private")
+        .await?;
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::PermissionsExt;
+        let metadata = std::fs::metadata(format!("{}/private", base_path))?;
+        assert_eq!(metadata.permissions().mode() & 0o777, 0o600);
+    }
+    cl.delete_entry(&format!("{}:private", key_name)).await?;
+
+    cl.create_entry(
+        "test_storage_macro_fs_safe_path:recursive_read_keys",
+        b"true",
+    )
+    .await?;
+    cl.create_entry(&format!("{}:dir:a", key_name), b"a")
+        .await?;
+    cl.create_entry(&format!("{}:dir:sub:b", key_name), b"b")
+        .await?;
+    let mut keys = cl
+        .read_keys(&format!("{}::{}:dir", cl.get_user_id()?, key_name), false)
+        .await?;
+    keys.sort_by(|a, b| a.key_path.cmp(&b.key_path));
+    assert_eq!(keys.len(), 2);
+    assert!(keys[0].key_path.ends_with(":dir:a@0"));
+    assert!(keys[1].key_path.ends_with(":dir:sub:b@0"));
+    assert_eq!(cl.read_entry(&keys[1].key_path).await?, b"b");
+    cl.delete_entry(&format!("{}:dir:a", key_name)).await?;
+    cl.delete_entry(&format!("{}:dir:sub:b", key_name)).await?;
+
+    Ok(())
+}
@@ -0,0 +1,58 @@
+mod common;
+use common::*;
+use rand::Rng;
+
+#[tokio::test]
+async fn test_storage_macro_compress(
+) -> Result<(), Box<dyn std::error::Error + Send + Sync + 'static>> {
+    let (_ir, _is, cl) = set_up_test_env_single_user().await?;
+
+    let key_name = "test_storage_macro_compress:$compress";
+    test_storage_macro_crud(&cl, key_name, compressible_payload).await?;
+    test_storage_macro_append(&cl, key_name, compressible_payload).await?;
+
+    cl.create_entry("test_storage_macro_compress_gzip:codec", b"gzip")
+        .await?;
+    let key_name = "test_storage_macro_compress_gzip:$compress:key";
+    test_storage_macro_crud(&cl, key_name, compressible_payload).await?;
+    test_storage_macro_append(&cl, key_name, compressible_payload).await?;
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_storage_macro_compress_chunk(
+) -> Result<(), Box<dyn std::error::Error + Send + Sync + 'static>> {
+    let (_ir, _is, cl) = set_up_test_env_single_user().await?;
+
+    let key_name = "test_storage_macro_compress_chunk:$compress:$chunk";
+    test_storage_macro_crud(&cl, key_name, compressible_payload).await?;
+    test_storage_macro_append(&cl, key_name, compressible_payload).await?;
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_storage_macro_compress_redis(
+) -> Result<(), Box<dyn std::error::Error + Send + Sync + 'static>> {
+    let (_ir, _is, cl) = set_up_test_env_single_user().await?;
+
+    cl.create_entry(
+        "test_storage_macro_compress_redis:redis_url",
+        b"redis://127.0.0.1",
+    )
+    .await?;
+    let key_name = "test_storage_macro_compress_redis:$redis:redis_key:$compress";
+    test_storage_macro_crud(&cl, key_name, compressible_payload).await?;
+    test_storage_macro_append(&cl, key_name, compressible_payload).await?;
+
+    Ok(())
+}
+
+fn compressible_payload(size: usize) -> Vec<u8> {
+    let pattern = rand::thread_rng()
+        .sample_iter(&rand::distributions::Standard)
+        .take(1024)
+        .collect::<Vec<u8>>();
+    pattern.iter().cycle().take(size).cloned().collect()
+}
@@ -1,4 +1,6 @@
+use colink::extensions::storage_macro::{dbc_value, DbcQueryResult};
 use common::*;
+use prost::Message;
 mod common;
 
 fn _get_mysql_connection_url() -> String {
@@ -97,3 +99,229 @@ async fn test_storage_macro_dbc_mysql(
 
     Ok(())
 }
+
+#[tokio::test]
+async fn test_storage_macro_dbc_mysql_write(
+) -> Result<(), Box<dyn std::error::Error + Send + Sync + 'static>> {
+    let (_ir, _is, cl) = set_up_test_env_single_user().await?;
+
+    cl.create_entry(
+        "storage_macro_test_write:db:url",
+        _get_mysql_connection_url().as_bytes(),
+    )
+    .await?;
+    cl.create_entry(
+        "storage_macro_test_write:db:create_table",
+        b"CREATE TABLE IF NOT EXISTS users_write (name VARCHAR(255), age INT)" as &[u8],
+    )
+    .await?;
+    cl.create_entry(
+        "storage_macro_test_write:db:insert_user",
+        b"INSERT INTO users_write VALUES (?, ?)" as &[u8],
+    )
+    .await?;
+    cl.create_entry(
+        "storage_macro_test_write:db:update_age",
+        b"UPDATE users_write SET age = ? WHERE name = ?" as &[u8],
+    )
+    .await?;
+    cl.create_entry(
+        "storage_macro_test_write:db:delete_user",
+        b"DELETE FROM users_write WHERE name = ?" as &[u8],
+    )
+    .await?;
+    cl.create_entry(
+        "storage_macro_test_write:db:query_user",
+        b"SELECT * FROM users_write WHERE name = ?" as &[u8],
+    )
+    .await?;
+    cl.create_entry(
+        "storage_macro_test_write:db:cleanup",
+        b"DROP TABLE IF EXISTS users_write" as &[u8],
+    )
+    .await?;
+
+    cl.update_entry("storage_macro_test_write:db:$dbc:create_table", b"")
+        .await?;
+    let res = cl
+        .create_entry(
+            "storage_macro_test_write:db:$dbc:insert_user",
+            br#"["O'Brien", 20]"#,
+        )
+        .await?;
+    assert_eq!(res, "1");
+    let res = cl
+        .update_entry(
+            "storage_macro_test_write:db:$dbc:update_age",
+            br#"[30, "O'Brien"]"#,
+        )
+        .await?;
+    assert_eq!(res, "1");
+
+    cl.create_entry("storage_macro_test_write:db:encoding", b"csv")
+        .await?;
+    let res = cl
+        .read_entry("storage_macro_test_write:db:$dbc:query_user:'O''Brien'")
+        .await?;
+    assert_eq!(String::from_utf8(res)?, "name,age\r\nO'Brien,30\r\n");
+    cl.update_entry("storage_macro_test_write:db:encoding", b"protobuf")
+        .await?;
+    let res = cl
+        .read_entry("storage_macro_test_write:db:$dbc:query_user:'O''Brien'")
+        .await?;
+    let res = DbcQueryResult::decode(&*res)?;
+    assert_eq!(res.rows.len(), 1);
+    assert_eq!(res.columns[1].name, "age");
+    assert_eq!(res.rows[0].values[1].value, Some(dbc_value::Value::Int(30)));
+
+    // parameters are escaped, so they cannot change the statement
+    let res = cl
+        .create_entry(
+            "storage_macro_test_write:db:$dbc:insert_user",
+            br#"["\\'); DROP TABLE users_write; -- ", 40]"#,
+        )
+        .await?;
+    assert_eq!(res, "1");
+    let res = cl
+        .delete_entry("storage_macro_test_write:db:$dbc:delete_user:x' OR '1'='1")
+        .await?;
+    assert_eq!(res, "0");
+    let res = cl
+        .delete_entry(
+            "storage_macro_test_write:db:$dbc:delete_user:\\'); DROP TABLE users_write; -- ",
+        )
+        .await?;
+    assert_eq!(res, "1");
+    // each operation only runs its own kind of statement
+    assert!(cl
+        .create_entry("storage_macro_test_write:db:$dbc:delete_user:O'Brien", b"")
+        .await
+        .is_err());
+    assert!(cl
+        .delete_entry("storage_macro_test_write:db:$dbc:insert_user")
+        .await
+        .is_err());
+    let res = cl
+        .delete_entry("storage_macro_test_write:db:$dbc:delete_user:O'Brien")
+        .await?;
+    assert_eq!(res, "1");
+    cl.update_entry("storage_macro_test_write:db:$dbc:cleanup", b"")
+        .await?;
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_storage_macro_dbc_transaction(
+) -> Result<(), Box<dyn std::error::Error + Send + Sync + 'static>> {
+    let (_ir, _is, cl) = set_up_test_env_single_user().await?;
+
+    cl.create_entry(
+        "storage_macro_test_transaction:db:url",
+        _get_mysql_connection_url().as_bytes(),
+    )
+    .await?;
+    cl.create_entry(
+        "storage_macro_test_transaction:db:create_table",
+        b"CREATE TABLE IF NOT EXISTS users_transaction (name VARCHAR(255), age INT)" as &[u8],
+    )
+    .await?;
+    cl.create_entry(
+        "storage_macro_test_transaction:db:insert_users",
+        // `?` in comments is not a parameter
+        serde_json::to_vec(&[
+            "INSERT INTO users_transaction /* name? age? */ VALUES (?, ?)",
+            "INSERT INTO users_transaction VALUES (?, ?) -- name? age?",
+        ])?
+        .as_slice(),
+    )
+    .await?;
+    cl.create_entry(
+        "storage_macro_test_transaction:db:insert_users_and_fail",
+        serde_json::to_vec(&[
+            "INSERT INTO users_transaction VALUES (?, ?)",
+            "INSERT INTO users_transaction_missing VALUES (?, ?)",
+        ])?
+        .as_slice(),
+    )
+    .await?;
+    cl.create_entry(
+        "storage_macro_test_transaction:db:count_users",
+        // `?` in a string literal with an escaped quote is not a parameter
+        br"SELECT name FROM users_transaction WHERE name <> 'x\'?'" as &[u8],
+    )
+    .await?;
+    cl.create_entry(
+        "storage_macro_test_transaction:db:cleanup",
+        b"DROP TABLE IF EXISTS users_transaction" as &[u8],
+    )
+    .await?;
+    cl.create_entry("storage_macro_test_transaction:db:encoding", b"csv")
+        .await?;
+
+    cl.update_entry("storage_macro_test_transaction:db:$dbc:create_table", b"")
+        .await?;
+    let res = cl
+        .update_entry(
+            "storage_macro_test_transaction:db:$dbc:insert_users",
+            br#"["Alice", 20, "Bob?", 30]"#,
+        )
+        .await?;
+    assert_eq!(res, "2");
+    assert!(cl
+        .update_entry(
+            "storage_macro_test_transaction:db:$dbc:insert_users",
+            br#"["Alice", 20]"#,
+        )
+        .await
+        .is_err());
+    // the first insert is rolled back when the second statement fails
+    assert!(cl
+        .update_entry(
+            "storage_macro_test_transaction:db:$dbc:insert_users_and_fail",
+            br#"["Carol", 40, "Dave", 50]"#,
+        )
+        .await
+        .is_err());
+    let res = cl
+        .read_entry("storage_macro_test_transaction:db:$dbc:count_users")
+        .await?;
+    assert_eq!(String::from_utf8(res)?, "name\r\nAlice\r\nBob?\r\n");
+    cl.update_entry("storage_macro_test_transaction:db:$dbc:cleanup", b"")
+        .await?;
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_storage_macro_dbc_keys(
+) -> Result<(), Box<dyn std::error::Error + Send + Sync + 'static>> {
+    let (_ir, _is, cl) = set_up_test_env_single_user().await?;
+
+    cl.create_entry("storage_macro_test_keys:db:url", b"sqlite://:memory:")
+        .await?;
+    cl.create_entry("storage_macro_test_keys:db:encoding", b"csv")
+        .await?;
+    cl.create_entry("storage_macro_test_keys:db:query_one", b"SELECT 1" as &[u8])
+        .await?;
+    cl.create_entry("storage_macro_test_keys:db:query_two", b"SELECT 2" as &[u8])
+        .await?;
+    let prefix = format!("{}::storage_macro_test_keys:db:$dbc", cl.get_user_id()?);
+    let mut keys = cl
+        .read_keys(&prefix, false)
+        .await?
+        .into_iter()
+        .map(|x| x.key_path[..x.key_path.rfind('@').unwrap()].to_string())
+        .collect::<Vec<String>>();
+    keys.sort();
+    assert_eq!(
+        keys,
+        [
+            format!("{}:query_one", prefix),
+            format!("{}:query_two", prefix)
+        ]
+    );
+    assert!(cl.read_keys(&prefix, true).await.is_err());
+
+    Ok(())
+}
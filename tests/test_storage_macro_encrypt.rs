@@ -0,0 +1,64 @@
+mod common;
+use colink::{generate_user, CoLink};
+use common::*;
+
+#[tokio::test]
+async fn test_storage_macro_encrypt(
+) -> Result<(), Box<dyn std::error::Error + Send + Sync + 'static>> {
+    let (_ir, _is, cl) = set_up_test_env_single_user().await?;
+
+    assert!(cl
+        .create_entry("test_storage_macro_encrypt:$encrypt", b"")
+        .await
+        .is_err());
+    cl.set_encryption_key("test_storage_macro_encrypt", &random_payload(32))?;
+    let key_name = "test_storage_macro_encrypt:$encrypt";
+    test_storage_macro_crud(&cl, key_name, random_payload).await?;
+    test_storage_macro_append(&cl, key_name, random_payload).await?;
+    let key_name = "test_storage_macro_encrypt:$encrypt:$chunk";
+    test_storage_macro_crud(&cl, key_name, random_payload).await?;
+
+    let (_, sk) = generate_user();
+    cl.set_encryption_key_from_secret_key("test_storage_macro_encrypt_sk", &sk)?;
+    let key_name = "test_storage_macro_encrypt_sk:$encrypt:key";
+    test_storage_macro_crud(&cl, key_name, random_payload).await?;
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_storage_macro_encrypt_rotation(
+) -> Result<(), Box<dyn std::error::Error + Send + Sync + 'static>> {
+    let (_ir, _is, cl) = set_up_test_env_single_user().await?;
+
+    let key_name = "test_storage_macro_encrypt_rotation:$encrypt";
+    cl.set_encryption_key("test_storage_macro_encrypt_rotation", &random_payload(32))?;
+    cl.create_entry(key_name, b"before rotation").await?;
+    cl.rotate_encryption_key("test_storage_macro_encrypt_rotation")
+        .await?;
+    assert_eq!(cl.read_entry(key_name).await?, b"before rotation");
+    cl.update_entry(&format!("{}:$append", key_name), b", after rotation")
+        .await?;
+    assert_eq!(
+        cl.read_entry(key_name).await?,
+        b"before rotation, after rotation"
+    );
+
+    // the encryption key is not stored in the core, so a new CoLink object cannot read the data
+    let new_cl = CoLink::new(&cl.get_core_addr()?, &cl.get_jwt()?);
+    assert!(new_cl.read_entry(key_name).await.is_err());
+
+    // the ciphertext is stored in a plain entry next to the key
+    let stored_key = "test_storage_macro_encrypt_rotation:_encrypt";
+    let mut data = cl.read_entry(stored_key).await?;
+    assert!(!data
+        .windows(b"before rotation".len())
+        .any(|x| x == b"before rotation"));
+    let last = data.len() - 1;
+    data[last] ^= 1;
+    cl.update_entry(stored_key, &data).await?;
+    assert!(cl.read_entry(key_name).await.is_err());
+    cl.delete_entry(key_name).await?;
+
+    Ok(())
+}
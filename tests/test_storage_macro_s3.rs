@@ -0,0 +1,84 @@
+use common::*;
+mod common;
+
+fn _get_s3_setting(name: &str) -> String {
+    match std::env::var(name) {
+        Ok(value) => value,
+        Err(_) => panic!(
+            "Please set the environment variable {} to run this test.",
+            name
+        ),
+    }
+}
+
+#[tokio::test]
+async fn test_storage_macro_s3() -> Result<(), Box<dyn std::error::Error + Send + Sync + 'static>> {
+    let (_ir, _is, cl) = set_up_test_env_single_user().await?;
+
+    for (setting, env) in [
+        ("s3_endpoint", "S3_ENDPOINT"),
+        ("s3_bucket", "S3_BUCKET"),
+        ("s3_access_key", "S3_ACCESS_KEY"),
+        ("s3_secret_key", "S3_SECRET_KEY"),
+    ] {
+        cl.create_entry(
+            &format!("test_storage_macro_s3:{}", setting),
+            _get_s3_setting(env).as_bytes(),
+        )
+        .await?;
+    }
+    let prefix = format!("test_storage_macro_s3:$s3:{}", cl.get_user_id()?);
+
+    let key_name = format!("{}:dir:test_key", prefix);
+    cl.create_entry(&key_name, b"test_value").await?;
+    assert!(cl.create_entry(&key_name, b"test_value").await.is_err());
+    let data = cl.read_entry(&key_name).await?;
+    assert_eq!(data, b"test_value");
+    cl.update_entry(&key_name, b"test_value_updated").await?;
+    let data = cl.read_entry(&key_name).await?;
+    assert_eq!(data, b"test_value_updated");
+    let data = cl.read_entry_range(&key_name, 5, 5).await?;
+    assert_eq!(data, b"value");
+    let data = cl.read_entry_range(&key_name, 100, 5).await?;
+    assert!(data.is_empty());
+
+    // larger than one part, so it is written with a multipart upload
+    let large_payload = (0..20 * 1024 * 1024)
+        .map(|i| (i % 251) as u8)
+        .collect::<Vec<u8>>();
+    let large_key_name = format!("{}:dir:large_key", prefix);
+    cl.update_entry(&large_key_name, &large_payload).await?;
+    let data = cl.read_entry(&large_key_name).await?;
+    assert_eq!(data, large_payload);
+    let data = cl
+        .read_entry_range(&large_key_name, 9 * 1024 * 1024, 1024)
+        .await?;
+    assert_eq!(
+        data,
+        &large_payload[9 * 1024 * 1024..9 * 1024 * 1024 + 1024]
+    );
+
+    let mut keys = cl
+        .read_keys(&format!("{}::{}:dir", cl.get_user_id()?, prefix), false)
+        .await?;
+    keys.sort_by(|a, b| a.key_path.cmp(&b.key_path));
+    assert_eq!(keys.len(), 2);
+    assert!(keys[0].key_path.ends_with(":dir:large_key@0"));
+    assert!(keys[1].key_path.ends_with(":dir:test_key@0"));
+    assert_eq!(
+        cl.read_entry(&keys[1].key_path).await?,
+        b"test_value_updated"
+    );
+    let keys = cl
+        .read_keys(&format!("{}::{}", cl.get_user_id()?, prefix), false)
+        .await?;
+    assert_eq!(keys.len(), 1);
+    assert!(keys[0].key_path.ends_with(":dir@0"));
+
+    cl.delete_entry(&key_name).await?;
+    cl.delete_entry(&large_key_name).await?;
+    assert!(cl.read_entry(&key_name).await.is_err());
+    assert!(cl.delete_entry(&key_name).await.is_err());
+
+    Ok(())
+}
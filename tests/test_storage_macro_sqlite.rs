@@ -0,0 +1,58 @@
+mod common;
+use common::*;
+
+#[tokio::test]
+async fn test_storage_macro_sqlite(
+) -> Result<(), Box<dyn std::error::Error + Send + Sync + 'static>> {
+    let (_ir, _is, cl) = set_up_test_env_single_user().await?;
+
+    let dir = temp_dir("colink-sm-sqlite-test")?;
+    cl.create_entry(
+        "test_storage_macro_sqlite:path",
+        format!("{}/test.db", dir).as_bytes(),
+    )
+    .await?;
+    let key_name = "test_storage_macro_sqlite:$sqlite:key";
+    test_storage_macro_crud(&cl, key_name, random_payload).await?;
+    test_storage_macro_append(&cl, key_name, random_payload).await?;
+    let key_name = "test_storage_macro_sqlite:$sqlite:chunk:$chunk";
+    test_storage_macro_crud(&cl, key_name, random_payload).await?;
+    std::fs::remove_dir_all(&dir)?;
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_storage_macro_sqlite_keys(
+) -> Result<(), Box<dyn std::error::Error + Send + Sync + 'static>> {
+    let (_ir, _is, cl) = set_up_test_env_single_user().await?;
+
+    let dir = temp_dir("colink-sm-sqlite-test")?;
+    cl.create_entry(
+        "test_storage_macro_sqlite_keys:path",
+        format!("{}/test-keys.db", dir).as_bytes(),
+    )
+    .await?;
+    let key_name = "test_storage_macro_sqlite_keys:$sqlite:dir";
+    cl.create_entry(&format!("{}:0", key_name), b"0").await?;
+    cl.create_entry(&format!("{}:1", key_name), b"1").await?;
+    cl.create_entry("test_storage_macro_sqlite_keys:$sqlite:dir2", b"2")
+        .await?;
+    let keys = cl
+        .read_keys(&format!("{}::{}", cl.get_user_id()?, key_name), false)
+        .await?;
+    assert_eq!(keys.len(), 2);
+    for key in keys {
+        let data = cl.read_entry(&key.key_path).await?;
+        assert!(key
+            .key_path
+            .contains(&format!(":{}@", String::from_utf8_lossy(&data))));
+    }
+    cl.delete_entry(&format!("{}:0", key_name)).await?;
+    cl.delete_entry(&format!("{}:1", key_name)).await?;
+    cl.delete_entry("test_storage_macro_sqlite_keys:$sqlite:dir2")
+        .await?;
+    std::fs::remove_dir_all(&dir)?;
+
+    Ok(())
+}